@@ -1,9 +1,15 @@
 use actix_web_static_files::NpmBuild;
+use std::env;
 use walkdir::WalkDir;
 
 const SERVE_DIR: &str = "web/dist/music-server";
 
 fn main() {
+    // cargo exposes enabled features to build scripts as env vars
+    if env::var_os("CARGO_FEATURE_FRONTEND").is_none() {
+        return;
+    }
+
     NpmBuild::new("web")
         .install()
         .unwrap()
@@ -0,0 +1,135 @@
+use crate::cdn::index::IndexHandle;
+use actix_service::{Service, Transform};
+use actix_web::{
+    dev::{ServiceRequest, ServiceResponse},
+    http::{header, header::HttpDate, HeaderValue, StatusCode},
+    web, HttpResponse,
+};
+use futures::{
+    future,
+    future::Ready,
+    task::{Context, Poll},
+};
+use std::{future::Future, pin::Pin, result, time::SystemTime};
+
+/// Middleware that adds `ETag` and `Last-Modified` headers to json responses and
+/// answers conditional requests for them with `304 Not Modified`.
+///
+/// Json responses are built from the index, which only changes when the
+/// library is reindexed, so the index's content hash and modification time
+/// describe every one of them. Other responses, like covers, and responses
+/// that set their own `Cache-Control` are left alone.
+pub struct IndexCaching;
+
+impl<S> Transform<S> for IndexCaching
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse, Error = actix_web::Error>,
+    S::Future: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse;
+    type Error = actix_web::Error;
+    type Transform = IndexCachingMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<result::Result<Self::Transform, ()>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        future::ok(IndexCachingMiddleware { service })
+    }
+}
+
+pub struct IndexCachingMiddleware<S> {
+    service: S,
+}
+
+impl<S> Service for IndexCachingMiddleware<S>
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse, Error = actix_web::Error>,
+    S::Future: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse;
+    type Error = actix_web::Error;
+    type Future = Pin<Box<dyn Future<Output = result::Result<Self::Response, Self::Error>>>>;
+
+    fn poll_ready(&mut self, ctx: &mut Context<'_>) -> Poll<result::Result<(), Self::Error>> {
+        self.service.poll_ready(ctx)
+    }
+
+    fn call(&mut self, req: Self::Request) -> Self::Future {
+        let index = req.app_data::<web::Data<IndexHandle>>().cloned();
+        let if_none_match = header_str(&req, header::IF_NONE_MATCH);
+        let if_modified_since = header_str(&req, header::IF_MODIFIED_SINCE)
+            .and_then(|since| since.parse::<HttpDate>().ok())
+            .map(SystemTime::from);
+        let fut = self.service.call(req);
+
+        Box::pin(async move {
+            // the index is read before the handler runs, so a reindex in
+            // between can only make a response look older than it is
+            let (etag, modified) = match index {
+                Some(index) => {
+                    let index = index.get().await;
+                    (index.etag(), HttpDate::from(index.modified()))
+                }
+                None => return fut.await,
+            };
+
+            let mut res = fut.await?;
+            if res.status() != StatusCode::OK
+                || !is_json(&res)
+                || res.headers().contains_key(header::CACHE_CONTROL)
+            {
+                return Ok(res);
+            }
+
+            // If-Modified-Since is ignored when If-None-Match is given
+            let not_modified = match (&if_none_match, if_modified_since) {
+                (Some(if_none_match), _) => etag_matches(if_none_match, &etag),
+                (None, Some(since)) => since >= SystemTime::from(modified),
+                (None, None) => false,
+            };
+            if not_modified {
+                res = res.into_response(HttpResponse::NotModified().finish());
+            }
+
+            let headers = res.headers_mut();
+            if let Ok(etag) = HeaderValue::from_str(&etag) {
+                headers.insert(header::ETAG, etag);
+            }
+            if let Ok(modified) = HeaderValue::from_str(&modified.to_string()) {
+                headers.insert(header::LAST_MODIFIED, modified);
+            }
+            // clients have to check whether the library changed before reusing
+            // a response
+            headers.insert(header::CACHE_CONTROL, HeaderValue::from_static("no-cache"));
+
+            Ok(res)
+        })
+    }
+}
+
+fn header_str(req: &ServiceRequest, name: header::HeaderName) -> Option<String> {
+    req.headers()
+        .get(name)
+        .and_then(|value| value.to_str().ok())
+        .map(|value| value.to_string())
+}
+
+fn is_json(res: &ServiceResponse) -> bool {
+    res.headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .map_or(false, |content_type| {
+            content_type.starts_with("application/json")
+        })
+}
+
+/// Checks whether an `If-None-Match` header lists `etag`, comparing weakly.
+fn etag_matches(if_none_match: &str, etag: &str) -> bool {
+    if_none_match.trim() == "*"
+        || if_none_match
+            .split(',')
+            .map(|tag| tag.trim().trim_start_matches("W/"))
+            .any(|tag| tag == etag)
+}
@@ -0,0 +1,319 @@
+use crate::{
+    config::Config,
+    error::{Result, ResultExt},
+    path_error,
+    util::{
+        fs::{is_fresh, write_atomic},
+        hash::hash_path,
+    },
+};
+use actix_web::web::Bytes;
+use image::{imageops::FilterType, DynamicImage, ImageOutputFormat};
+use serde::Deserialize;
+use std::{
+    collections::HashMap,
+    convert::TryFrom,
+    fs,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::SystemTime,
+};
+use tokio::sync::Mutex;
+
+const COVERS_DIR: &str = "covers";
+pub const WEBP_QUALITY: f32 = 80.0;
+const JPEG_QUALITY: u8 = 85;
+/// Sizes in pixels that requested sizes are rounded up to, so only a few
+/// variants of each cover get cached.
+const SIZE_BUCKETS: [u32; 6] = [64, 128, 256, 512, 1024, 2048];
+/// Number of embedded covers kept in memory.
+const EMBEDDED_CACHE_SIZE: usize = 64;
+
+/// The formats covers can be converted to.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+pub enum CoverFormat {
+    WebP,
+    Jpeg,
+}
+
+impl CoverFormat {
+    fn extension(self) -> &'static str {
+        match self {
+            CoverFormat::WebP => "webp",
+            CoverFormat::Jpeg => "jpg",
+        }
+    }
+
+    pub fn content_type(self) -> &'static str {
+        match self {
+            CoverFormat::WebP => "image/webp",
+            CoverFormat::Jpeg => "image/jpeg",
+        }
+    }
+}
+
+/// The sizes covers can be scaled down to.
+///
+/// Deserializes from `original`, `medium`, `thumb` or a maximum dimension in
+/// pixels, which is rounded up to the next of the `SIZE_BUCKETS`.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Deserialize)]
+#[serde(try_from = "String")]
+pub enum CoverSize {
+    Original,
+    Medium,
+    Thumb,
+    Max(u32),
+}
+
+impl Default for CoverSize {
+    fn default() -> Self {
+        CoverSize::Original
+    }
+}
+
+impl TryFrom<String> for CoverSize {
+    type Error = String;
+
+    fn try_from(value: String) -> std::result::Result<Self, Self::Error> {
+        match value.as_str() {
+            "original" => Ok(CoverSize::Original),
+            "medium" => Ok(CoverSize::Medium),
+            "thumb" => Ok(CoverSize::Thumb),
+            _ => value
+                .parse::<u32>()
+                .map(CoverSize::from_pixels)
+                .map_err(|_| format!("invalid cover size: {}", value)),
+        }
+    }
+}
+
+impl CoverSize {
+    /// Rounds a maximum dimension up to the next size bucket, reusing the
+    /// named sizes where they match.
+    pub fn from_pixels(pixels: u32) -> CoverSize {
+        let bucket = SIZE_BUCKETS
+            .iter()
+            .copied()
+            .find(|&bucket| bucket >= pixels)
+            .unwrap_or(SIZE_BUCKETS[SIZE_BUCKETS.len() - 1]);
+
+        match bucket {
+            128 => CoverSize::Thumb,
+            512 => CoverSize::Medium,
+            _ => CoverSize::Max(bucket),
+        }
+    }
+
+    pub fn name(self) -> String {
+        match self {
+            CoverSize::Original => "original".to_string(),
+            CoverSize::Medium => "medium".to_string(),
+            CoverSize::Thumb => "thumb".to_string(),
+            CoverSize::Max(max) => max.to_string(),
+        }
+    }
+
+    /// The largest width and height of covers of this size.
+    fn max_dimension(self) -> Option<u32> {
+        match self {
+            CoverSize::Original => None,
+            CoverSize::Medium => Some(512),
+            CoverSize::Thumb => Some(128),
+            CoverSize::Max(max) => Some(max),
+        }
+    }
+}
+
+/// Stores converted versions of album covers in the cache directory.
+///
+/// Variants are keyed by album, source cover, size and format, and are
+/// regenerated whenever the original cover is newer than the cached variant.
+/// Keying by the source means picking another image as an album's cover never
+/// serves the old one, even when the new image is older.
+///
+/// Covers embedded in songs are kept in memory instead, since they are read
+/// straight from the songs and never written to disk.
+pub struct CoverCache {
+    dir: PathBuf,
+    locks: Mutex<HashMap<PathBuf, Arc<Mutex<()>>>>,
+    embedded: std::sync::Mutex<EmbeddedCovers>,
+}
+
+/// Encoded embedded covers by song path and format, evicting the least
+/// recently used cover when full.
+#[derive(Default)]
+struct EmbeddedCovers {
+    covers: HashMap<(PathBuf, CoverFormat), EmbeddedCover>,
+    uses: u64,
+}
+
+struct EmbeddedCover {
+    /// Modification time of the song the cover was read from.
+    modified: SystemTime,
+    data: Bytes,
+    last_used: u64,
+}
+
+impl CoverCache {
+    pub fn new(config: &Config) -> CoverCache {
+        CoverCache {
+            dir: config.cache_dir.join(COVERS_DIR),
+            locks: Default::default(),
+            embedded: Default::default(),
+        }
+    }
+
+    /// Gets a song's embedded cover encoded as `format`, if it was cached since
+    /// the song was last modified.
+    pub fn get_embedded(
+        &self,
+        song_path: &Path,
+        modified: SystemTime,
+        format: CoverFormat,
+    ) -> Option<Bytes> {
+        let mut embedded = self.embedded.lock().unwrap();
+        embedded.uses += 1;
+        let uses = embedded.uses;
+
+        let cover = embedded
+            .covers
+            .get_mut(&(song_path.to_path_buf(), format))
+            .filter(|cover| cover.modified == modified)?;
+        cover.last_used = uses;
+        Some(cover.data.clone())
+    }
+
+    pub fn insert_embedded(
+        &self,
+        song_path: &Path,
+        modified: SystemTime,
+        format: CoverFormat,
+        data: Bytes,
+    ) {
+        let mut embedded = self.embedded.lock().unwrap();
+        embedded.uses += 1;
+        let last_used = embedded.uses;
+
+        let key = (song_path.to_path_buf(), format);
+        if !embedded.covers.contains_key(&key) && embedded.covers.len() >= EMBEDDED_CACHE_SIZE {
+            let oldest = embedded
+                .covers
+                .iter()
+                .min_by_key(|(_, cover)| cover.last_used)
+                .map(|(key, _)| key.clone());
+            if let Some(oldest) = oldest {
+                embedded.covers.remove(&oldest);
+            }
+        }
+
+        embedded.covers.insert(
+            key,
+            EmbeddedCover {
+                modified,
+                data,
+                last_used,
+            },
+        );
+    }
+
+    /// Gets the path of a cover scaled down to `size` and converted to `format`,
+    /// converting it if there is no up-to-date conversion yet.
+    pub async fn get(
+        &self,
+        album_name: &str,
+        cover_path: &Path,
+        size: CoverSize,
+        format: CoverFormat,
+    ) -> Result<PathBuf> {
+        // a short prefix of the hash is plenty to tell an album's covers apart
+        let source = hash_path(cover_path);
+        let output = self.dir.join(format!(
+            "{}-{}-{}.{}",
+            album_name,
+            &source[..16],
+            size.name(),
+            format.extension()
+        ));
+
+        let lock = {
+            let mut locks = self.locks.lock().await;
+            locks.retain(|_, lock| Arc::strong_count(lock) > 1);
+            locks
+                .entry(output.clone())
+                .or_insert_with(Default::default)
+                .clone()
+        };
+        let _guard = lock.lock().await;
+
+        let cover_path = cover_path.to_path_buf();
+        let output_moved = output.clone();
+        tokio::task::spawn_blocking(move || {
+            if is_fresh(&output_moved, &cover_path) {
+                return Ok(());
+            }
+
+            debug!(
+                "Converting {} to {}",
+                cover_path.to_string_lossy(),
+                output_moved.to_string_lossy()
+            );
+            CoverCache::convert(&cover_path, &output_moved, size, format)
+        })
+        .await
+        .chain_err(path_error!(
+            TranscodeError,
+            output,
+            "converting cover off-thread"
+        ))??;
+
+        Ok(output)
+    }
+
+    fn convert(source: &Path, output: &Path, size: CoverSize, format: CoverFormat) -> Result<()> {
+        let mut image =
+            image::open(source).chain_err(path_error!(TranscodeError, source, "reading cover"))?;
+
+        if let Some(max) = size.max_dimension() {
+            // covers are never scaled up
+            if image.width() > max || image.height() > max {
+                image = image.resize(max, max, FilterType::Lanczos3);
+            }
+        }
+
+        let data = encode(&image, format).chain_err(path_error!(
+            TranscodeError,
+            output,
+            "encoding cover"
+        ))?;
+
+        let parent = output.parent().expect("BUG: Cover output has no parent");
+        fs::create_dir_all(parent).chain_err(path_error!(
+            TranscodeError,
+            parent,
+            "creating cover cache"
+        ))?;
+
+        write_atomic(output, &data).chain_err(path_error!(TranscodeError, output, "writing cover"))
+    }
+}
+
+/// Encodes a cover as `format`.
+pub fn encode(image: &DynamicImage, format: CoverFormat) -> image::ImageResult<Vec<u8>> {
+    match format {
+        CoverFormat::WebP => {
+            let image = image.to_rgba8();
+            Ok(
+                webp::Encoder::from_rgba(&image, image.width(), image.height())
+                    .encode(WEBP_QUALITY)
+                    .to_vec(),
+            )
+        }
+        CoverFormat::Jpeg => {
+            // jpeg has no alpha channel
+            let mut data = vec![];
+            DynamicImage::ImageRgb8(image.to_rgb8())
+                .write_to(&mut data, ImageOutputFormat::Jpeg(JPEG_QUALITY))?;
+            Ok(data)
+        }
+    }
+}
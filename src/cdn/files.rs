@@ -1,11 +1,14 @@
 use crate::{
-    config::Config,
-    error::{Error, ErrorKind, Result},
+    cdn::index::IndexHandle,
+    config::{Config, UrlScheme},
+    error::{Error, ErrorKind, Result, ResultExt},
+    metrics::Metrics,
 };
-use actix_files::Files;
+use actix_files::{Files, NamedFile};
 use actix_service::{Service, ServiceFactory, Transform};
 use actix_web::{
-    dev::{ServiceRequest, ServiceResponse},
+    dev::{BodyEncoding, ServiceRequest, ServiceResponse},
+    http::{header, ContentEncoding, HeaderValue, Method},
     web, Scope,
 };
 use futures::{
@@ -13,7 +16,12 @@ use futures::{
     future::{ok, Either, Ready},
     task::{Context, Poll},
 };
-use std::{future::Future, path::PathBuf, pin::Pin, result};
+use std::{
+    future::Future,
+    path::{Path, PathBuf},
+    pin::Pin,
+    result,
+};
 
 pub fn apply_services(
     config: &Config,
@@ -26,11 +34,154 @@ pub fn apply_services(
         InitError = (),
     >,
 > {
-    web::scope("/files")
+    let cache_control = FileCacheControl::new(config);
+    let scope = web::scope("/files")
         .wrap(FilesLimiter {
             config: config.clone(),
         })
-        .service(Files::new("", &config.base_dir))
+        // media files are already compressed
+        .wrap_fn(move |req, srv| {
+            let fut = srv.call(req);
+            let cache_control = cache_control.clone();
+            async move {
+                let mut res = fut.await?;
+                res.response_mut().encoding(ContentEncoding::Identity);
+
+                // ids have no extension, so get_file_by_id sets the type itself
+                if res.status().is_success() {
+                    if let Some(content_type) = audio_content_type(Path::new(res.request().path()))
+                    {
+                        res.headers_mut()
+                            .insert(header::CONTENT_TYPE, HeaderValue::from_static(content_type));
+                    }
+                }
+                cache_control.apply(&mut res);
+
+                // HEAD responses announce a length without sending a body
+                let is_head = res.request().method() == Method::HEAD;
+                let metrics = res.request().app_data::<web::Data<Metrics>>().cloned();
+                if let Some(metrics) = metrics.filter(|_| !is_head) {
+                    let length = res
+                        .headers()
+                        .get(header::CONTENT_LENGTH)
+                        .and_then(|length| length.to_str().ok())
+                        .and_then(|length| length.parse::<u64>().ok());
+                    metrics.add_bytes_served(length.unwrap_or(0));
+                }
+
+                Ok(res)
+            }
+        });
+
+    match config.url_scheme {
+        UrlScheme::Path => scope.service(Files::new("", &config.base_dir)),
+        UrlScheme::Id => scope.service(get_file_by_id),
+    }
+}
+
+/// Serves covers generated into `cover-cache-dir`. Covers served by id go
+/// through the files scope instead.
+pub fn apply_generated_covers(
+    config: &Config,
+) -> Scope<
+    impl ServiceFactory<
+        Config = (),
+        Request = ServiceRequest,
+        Response = ServiceResponse,
+        Error = actix_web::Error,
+        InitError = (),
+    >,
+> {
+    let cache_control = FileCacheControl::new(config);
+    let scope = web::scope("/generated-covers").wrap_fn(move |req, srv| {
+        let fut = srv.call(req);
+        let cache_control = cache_control.clone();
+        async move {
+            let mut res = fut.await?;
+            cache_control.apply(&mut res);
+            Ok(res)
+        }
+    });
+
+    match (&config.cover_cache_dir, config.url_scheme) {
+        (Some(dir), UrlScheme::Path) => scope.service(Files::new("", dir)),
+        _ => scope,
+    }
+}
+
+#[get("/{id}")]
+async fn get_file_by_id(
+    index: web::Data<IndexHandle>,
+    web::Path(id): web::Path<String>,
+) -> Result<NamedFile> {
+    let index = index.get().await;
+    let path = index
+        .file_path(&id)
+        .ok_or_else(|| ErrorKind::NoSuchResource("file", id.clone()))?;
+
+    let mut file =
+        NamedFile::open(path).chain_err(|| ErrorKind::NoSuchResource("file", id.clone()))?;
+    if let Some(content_type) = audio_content_type(path) {
+        file = file.set_content_type(content_type.parse().unwrap());
+    }
+
+    Ok(file)
+}
+
+/// Gets the content type of audio files by extension. Guessed content types
+/// are missing or generic for some audio formats, and some browsers refuse to
+/// play audio without a specific one.
+fn audio_content_type(path: &Path) -> Option<&'static str> {
+    let extension = path.extension()?.to_str()?.to_ascii_lowercase();
+    match extension.as_str() {
+        "flac" => Some("audio/flac"),
+        "mp3" => Some("audio/mpeg"),
+        "ogg" | "oga" | "opus" => Some("audio/ogg"),
+        "m4a" => Some("audio/mp4"),
+        "aac" => Some("audio/aac"),
+        "wav" => Some("audio/wav"),
+        _ => None,
+    }
+}
+
+/// `Cache-Control` headers of served files by their content type.
+#[derive(Clone)]
+struct FileCacheControl {
+    cover: Option<HeaderValue>,
+    audio: Option<HeaderValue>,
+}
+
+impl FileCacheControl {
+    fn new(config: &Config) -> FileCacheControl {
+        FileCacheControl {
+            cover: config.files_cover_cache_control.clone(),
+            audio: config.files_audio_cache_control.clone(),
+        }
+    }
+
+    fn apply<B>(&self, res: &mut ServiceResponse<B>) {
+        if !res.status().is_success() {
+            return;
+        }
+
+        let content_type = res
+            .headers()
+            .get(header::CONTENT_TYPE)
+            .and_then(|content_type| content_type.to_str().ok())
+            .unwrap_or_default();
+        let cache_control = if content_type.starts_with("image/") {
+            &self.cover
+        } else if content_type.starts_with("audio/") {
+            &self.audio
+        } else {
+            return;
+        };
+
+        if let Some(cache_control) = cache_control.clone() {
+            res.headers_mut()
+                .insert(header::CACHE_CONTROL, cache_control);
+        }
+    }
 }
 
 struct FilesLimiter {
@@ -82,6 +233,11 @@ where
     }
 
     fn call(&mut self, req: Self::Request) -> Self::Future {
+        // ids only ever refer to files the index accepted
+        if self.config.url_scheme == UrlScheme::Id {
+            return Either::Right(Box::pin(self.service.call(req)));
+        }
+
         let real_path = match parse_path(req.match_info().path(), false) {
             Ok(item) => item,
             Err(e) => return Either::Left(ok(req.error_response(e))),
@@ -98,6 +254,8 @@ where
             && !self.config.media_exclude_patterns.is_match(&path_str))
             || (self.config.cover_include_patterns.is_match(&path_str)
                 && !self.config.cover_exclude_patterns.is_match(&path_str))
+            || (self.config.document_include_patterns.is_match(&path_str)
+                && !self.config.document_exclude_patterns.is_match(&path_str))
         {
             Either::Right(Box::pin(self.service.call(req)))
         } else {
@@ -140,3 +298,54 @@ fn parse_path(path: &str, hidden_files: bool) -> Result<PathBuf> {
 
     Ok(buf)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::{http::StatusCode, rt::System, test, App};
+    use std::fs;
+
+    #[test]
+    fn audio_content_types_by_extension() {
+        let content_type = |file: &str| audio_content_type(Path::new(file));
+
+        assert_eq!(content_type("song.flac"), Some("audio/flac"));
+        assert_eq!(content_type("song.mp3"), Some("audio/mpeg"));
+        assert_eq!(content_type("song.ogg"), Some("audio/ogg"));
+        assert_eq!(content_type("song.opus"), Some("audio/ogg"));
+        assert_eq!(content_type("song.m4a"), Some("audio/mp4"));
+        assert_eq!(content_type("song.aac"), Some("audio/aac"));
+        assert_eq!(content_type("song.wav"), Some("audio/wav"));
+        assert_eq!(content_type("Album/SONG.FLAC"), Some("audio/flac"));
+
+        assert_eq!(content_type("cover.jpg"), None);
+        assert_eq!(content_type("song.xyz"), None);
+        assert_eq!(content_type("song"), None);
+    }
+
+    #[test]
+    fn unsatisfiable_ranges_get_416() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("song.mp3"), vec![0u8; 1000]).unwrap();
+        let config = Config::from_toml(&format!(
+            "[general]\nbase-dir = {:?}\nurl-scheme = \"path\"",
+            dir.path()
+        ))
+        .unwrap();
+
+        System::new("test").block_on(async move {
+            let mut app = test::init_service(App::new().service(apply_services(&config))).await;
+            let req = test::TestRequest::get()
+                .uri("/files/song.mp3")
+                .header(header::RANGE, "bytes=99999999-")
+                .to_request();
+            let res = test::call_service(&mut app, req).await;
+
+            assert_eq!(res.status(), StatusCode::RANGE_NOT_SATISFIABLE);
+            assert_eq!(
+                res.headers().get(header::CONTENT_RANGE).unwrap(),
+                "bytes */1000"
+            );
+        });
+    }
+}
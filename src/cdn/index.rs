@@ -1,32 +1,71 @@
 use crate::{
+    cdn::{
+        caching::IndexCaching,
+        covers,
+        covers::{CoverCache, CoverFormat, CoverSize, WEBP_QUALITY},
+        manifest::Manifest,
+        metadata::{self, DirectoryMetadata, COVER_FILE, METADATA_FILE},
+        names::UniqueNames,
+        ratings::{Rating, Ratings, MAX_RATING},
+        transcode::{StreamFormat, TranscodeCache, HLS_PLAYLIST},
+        GENERATED_COVERS_URL,
+    },
+    config::{
+        ArtistGrouping, Config, CoverTiebreak, DuplicateTracks, GeneratedCoverFormat,
+        SharedDirectoryCovers, UrlScheme,
+    },
     error::{ErrorKind, Result, ResultExt},
-    util::w_ok,
+    metrics::Metrics,
+    path_error,
+    util::{
+        auth::require_admin,
+        body::BodyWriter,
+        ffmpeg,
+        fs::{is_fresh, write_atomic},
+        hash::hash_file,
+        w_ok, zip,
+        zip::ZipWriter,
+    },
+    INDEX_URL,
 };
-use actix_web::{dev::HttpResponseBuilder, http::StatusCode, web, HttpResponse, Scope};
+use actix_files::NamedFile;
+use actix_service::ServiceFactory;
+use actix_web::{
+    dev::{BodyEncoding, HttpResponseBuilder, ServiceRequest, ServiceResponse},
+    http::{header, ContentEncoding, StatusCode},
+    web,
+    web::Bytes,
+    HttpRequest, HttpResponse, Responder, Scope,
+};
+use deunicode::{deunicode_char, deunicode_with_tofu};
+use error_chain::ChainedError;
 use ffmpeg4::{format, frame, media, software, DictionaryRef};
-use futures::{stream, StreamExt};
-use image::ColorType;
+use futures::{channel::mpsc, executor, stream, SinkExt, StreamExt};
+use image::{
+    codecs::{jpeg::JpegEncoder, png::PngEncoder},
+    ColorType, DynamicImage, ImageEncoder, RgbaImage,
+};
+use notify::DebouncedEvent;
 use path_slash::PathExt;
 use percent_encoding::{utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+use rand::seq::SliceRandom;
 use regex::{Regex, RegexSet};
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 use std::{
     borrow::Cow,
-    collections::HashMap,
-    path::{Path, PathBuf},
-    sync::Arc,
-    time::SystemTime,
+    cmp::Reverse,
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
+    fs,
+    path::{Component, Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, AtomicU32, Ordering},
+        Arc,
+    },
+    thread,
+    time::{Duration, Instant, SystemTime},
 };
-use tokio::sync::RwLock;
-
-macro_rules! indexing_error {
-    ($path:expr, $desc:expr) => {
-        || {
-            let path: &Path = $path.as_ref();
-            ErrorKind::IndexingError(Some(path.to_string_lossy().to_string()), $desc)
-        }
-    };
-}
+use tokio::sync::{Mutex, RwLock};
 
 #[derive(Debug)]
 pub struct Index {
@@ -34,12 +73,215 @@ pub struct Index {
     artist_list: Vec<Arc<RwLock<Artist>>>,
     albums: HashMap<String, Arc<RwLock<Album>>>,
     album_list: Vec<Arc<RwLock<Album>>>,
+    urls: FileUrls,
+    names: UniqueNames,
+    /// Shared by every index, since ratings outlive reindexes.
+    ratings: Arc<Ratings>,
+    // kept for reindexing
+    config: Config,
+    base_url: String,
+    /// How long building this index took.
+    build_time: Duration,
+    /// When the indexed data last changed.
+    modified: SystemTime,
+    /// Hash of everything the index serves, which changes whenever the data
+    /// does.
+    content_hash: String,
+    /// Songs that claimed a track another song of their album already had.
+    duplicates: Vec<DuplicateTrack>,
+    /// Unique names of artists by the name they are grouped by.
+    artist_keys: HashMap<String, String>,
+    /// Every path found walking the library, in walk order, so reindexing an
+    /// album only has to walk that album's directories again.
+    walked: Vec<PathBuf>,
+}
+
+/// Shares the current index between requests and swaps in a new one when the
+/// library is reindexed.
+pub struct IndexHandle {
+    index: RwLock<Arc<Index>>,
+    reindexing: Mutex<()>,
+    /// Whether the library has been indexed at least once.
+    ready: AtomicBool,
+    /// Records reindex durations when metrics are enabled.
+    metrics: Option<Arc<Metrics>>,
+    progress: Arc<IndexProgress>,
+}
+
+/// Counts what the running index has found so far, so clients can follow
+/// along while a large library is indexed.
+#[derive(Debug, Default)]
+pub struct IndexProgress {
+    indexing: AtomicBool,
+    media_files: AtomicU32,
+    files_scanned: AtomicU32,
+    songs_found: AtomicU32,
+    covers_generated: AtomicU32,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+struct IndexProgressJson {
+    indexing: bool,
+    /// Media files found while walking the music directory.
+    media_files: u32,
+    files_scanned: u32,
+    songs_found: u32,
+    covers_generated: u32,
+}
+
+impl IndexProgress {
+    fn start(&self) {
+        self.media_files.store(0, Ordering::Relaxed);
+        self.files_scanned.store(0, Ordering::Relaxed);
+        self.songs_found.store(0, Ordering::Relaxed);
+        self.covers_generated.store(0, Ordering::Relaxed);
+        self.indexing.store(true, Ordering::Release);
+    }
+
+    fn finish(&self) {
+        self.indexing.store(false, Ordering::Release);
+    }
+
+    fn to_json(&self) -> IndexProgressJson {
+        IndexProgressJson {
+            indexing: self.indexing.load(Ordering::Acquire),
+            media_files: self.media_files.load(Ordering::Relaxed),
+            files_scanned: self.files_scanned.load(Ordering::Relaxed),
+            songs_found: self.songs_found.load(Ordering::Relaxed),
+            covers_generated: self.covers_generated.load(Ordering::Relaxed),
+        }
+    }
+}
+
+impl IndexHandle {
+    /// Creates a handle to an empty index that isn't ready until `load` has
+    /// indexed the library, so the server can start before indexing finishes.
+    pub fn empty(config: &Config, base_url: &str, metrics: Option<Arc<Metrics>>) -> IndexHandle {
+        IndexHandle {
+            index: RwLock::new(Arc::new(Index::empty(
+                config,
+                base_url,
+                Arc::new(Ratings::load(config)),
+            ))),
+            reindexing: Mutex::new(()),
+            ready: AtomicBool::new(false),
+            metrics,
+            // the first index starts right away
+            progress: Arc::new(IndexProgress {
+                indexing: AtomicBool::new(true),
+                ..Default::default()
+            }),
+        }
+    }
+
+    /// Indexes the library for the first time, marking the index as ready.
+    pub async fn load(&self) -> Result<()> {
+        self.reindex().await?;
+        self.ready.store(true, Ordering::Release);
+        Ok(())
+    }
+
+    pub fn is_ready(&self) -> bool {
+        self.ready.load(Ordering::Acquire)
+    }
+
+    /// Gets the current index. Requests keep using the index they got even if
+    /// a reindex finishes in the meantime.
+    pub async fn get(&self) -> Arc<Index> {
+        self.index.read().await.clone()
+    }
+
+    /// Reindexes the library and replaces the current index with the result.
+    /// Only one reindex runs at a time.
+    pub async fn reindex(&self) -> Result<Arc<Index>> {
+        self.reindex_probing(&[], None).await
+    }
+
+    /// Reindexes one album, walking only the directories its songs are in and
+    /// always probing its songs again, even if they look unchanged. Taggers
+    /// often keep the modification time of the files they edit.
+    pub async fn reindex_album(&self, album_name: &str) -> Result<Arc<Index>> {
+        let (paths, dirs) = {
+            let index = self.get().await;
+            let album = index
+                .albums
+                .get(album_name)
+                .ok_or_else(|| ErrorKind::NoSuchResource("album", album_name.to_string()))?;
+            let album = album.read().await;
+
+            let mut paths = vec![];
+            let mut dirs = BTreeSet::new();
+            for song in album.songs_by_name.values() {
+                let path = song.read().await.path.clone();
+                if let Some(parent) = path.parent() {
+                    dirs.insert(parent.to_path_buf());
+                }
+                paths.push(path);
+            }
+            (paths, dirs.into_iter().collect::<Vec<_>>())
+        };
+
+        self.reindex_probing(&paths, Some(&dirs)).await
+    }
+
+    async fn reindex_probing(
+        &self,
+        reprobe: &[PathBuf],
+        dirs: Option<&[PathBuf]>,
+    ) -> Result<Arc<Index>> {
+        let _guard = self.reindexing.lock().await;
+
+        let start = Instant::now();
+        let current = self.get().await;
+        self.progress.start();
+        let index = current.reindex(reprobe, dirs, &self.progress).await;
+        self.progress.finish();
+        let mut index = index?;
+        if let Some(metrics) = &self.metrics {
+            metrics.record_reindex(start.elapsed());
+        }
+        // clients' cached responses stay valid if nothing changed
+        if index.content_hash == current.content_hash {
+            index.modified = current.modified;
+        }
+
+        let index = Arc::new(index);
+        *self.index.write().await = index.clone();
+
+        Ok(index)
+    }
+
+    /// Reindexes the library if any of the file system events reported by the
+    /// watcher can change the index. Returns whether a reindex happened.
+    pub async fn apply_fs_events(&self, events: &[DebouncedEvent]) -> Result<bool> {
+        let index = self.get().await;
+        if !events.iter().any(|event| index.is_affected_by(event)) {
+            return Ok(false);
+        }
+        drop(index);
+
+        self.reindex().await?;
+        Ok(true)
+    }
+}
+
+/// Builds the urls files are served at, remembering which file each id refers
+/// to when files are served by id.
+#[derive(Debug)]
+struct FileUrls {
+    scheme: UrlScheme,
+    base_dir: PathBuf,
+    cover_cache_dir: Option<PathBuf>,
+    files_url: String,
+    ids: HashMap<String, PathBuf>,
 }
 
 #[derive(Debug)]
 pub struct Artist {
     name: String,
     unique_name: String,
+    /// `name` folded for searching.
+    search_key: String,
     albums: HashMap<String, Arc<RwLock<Album>>>,
     cover_url: Option<String>,
 }
@@ -48,25 +290,183 @@ pub struct Artist {
 pub struct Album {
     name: String,
     unique_name: String,
+    /// `name` folded for searching.
+    search_key: String,
     artists: Vec<ArtistRef>,
+    /// All songs in play order: the tracks of each disc, with gaps for missing
+    /// tracks, followed by the untracked songs.
     songs: Vec<Option<Arc<RwLock<Song>>>>,
+    /// Tracked songs by disc number. Songs without a disc number are on disc 1.
+    discs: BTreeMap<u32, Vec<Option<Arc<RwLock<Song>>>>>,
+    /// Songs without a track number by lowercased name and then path, so they
+    /// are in the same order regardless of directory walk order.
+    untracked: BTreeMap<(String, PathBuf), Arc<RwLock<Song>>>,
     songs_by_name: HashMap<String, Arc<RwLock<Song>>>,
     cover_url: Option<String>,
-    cover_rating: u32,
+    cover_path: Option<PathBuf>,
+    cover_size: Option<(u32, u32)>,
+    cover_rating: CoverRating,
+    /// Whether the cover was extracted from the songs' embedded art.
+    cover_generated: bool,
     tracked: bool,
+    year: Option<u32>,
+    /// Genres of the album's songs, the most common first.
+    genres: Vec<String>,
+    /// Number of discs, from the songs' total disc tags or else the highest
+    /// disc number seen.
+    disc_total: Option<u32>,
+    /// Number of tracks on each disc, from the songs' total track tags.
+    track_totals: BTreeMap<u32, u32>,
+    documents: Vec<String>,
     path: PathBuf,
 }
 
+/// The tags read from a song file.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SongMetadata {
+    title: Option<String>,
+    album: Option<String>,
+    /// Every artist tag, for files with repeated artist tags.
+    artists: Vec<String>,
+    album_artist: Option<String>,
+    track: Option<u32>,
+    track_total: Option<u32>,
+    year: Option<u32>,
+    /// Every genre tag, for files with repeated genre tags.
+    genres: Vec<String>,
+    disc: Option<u32>,
+    disc_total: Option<u32>,
+    composer: Option<String>,
+    comment: Option<String>,
+    replay_gain: ReplayGain,
+    /// Bit rate of the audio stream, or else of the whole file, in bit/s.
+    bit_rate: Option<u64>,
+    /// Sample rate in Hz.
+    sample_rate: Option<u32>,
+    channels: Option<u16>,
+    /// Length in seconds.
+    duration: Option<f64>,
+}
+
 #[derive(Debug, Clone)]
 pub struct Song {
     name: String,
     unique_name: String,
+    /// `name` folded for searching.
+    search_key: String,
     album: AlbumRef,
     artists: Vec<ArtistRef>,
+    /// The artists the album belongs to, if they differ from the song's
+    /// artists, like for compilations.
+    album_artists: Vec<ArtistRef>,
     track: Option<u32>,
+    track_total: Option<u32>,
     cover_url: Option<String>,
     url: String,
     path: PathBuf,
+    missing: MissingTags,
+    year: Option<u32>,
+    genres: Vec<String>,
+    disc: Option<u32>,
+    disc_total: Option<u32>,
+    composer: Option<String>,
+    comment: Option<String>,
+    replay_gain: ReplayGain,
+    bit_rate: Option<u64>,
+    sample_rate: Option<u32>,
+    channels: Option<u16>,
+    /// Length in seconds.
+    duration: Option<f64>,
+}
+
+/// ReplayGain tags, letting players normalize loudness without analyzing the
+/// audio themselves.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct ReplayGain {
+    /// Gain in dB.
+    track_gain: Option<f32>,
+    track_peak: Option<f32>,
+    /// Gain in dB.
+    album_gain: Option<f32>,
+    album_peak: Option<f32>,
+}
+
+impl ReplayGain {
+    fn is_complete(&self) -> bool {
+        self.track_gain.is_some()
+            && self.track_peak.is_some()
+            && self.album_gain.is_some()
+            && self.album_peak.is_some()
+    }
+
+    fn or(self, other: ReplayGain) -> ReplayGain {
+        ReplayGain {
+            track_gain: self.track_gain.or(other.track_gain),
+            track_peak: self.track_peak.or(other.track_peak),
+            album_gain: self.album_gain.or(other.album_gain),
+            album_peak: self.album_peak.or(other.album_peak),
+        }
+    }
+}
+
+/// A set of tags that a song was missing and that had to be guessed.
+#[derive(Debug, Copy, Clone, Default, Eq, PartialEq)]
+pub struct MissingTags(u8);
+
+impl MissingTags {
+    const TITLE: MissingTags = MissingTags(1);
+    const ALBUM: MissingTags = MissingTags(1 << 1);
+    const ARTIST: MissingTags = MissingTags(1 << 2);
+    const TRACK: MissingTags = MissingTags(1 << 3);
+
+    const NAMES: [(MissingTags, &'static str); 4] = [
+        (MissingTags::TITLE, "title"),
+        (MissingTags::ALBUM, "album"),
+        (MissingTags::ARTIST, "artist"),
+        (MissingTags::TRACK, "track"),
+    ];
+
+    fn set(&mut self, tags: MissingTags, missing: bool) {
+        if missing {
+            self.0 |= tags.0;
+        } else {
+            self.0 &= !tags.0;
+        }
+    }
+
+    fn intersects(self, tags: MissingTags) -> bool {
+        self.0 & tags.0 != 0
+    }
+
+    fn names(self) -> Vec<&'static str> {
+        MissingTags::NAMES
+            .iter()
+            .filter(|(tags, _)| self.intersects(*tags))
+            .map(|(_, name)| *name)
+            .collect()
+    }
+
+    /// Parses a comma-separated list of tag names.
+    fn parse(s: &str) -> Result<MissingTags> {
+        let mut tags = MissingTags::default();
+        for name in s.split(',').map(|name| name.trim()) {
+            let found = MissingTags::NAMES
+                .iter()
+                .find(|(_, n)| *n == name)
+                .chain_err(|| ErrorKind::InvalidQuery(format!("Unknown tag: {}", name)))?;
+            tags.set(found.0, true);
+        }
+
+        Ok(tags)
+    }
+}
+
+/// How well a file fits as an album's cover. Covers matching the configured
+/// priority list always beat covers rated by the heuristic score.
+#[derive(Debug, Copy, Clone, Default, Eq, PartialEq, Ord, PartialOrd, Serialize)]
+pub struct CoverRating {
+    priority: u32,
+    score: u32,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -87,53 +487,146 @@ pub struct SongRef {
     unique_name: String,
 }
 
+/// Two songs claiming the same track of an album. Only the kept song is listed
+/// in the album, but the dropped one can still be looked up by name.
+#[derive(Debug, Clone, Serialize)]
+struct DuplicateTrack {
+    album: AlbumRef,
+    disc: u32,
+    track: u32,
+    kept: DuplicateSong,
+    dropped: DuplicateSong,
+}
+
+#[derive(Debug, Clone, Serialize)]
+struct DuplicateSong {
+    unique_name: String,
+    url: String,
+    bit_rate: Option<u64>,
+}
+
+impl DuplicateSong {
+    fn from_song(song: &Song) -> DuplicateSong {
+        DuplicateSong {
+            unique_name: song.unique_name.clone(),
+            url: song.url.clone(),
+            bit_rate: song.bit_rate,
+        }
+    }
+}
+
+/// Which part of the library an index walks.
+enum Walk<'a> {
+    /// The whole library.
+    All,
+    /// Only the files directly in `dirs`, reusing the rest of `previous`.
+    Dirs {
+        dirs: &'a [PathBuf],
+        previous: &'a [PathBuf],
+    },
+}
+
+const SINGLES_ALBUM_NAME: &str = "Singles";
+/// Marks the names of covers extracted from songs, which the watcher ignores.
+const GENERATED_COVER_MARKER: &str = "-ms1-cover-small-generated.";
+/// Maximum number of results of each kind returned by a search.
+const SEARCH_LIMIT: usize = 50;
+/// Number of items in a page of a listing when no limit is given.
+const DEFAULT_PAGE_LIMIT: usize = 100;
+/// Largest number of items a page of a listing can have.
+const MAX_PAGE_LIMIT: usize = 1000;
+/// Number of random songs returned when no count is given.
+const DEFAULT_RANDOM_COUNT: usize = 10;
+/// Largest number of random songs returned at once.
+const MAX_RANDOM_COUNT: usize = 100;
+/// Number of chunks of a zip read ahead of a slow client.
+const ZIP_BUFFER_CHUNKS: usize = 4;
+/// How often indexing progress is checked for changes.
+const PROGRESS_INTERVAL: Duration = Duration::from_millis(500);
+/// Bit rates in kbit/s songs can be transcoded to on demand.
+const MIN_TRANSCODE_BIT_RATE: usize = 32;
+const MAX_TRANSCODE_BIT_RATE: usize = 320;
+
+/// Keys of the artist tags, with the raw iTunes atom and RIFF INFO names as
+/// fallbacks for files ffmpeg doesn't translate the tags of.
+const ARTIST_KEYS: &[&str] = &["artist", "©ART", "IART"];
+const GENRE_KEYS: &[&str] = &["genre", "©gen", "IGNR"];
+
 lazy_static::lazy_static! {
-static ref TRACK_INFO_TRACK_PATTERN: Regex = Regex::new("(?P<track>\\d+)(/\\d+)?").unwrap();
+static ref TRACK_INFO_TRACK_PATTERN: Regex = Regex::new("(?P<track>\\d+)(/(?P<total>\\d+))?").unwrap();
+static ref YEAR_PATTERN: Regex = Regex::new("(?:^|\\D)(?P<year>\\d{4})(?:\\D|$)").unwrap();
+static ref DISC_INFO_PATTERN: Regex = Regex::new("(?P<disc>\\d+)(/(?P<total>\\d+))?").unwrap();
 static ref FILENAME_STRIP_SUFFIX: Regex = Regex::new("(?P<name>.+)\\.[^.]+$").unwrap();
-static ref ARTIST_SPLIT_PATTERN: Regex = Regex::new("( +& +| *, +)").unwrap();
+static ref LRC_TIME_TAGS: Regex = Regex::new("^(\\[\\d+:\\d+([.:]\\d+)?\\])+").unwrap();
+static ref LRC_ID_TAG: Regex = Regex::new("^\\[[a-zA-Z]+:.*\\]$").unwrap();
 static ref PATH_SET: AsciiSet = NON_ALPHANUMERIC.remove(b'/').remove(b'-').remove(b'_').remove(b'.').remove(b'+');
 }
 
 impl Song {
-    async fn parse(path: &Path, base: &Path, files_url: &str) -> Result<Song> {
-        let url = find_url(&path, &base, &files_url)?;
-        let path_moved = path.to_path_buf();
-
-        let res: Result<_> = tokio::task::spawn_blocking(move || {
-            let context = format::input(&path_moved)
-                .chain_err(indexing_error!(path_moved, "probing media file"))?;
+    /// Gets the tags of a song file, only probing it if it changed since it was
+    /// recorded in the manifest. Can run concurrently for many files.
+    async fn read_metadata(
+        path: &Path,
+        config: &Config,
+        manifest: &Manifest,
+    ) -> Result<SongMetadata> {
+        manifest
+            .get_or_probe(path, || Song::probe(path, config))
+            .await
+    }
 
-            trace!("Format Metadata:");
-            let metadata = context.metadata();
-            for (key, value) in metadata.iter() {
-                trace!("  '{}': '{}'", key, value);
-            }
-            let mut title = Song::find_title(&metadata);
-            let mut album = Song::find_album(&metadata);
-            let mut artist = Song::find_artist(&metadata);
-            let mut track = Song::find_track(&metadata);
+    fn parse(
+        path: &Path,
+        metadata: SongMetadata,
+        config: &Config,
+        urls: &mut FileUrls,
+    ) -> Result<Song> {
+        let url = urls.url(path)?;
+        let SongMetadata {
+            mut title,
+            album,
+            artists,
+            album_artist,
+            track,
+            track_total,
+            year,
+            genres,
+            disc,
+            disc_total,
+            composer,
+            comment,
+            replay_gain,
+            bit_rate,
+            sample_rate,
+            channels,
+            duration,
+        } = metadata;
 
-            for (index, stream) in context.streams().enumerate() {
-                if !(title.is_none() || album.is_none() || artist.is_none() || track.is_none()) {
-                    break;
-                }
+        // names differing only by whitespace are the same
+        title = title
+            .as_deref()
+            .map(collapse_whitespace)
+            .filter(|title| !title.is_empty());
+        let album = album
+            .as_deref()
+            .map(collapse_whitespace)
+            .filter(|album| !album.is_empty());
+        let artists: Vec<_> = artists
+            .iter()
+            .map(|artist| collapse_whitespace(artist))
+            .filter(|artist| !artist.is_empty())
+            .collect();
 
-                trace!("Stream {} Metadata:", index);
-                let metadata = stream.metadata();
-                for (key, value) in metadata.iter() {
-                    trace!("  '{}': '{}'", key, value);
-                }
-                title = title.or_else(|| Song::find_title(&metadata));
-                album = album.or_else(|| Song::find_album(&metadata));
-                artist = artist.or_else(|| Song::find_artist(&metadata));
-                track = track.or_else(|| Song::find_track(&metadata));
-            }
+        let directory_artist = Song::find_directory_artist(path, config);
 
-            Ok((title, album, artist, track))
-        })
-        .await
-        .chain_err(indexing_error!(path, "running ffmpeg to probe media file"))?;
-        let (mut title, album, artist, track) = res?;
+        let mut missing = MissingTags::default();
+        missing.set(MissingTags::TITLE, title.is_none());
+        missing.set(MissingTags::ALBUM, album.is_none());
+        missing.set(
+            MissingTags::ARTIST,
+            artists.is_empty() && directory_artist.is_none(),
+        );
+        missing.set(MissingTags::TRACK, track.is_none());
 
         if title.is_none() {
             title = path.file_name().map(|n| n.to_string_lossy()).and_then(|n| {
@@ -144,811 +637,3829 @@ impl Song {
             })
         }
 
-        let title = title.unwrap_or("Unknown".to_string());
+        let title = title.unwrap_or_else(|| config.unknown_title.clone());
+        let (directory_album, directory_tag_artist) = Song::find_directory_tags(path, config);
+
+        let mut tag_artists = vec![];
+        for artist in artists.iter() {
+            for name in Song::split_artists(artist, config) {
+                if !tag_artists.contains(&name) {
+                    tag_artists.push(name);
+                }
+            }
+        }
+        if tag_artists.is_empty() {
+            tag_artists.push(directory_tag_artist.unwrap_or_else(|| config.unknown_artist.clone()));
+        }
+
+        // directory artists own both the songs and the albums
+        let (artists, album_artists) = match (directory_artist, &composer) {
+            (Some(artist), _) => (vec![artist], vec![]),
+            // the performers keep the songs, but the composer gets the album
+            (None, Some(composer)) if config.artist_grouping == ArtistGrouping::Composer => {
+                (tag_artists, Song::split_artists(composer, config))
+            }
+            (None, _) => (
+                tag_artists,
+                album_artist
+                    .map(|album_artist| Song::split_artists(&album_artist, config))
+                    .unwrap_or_default(),
+            ),
+        };
 
         Ok(Song {
             unique_name: sanitize(&title),
+            search_key: search_key(&title),
             name: title,
             album: AlbumRef {
-                name: album.unwrap_or("Unknown".to_string()),
+                name: album
+                    .or(directory_album)
+                    .unwrap_or_else(|| config.unknown_album.clone()),
                 unique_name: "".to_string(),
             },
-            artists: ARTIST_SPLIT_PATTERN
-                .split(&artist.unwrap_or("Unknown".to_string()))
-                .map(|s| ArtistRef {
-                    name: s.to_string(),
+            artists: artists
+                .into_iter()
+                .map(|name| ArtistRef {
+                    name,
+                    unique_name: "".to_string(),
+                })
+                .collect(),
+            album_artists: album_artists
+                .into_iter()
+                .map(|name: String| ArtistRef {
+                    name,
                     unique_name: "".to_string(),
                 })
                 .collect(),
             track,
+            track_total,
             cover_url: None,
             url,
             path: path.to_path_buf(),
+            missing,
+            year,
+            genres,
+            disc,
+            disc_total,
+            composer,
+            comment,
+            replay_gain,
+            bit_rate,
+            sample_rate,
+            channels,
+            duration,
         })
     }
 
-    fn find_title(dict: &DictionaryRef) -> Option<String> {
-        dict.get("title")
-            .or_else(|| dict.get("TITLE"))
-            .map(|s| s.to_string())
-    }
+    /// Splits an artist tag listing several artists, leaving the configured
+    /// exceptions whole wherever they appear.
+    fn split_artists(artists: &str, config: &Config) -> Vec<String> {
+        let exceptions: Vec<_> = config
+            .artist_split_exceptions
+            .iter()
+            .flat_map(|exception| {
+                artists
+                    .match_indices(exception.as_str())
+                    .map(|(start, found)| start..start + found.len())
+            })
+            .collect();
 
-    fn find_album(dict: &DictionaryRef) -> Option<String> {
-        dict.get("album")
-            .or_else(|| dict.get("ALBUM"))
-            .map(|s| s.to_string())
-    }
+        let mut split = vec![];
+        let mut start = 0;
+        for separator in config.artist_split_pattern.find_iter(artists) {
+            let in_exception = exceptions.iter().any(|exception| {
+                exception.start < separator.end() && separator.start() < exception.end
+            });
+            if !in_exception {
+                split.push(artists[start..separator.start()].to_string());
+                start = separator.end();
+            }
+        }
+        split.push(artists[start..].to_string());
 
-    fn find_artist(dict: &DictionaryRef) -> Option<String> {
-        dict.get("artist")
-            .or_else(|| dict.get("ARTIST"))
-            .map(|s| s.to_string())
+        split
+            .iter()
+            .map(|artist| collapse_whitespace(artist))
+            .filter(|artist| !artist.is_empty())
+            .collect()
     }
 
-    fn find_track(dict: &DictionaryRef) -> Option<u32> {
-        dict.get("track")
-            .or_else(|| dict.get("TRACK"))
-            .and_then(|track_str| TRACK_INFO_TRACK_PATTERN.captures(track_str))
-            .and_then(|captures| captures.name("track"))
-            .and_then(|track_str| track_str.as_str().parse().ok().filter(|t| *t != 0))
-    }
-}
+    /// Overrides the song's tags with the album metadata found in
+    /// `metadata_dir`, which is the song's directory or one of its ancestors.
+    fn apply_metadata(&mut self, metadata_dir: &Path, metadata: &DirectoryMetadata) {
+        self.album.name = metadata.album.clone();
+        self.missing.set(MissingTags::ALBUM, false);
 
-impl Index {
-    pub async fn index<P: AsRef<Path>, S: AsRef<str>>(
-        base_dir: P,
-        base_url: S,
-        media_include: &RegexSet,
-        media_exclude: &RegexSet,
-        cover_include: &RegexSet,
-        cover_exclude: &RegexSet,
-    ) -> Result<Index> {
-        info!("Indexing {}", base_dir.as_ref().to_string_lossy());
-        let index_start_time = SystemTime::now();
+        let file = self
+            .path
+            .strip_prefix(metadata_dir)
+            .map(|relative| {
+                relative
+                    .components()
+                    .map(|c| c.as_os_str().to_string_lossy())
+                    .collect::<Vec<_>>()
+                    .join("/")
+            })
+            .unwrap_or_default();
+        let track = metadata.track(&file);
 
-        let mut index = Index {
-            artists: Default::default(),
-            artist_list: Default::default(),
-            albums: Default::default(),
-            album_list: Default::default(),
+        let artists = match track {
+            Some((_, track)) if !track.artists.is_empty() => &track.artists,
+            _ => &metadata.artists,
         };
+        if !artists.is_empty() {
+            self.missing.set(MissingTags::ARTIST, false);
+            self.artists = artists
+                .iter()
+                .map(|name| ArtistRef {
+                    name: name.clone(),
+                    unique_name: "".to_string(),
+                })
+                .collect();
+        }
 
-        let mut song_count = 0u32;
-        let mut previous_parent = None;
-        let mut previous_song_parent = None;
-        let mut previous_album = None;
-        let mut found_covers: Vec<PathBuf> = vec![];
+        // the album belongs to the album's artists even if a track lists others
+        if !metadata.artists.is_empty() {
+            self.album_artists = metadata
+                .artists
+                .iter()
+                .map(|name| ArtistRef {
+                    name: name.clone(),
+                    unique_name: "".to_string(),
+                })
+                .collect();
+        }
 
-        debug!("Traversing music directory...");
-        let base_dir_moved = base_dir.as_ref().to_path_buf();
-        let walked: Vec<_> = tokio::task::spawn_blocking(move || {
-            walkdir::WalkDir::new(&base_dir_moved)
-                .follow_links(true)
-                .into_iter()
-                .collect()
-        })
-        .await
-        .chain_err(|| ErrorKind::IndexingError(None, "doing initial music directory traversal"))?;
+        if !metadata.tracks.is_empty() {
+            self.track = track.map(|(number, _)| number);
+            self.track_total = Some(metadata.tracks.len() as u32);
+            self.missing.set(MissingTags::TRACK, self.track.is_none());
+        }
 
-        debug!("Browsing results...");
-        for dir in walked {
-            match dir {
-                Ok(dir) => {
-                    let path = dir.path();
-                    let path_str = path.to_string_lossy();
-                    trace!("Visiting {}", path_str);
-
-                    // don't keep covers from other directories
-                    if !paths_eq(&previous_parent, &path.parent()) {
-                        found_covers.clear();
-                    }
-                    previous_parent = path.parent().map(|p| p.to_path_buf());
+        if let Some(year) = metadata.year {
+            self.year = Some(year);
+        }
+
+        if let Some(genre) = &metadata.genre {
+            self.genres = vec![genre.clone()];
+        }
+
+        if let Some(title) = track.and_then(|(_, track)| track.title.as_ref()) {
+            self.name = title.clone();
+            self.unique_name = sanitize(title);
+            self.search_key = search_key(title);
+            self.missing.set(MissingTags::TITLE, false);
+        }
+    }
 
-                    if media_include.is_match(&path_str) && !media_exclude.is_match(&path_str) {
-                        trace!("Found media file.");
+    /// Reads a song's tags with ffmpeg.
+    async fn probe(path: &Path, config: &Config) -> Result<SongMetadata> {
+        let path_moved = path.to_path_buf();
+        let retries = config.probe_retries;
+        let retry_delay = Duration::from_millis(config.probe_retry_delay);
 
-                        let song = Song::parse(&path, base_dir.as_ref(), base_url.as_ref()).await?;
-                        debug!("Loaded metadata: {:?}", &song);
-                        let song = index.insert_song(song).await?;
-                        song_count += 1;
+        let res: Result<_> = ffmpeg::spawn_blocking(move || {
+            let context = Song::open_with_retries(&path_moved, retries, retry_delay)?;
 
-                        let album_unique_name = song.read().await.album.unique_name.clone();
+            trace!("Format Metadata:");
+            let metadata = context.metadata();
+            for (key, value) in metadata.iter() {
+                trace!("  '{}': '{}'", key, value);
+            }
+            let mut title = Song::find_title(&metadata);
+            let mut album = Song::find_album(&metadata);
+            let mut artists = Song::find_all(&metadata, ARTIST_KEYS);
+            let mut album_artist = Song::find_album_artist(&metadata);
+            let (mut track, mut track_total) = Song::find_track(&metadata);
+            let mut year = Song::find_year(&metadata);
+            let mut genres = Song::find_all(&metadata, GENRE_KEYS);
+            let (mut disc, mut disc_total) = Song::find_disc(&metadata);
+            let mut composer = Song::find_composer(&metadata);
+            let mut comment = Song::find_comment(&metadata);
+            let mut replay_gain = Song::find_replay_gain(&metadata);
 
-                        previous_song_parent = path.parent().map(|p| p.to_path_buf());
-                        previous_album = Some(album_unique_name.clone());
+            for (index, stream) in context.streams().enumerate() {
+                if !(title.is_none() || album.is_none() || artists.is_empty() || track.is_none()) {
+                    break;
+                }
 
-                        // if we found the cover first, then this vec should be populated
-                        for cover in found_covers.drain(..) {
-                            trace!(
-                                "Inserting cover into new album: {}: {}",
-                                &album_unique_name,
-                                cover.to_string_lossy()
-                            );
-                            let mut album = index.albums[&album_unique_name].write().await;
-                            Index::insert_cover(
-                                &mut album,
-                                &cover,
-                                base_dir.as_ref(),
-                                base_url.as_ref(),
-                            )
-                            .await?;
-                        }
-                    } else if cover_include.is_match(&path_str)
-                        && !cover_exclude.is_match(&path_str)
-                    {
-                        trace!("Found cover file.");
-
-                        // if we found songs first, then the album should have been created already
-                        if paths_eq(&previous_song_parent, &path.parent()) {
-                            let previous_album = previous_album.as_ref().expect(
-                                "BUG: previous_song_parent was Some but previous_album was None",
-                            );
-                            trace!("Editing existing album: {}: {}", previous_album, &path_str);
-                            let mut album = index.albums[previous_album].write().await;
-                            Index::insert_cover(
-                                &mut album,
-                                &path,
-                                base_dir.as_ref(),
-                                base_url.as_ref(),
-                            )
-                            .await?;
-                        } else {
-                            // we haven't found any songs for this album yet
-                            trace!("Found cover: {} for new album.", &path_str);
-                            found_covers.push(path.to_path_buf());
-                        }
-                    }
+                trace!("Stream {} Metadata:", index);
+                let metadata = stream.metadata();
+                for (key, value) in metadata.iter() {
+                    trace!("  '{}': '{}'", key, value);
                 }
-                Err(err) => {
-                    warn!(
-                        "Encountered invalid file while scanning. Path: {}",
-                        if let Some(entry) = err.path() {
-                            entry.to_string_lossy()
-                        } else {
-                            "Unknown".into()
-                        }
-                    );
+                title = title.or_else(|| Song::find_title(&metadata));
+                album = album.or_else(|| Song::find_album(&metadata));
+                if artists.is_empty() {
+                    artists = Song::find_all(&metadata, ARTIST_KEYS);
+                }
+                album_artist = album_artist.or_else(|| Song::find_album_artist(&metadata));
+                if track.is_none() || track_total.is_none() {
+                    let (stream_track, stream_track_total) = Song::find_track(&metadata);
+                    track = track.or(stream_track);
+                    track_total = track_total.or(stream_track_total);
+                }
+                year = year.or_else(|| Song::find_year(&metadata));
+                if genres.is_empty() {
+                    genres = Song::find_all(&metadata, GENRE_KEYS);
+                }
+                composer = composer.or_else(|| Song::find_composer(&metadata));
+                comment = comment.or_else(|| Song::find_comment(&metadata));
+                if !replay_gain.is_complete() {
+                    replay_gain = replay_gain.or(Song::find_replay_gain(&metadata));
+                }
+                if disc.is_none() || disc_total.is_none() {
+                    let (stream_disc, stream_disc_total) = Song::find_disc(&metadata);
+                    disc = disc.or(stream_disc);
+                    disc_total = disc_total.or(stream_disc_total);
                 }
             }
-        }
 
-        debug!("Sorting artists...");
-        index.artist_list.reserve(index.artists.len());
-        let mut artist_names = index.artists.keys().collect::<Vec<_>>();
-        artist_names.sort();
-        for artist_name in artist_names {
-            index.artist_list.push(index.artists[artist_name].clone());
+            let (stream_bit_rate, sample_rate, channels) = Song::find_audio_format(&context);
+            let bit_rate = stream_bit_rate.or_else(|| {
+                Some(context.bit_rate())
+                    .filter(|&bit_rate| bit_rate > 0)
+                    .map(|bit_rate| bit_rate as u64)
+            });
+            let duration = Song::find_duration(&context);
+
+            Ok(SongMetadata {
+                title,
+                album,
+                artists,
+                album_artist,
+                track,
+                track_total,
+                year,
+                genres,
+                disc,
+                disc_total,
+                composer,
+                comment,
+                replay_gain,
+                bit_rate,
+                sample_rate,
+                channels,
+                duration,
+            })
+        })
+        .await
+        .chain_err(path_error!(
+            IndexingError,
+            path,
+            "running ffmpeg to probe media file"
+        ))?;
+        res
+    }
+
+    /// Finds the bit rate, sample rate and channel count of a media file's audio
+    /// stream. Values ffmpeg reports as zero are unknown.
+    fn find_audio_format(
+        context: &format::context::Input,
+    ) -> (Option<u64>, Option<u32>, Option<u16>) {
+        let decoder = context
+            .streams()
+            .best(media::Type::Audio)
+            .and_then(|stream| {
+                let mut decoder = stream.codec().decoder().audio().ok()?;
+                decoder.set_parameters(stream.parameters()).ok()?;
+                Some(decoder)
+            });
+
+        match decoder {
+            Some(decoder) => (
+                Some(decoder.bit_rate() as u64).filter(|&bit_rate| bit_rate > 0),
+                Some(decoder.rate()).filter(|&rate| rate > 0),
+                Some(decoder.channels()).filter(|&channels| channels > 0),
+            ),
+            None => (None, None, None),
         }
+    }
 
-        debug!("Sorting albums...");
-        index.album_list.reserve(index.albums.len());
-        let mut album_names = index.albums.keys().collect::<Vec<_>>();
-        album_names.sort();
-        for album_name in album_names {
-            index.album_list.push(index.albums[album_name].clone());
+    /// Finds the length of a media file in seconds, from the container or else
+    /// from its audio stream. Files that report no length have none.
+    fn find_duration(context: &format::context::Input) -> Option<f64> {
+        // the container's duration is in AV_TIME_BASE units
+        let duration = context.duration();
+        if duration > 0 {
+            return Some(duration as f64 / f64::from(ffmpeg4_sys::AV_TIME_BASE));
         }
 
-        info!(
-            "Indexed {} songs in {:?}",
-            song_count,
-            SystemTime::now().duration_since(index_start_time).unwrap()
-        );
+        context
+            .streams()
+            .best(media::Type::Audio)
+            .filter(|stream| stream.duration() > 0)
+            .map(|stream| stream.duration() as f64 * f64::from(stream.time_base()))
+    }
 
-        info!("{} Albums loaded.", index.albums.len());
+    /// Reads a song's lyrics from its tags, or else from an `.lrc` file next to
+    /// it. Lyrics can be long, so they are read on demand instead of being kept
+    /// in the index.
+    async fn read_lyrics(path: &Path, config: &Config) -> Result<Option<String>> {
+        let path_moved = path.to_path_buf();
+        let retries = config.probe_retries;
+        let retry_delay = Duration::from_millis(config.probe_retry_delay);
 
-        debug!(
-            "Albums: {:?}",
-            &stream::iter(&index.album_list)
-                .then(|rc| async move { rc.read().await.unique_name.clone() })
-                .collect::<Vec<_>>()
-                .await
-        );
+        let lyrics = ffmpeg::spawn_blocking(move || -> Result<_> {
+            let context = Song::open_with_retries(&path_moved, retries, retry_delay)?;
+            Ok(Song::find_lyrics(&context.metadata()).or_else(|| {
+                context
+                    .streams()
+                    .find_map(|stream| Song::find_lyrics(&stream.metadata()))
+            }))
+        })
+        .await
+        .chain_err(path_error!(
+            IndexingError,
+            path,
+            "running ffmpeg to read lyrics"
+        ))??;
+        if lyrics.is_some() {
+            return Ok(lyrics);
+        }
 
-        info!("{} Artists loaded.", index.artists.len());
+        let lrc_path = path.with_extension("lrc");
+        if !lrc_path.is_file() {
+            return Ok(None);
+        }
+        let lrc = fs::read_to_string(&lrc_path).chain_err(path_error!(
+            IndexingError,
+            lrc_path,
+            "reading lyrics file"
+        ))?;
 
-        debug!(
-            "Artists: {:?}",
-            &stream::iter(&index.artist_list)
-                .then(|rc| async move { rc.read().await.unique_name.clone() })
-                .collect::<Vec<_>>()
-                .await
-        );
+        // only the text is kept, without the timestamps and the id tags
+        let lines: Vec<_> = lrc
+            .lines()
+            .map(str::trim)
+            .filter(|line| !LRC_ID_TAG.is_match(line))
+            .map(|line| LRC_TIME_TAGS.replace(line, "").trim().to_string())
+            .collect();
+        Ok(Some(lines.join("\n").trim().to_string()).filter(|lyrics| !lyrics.is_empty()))
+    }
 
-        info!("Generating album covers...");
-        let cover_gen_start_time = SystemTime::now();
-        let mut covers_generated = 0u32;
-        for album in index.album_list.iter() {
-            if album.read().await.cover_url.is_none() {
-                let cover_path = {
-                    // we don't want to be holding this lock when we insert the cover
-                    let album = album.read().await;
-                    Index::gen_cover(&album).await?
-                };
-                if let Some(cover_path) = cover_path {
-                    let mut album = album.write().await;
-                    Index::insert_cover(
-                        &mut album,
-                        &cover_path,
-                        base_dir.as_ref(),
-                        base_url.as_ref(),
-                    )
-                    .await?;
-                    covers_generated += 1;
+    /// Finds unsynced lyrics. Id3 `USLT` frames show up with their language,
+    /// as in `lyrics-eng`.
+    fn find_lyrics(dict: &DictionaryRef) -> Option<String> {
+        dict.iter()
+            .find(|(key, _)| {
+                let key = key.to_lowercase();
+                key == "lyrics" || key == "unsyncedlyrics" || key.starts_with("lyrics-")
+            })
+            .map(|(_, value)| value.trim().to_string())
+            .filter(|lyrics| !lyrics.is_empty())
+    }
+
+    /// Opens a media file, retrying errors that are likely to be transient, like
+    /// I/O errors from a disk that is still spinning up.
+    fn open_with_retries(
+        path: &Path,
+        retries: u32,
+        delay: Duration,
+    ) -> Result<format::context::Input> {
+        let mut attempt = 0u32;
+        loop {
+            match format::input(&path) {
+                Ok(context) => return Ok(context),
+                Err(e) if attempt < retries && Song::is_transient(&e) => {
+                    attempt += 1;
+                    warn!(
+                        "Transient error probing {}: {}. Retrying ({}/{})...",
+                        path.to_string_lossy(),
+                        e,
+                        attempt,
+                        retries
+                    );
+                    thread::sleep(delay);
+                }
+                Err(e) => {
+                    return Err(e).chain_err(path_error!(IndexingError, path, "probing media file"))
                 }
             }
         }
-        info!(
-            "Generated {} covers in {:?}",
-            covers_generated,
-            SystemTime::now()
-                .duration_since(cover_gen_start_time)
-                .unwrap()
-        );
-
-        Ok(index)
     }
 
-    async fn insert_song(&mut self, mut song: Song) -> Result<Arc<RwLock<Song>>> {
-        for artist in song.artists.iter_mut() {
-            artist.unique_name = self.get_or_insert_artist(&artist.name).await;
+    /// Transient errors are I/O errors, as opposed to errors about the file's
+    /// contents, which won't go away by retrying.
+    fn is_transient(error: &ffmpeg4::Error) -> bool {
+        match error {
+            ffmpeg4::Error::Other { errno } => matches!(
+                *errno,
+                libc::EIO | libc::EAGAIN | libc::EINTR | libc::EBUSY | libc::ETIMEDOUT
+            ),
+            _ => false,
         }
+    }
 
-        let album = self
-            .get_or_insert_album(
-                &song.album.name,
-                &song.artists,
-                song.path
-                    .parent()
-                    .chain_err(indexing_error!(song.path, "getting song path"))?
-                    .to_path_buf(),
-            )
-            .await;
-
-        song.album.unique_name = album.read().await.unique_name.clone();
-
-        if let Some(cover_url) = &album.read().await.cover_url {
-            song.cover_url = Some(cover_url.clone());
+    /// Finds the artist directory of a song when grouping artists by directory.
+    fn find_directory_artist(path: &Path, config: &Config) -> Option<String> {
+        if config.artist_grouping != ArtistGrouping::ByDirectory {
+            return None;
         }
 
-        if let Some(track) = song.track {
-            album.write().await.tracked = true;
-
-            if album.read().await.songs.len() <= (track - 1) as usize {
-                album.write().await.songs.resize(track as usize, None);
-            }
-
-            let song_name = song.unique_name.clone();
-            let song = Arc::new(RwLock::new(song));
+        let relative = path.strip_prefix(&config.base_dir).ok()?;
+        let mut components = relative.components();
+        // the last component is the song file itself
+        components.next_back();
+        components
+            .nth(config.artist_directory_depth)
+            .map(|c| c.as_os_str().to_string_lossy().to_string())
+    }
 
-            album.write().await.songs[(track - 1) as usize] = Some(song.clone());
-            album
-                .write()
-                .await
-                .songs_by_name
-                .insert(song_name, song.clone());
+    /// Finds the album and artist named by the song's directory and the one
+    /// above it, for filling in missing tags. Directories outside of the base
+    /// directory never name anything.
+    fn find_directory_tags(path: &Path, config: &Config) -> (Option<String>, Option<String>) {
+        if !config.directory_tag_fallback {
+            return (None, None);
+        }
 
-            Ok(song)
-        } else {
-            let song_name = song.unique_name.clone();
-            let song = Arc::new(RwLock::new(song));
+        let relative = match path.strip_prefix(&config.base_dir) {
+            Ok(relative) => relative,
+            Err(_) => return (None, None),
+        };
+        let mut components = relative.components();
+        // the last component is the song file itself
+        components.next_back();
+        let name = |c: Option<Component>| c.map(|c| c.as_os_str().to_string_lossy().to_string());
+        let album = name(components.next_back());
+        let artist = name(components.next_back());
 
-            album.write().await.songs.push(Some(song.clone()));
-            album
-                .write()
-                .await
-                .songs_by_name
-                .insert(song_name, song.clone());
+        (album, artist)
+    }
 
-            Ok(song)
-        }
+    fn find_title(dict: &DictionaryRef) -> Option<String> {
+        dict.get("title")
+            .or_else(|| dict.get("TITLE"))
+            .or_else(|| dict.get("©nam"))
+            .or_else(|| dict.get("INAM"))
+            .map(|s| s.to_string())
     }
 
-    async fn get_or_insert_album(
-        &mut self,
-        name: &str,
-        artists: &[ArtistRef],
-        path: PathBuf,
-    ) -> Arc<RwLock<Album>> {
-        let mut unique_name = sanitize(name);
+    fn find_album(dict: &DictionaryRef) -> Option<String> {
+        dict.get("album")
+            .or_else(|| dict.get("ALBUM"))
+            .or_else(|| dict.get("©alb"))
+            .or_else(|| dict.get("IPRD"))
+            .map(|s| s.to_string())
+    }
 
-        if self.albums.contains_key(&unique_name) {
-            let found = self
-                .albums
-                .get(&unique_name)
-                .expect("BUG: Missing found artist")
-                .clone();
-            if found.read().await.name == name {
-                for artist_ref in artists {
-                    if found
-                        .read()
-                        .await
-                        .artists
-                        .iter()
-                        .find(|a| a.unique_name == artist_ref.unique_name)
-                        .is_none()
-                    {
-                        found.write().await.artists.push(artist_ref.clone());
-                        self.artists[&artist_ref.unique_name]
-                            .write()
-                            .await
-                            .albums
-                            .insert(unique_name.clone(), found.clone());
+    /// Finds every value of a tag that can be repeated, like the artist and
+    /// genre vorbis comments of flac files. Keys are matched ignoring case, and
+    /// values ffmpeg joined with `;` are split up again. Only the first of the
+    /// `keys` that has any values is used.
+    fn find_all(dict: &DictionaryRef, keys: &[&str]) -> Vec<String> {
+        for key in keys {
+            let mut values = vec![];
+            for (_, value) in dict.iter().filter(|(k, _)| k.eq_ignore_ascii_case(key)) {
+                for value in value.split(';').map(str::trim).filter(|v| !v.is_empty()) {
+                    if !values.iter().any(|v| v == value) {
+                        values.push(value.to_string());
                     }
                 }
+            }
 
-                return found;
+            if !values.is_empty() {
+                return values;
             }
+        }
+        vec![]
+    }
 
-            let mut index = 1u32;
-            let mut found_name = format!("{}-{}", unique_name, index);
-            while self.albums.contains_key(&found_name) {
-                let found = self
-                    .albums
-                    .get(&found_name)
-                    .expect("BUG: Missing found artist")
-                    .clone();
-                if found.read().await.name == name {
-                    for artist_ref in artists {
-                        if found
-                            .read()
-                            .await
-                            .artists
-                            .iter()
-                            .find(|a| a.unique_name == artist_ref.unique_name)
-                            .is_none()
-                        {
-                            found.write().await.artists.push(artist_ref.clone());
-                            self.artists[&artist_ref.unique_name]
-                                .write()
-                                .await
-                                .albums
-                                .insert(found_name.clone(), found.clone());
-                        }
-                    }
+    fn find_album_artist(dict: &DictionaryRef) -> Option<String> {
+        dict.get("album_artist")
+            .or_else(|| dict.get("ALBUMARTIST"))
+            .or_else(|| dict.get("album artist"))
+            .or_else(|| dict.get("aART"))
+            .map(|s| s.to_string())
+    }
 
-                    return found;
-                }
+    /// Finds the release year, which is the first four digit number in the date
+    /// so that both plain years and malformed dates like `2021-03-00` work.
+    fn find_year(dict: &DictionaryRef) -> Option<u32> {
+        dict.get("date")
+            .or_else(|| dict.get("DATE"))
+            .or_else(|| dict.get("year"))
+            .or_else(|| dict.get("YEAR"))
+            .or_else(|| dict.get("originaldate"))
+            .or_else(|| dict.get("ORIGINALDATE"))
+            .or_else(|| dict.get("©day"))
+            .or_else(|| dict.get("ICRD"))
+            .and_then(|date_str| YEAR_PATTERN.captures(date_str))
+            .and_then(|captures| captures.name("year"))
+            .and_then(|year_str| year_str.as_str().parse().ok())
+    }
 
-                index += 1;
-                found_name = format!("{}-{}", unique_name, index);
-            }
-            unique_name = found_name;
-        }
+    fn find_composer(dict: &DictionaryRef) -> Option<String> {
+        dict.get("composer")
+            .or_else(|| dict.get("COMPOSER"))
+            .or_else(|| dict.get("©wrt"))
+            .map(|s| s.to_string())
+    }
 
-        let album = Arc::new(RwLock::new(Album {
-            name: name.to_string(),
-            unique_name: unique_name.clone(),
-            artists: artists.to_vec(),
-            songs: Default::default(),
-            songs_by_name: Default::default(),
-            cover_url: None,
-            cover_rating: 0,
-            tracked: false,
-            path,
-        }));
+    fn find_comment(dict: &DictionaryRef) -> Option<String> {
+        dict.get("comment")
+            .or_else(|| dict.get("COMMENT"))
+            .or_else(|| dict.get("description"))
+            .or_else(|| dict.get("DESCRIPTION"))
+            .or_else(|| dict.get("©cmt"))
+            .or_else(|| dict.get("ICMT"))
+            .map(|s| s.to_string())
+    }
 
-        self.albums.insert(unique_name.clone(), album.clone());
-        for artist_ref in artists {
-            self.artists
-                .get_mut(&artist_ref.unique_name)
-                .expect("BUG: Missing newly inserted artist")
-                .write()
-                .await
-                .albums
-                .insert(unique_name.clone(), album.clone());
+    fn find_replay_gain(dict: &DictionaryRef) -> ReplayGain {
+        let find = |key: &str| {
+            dict.iter()
+                .find(|(k, _)| k.eq_ignore_ascii_case(key))
+                .and_then(|(_, value)| Song::parse_replay_gain(value))
+        };
+
+        ReplayGain {
+            track_gain: find("replaygain_track_gain"),
+            track_peak: find("replaygain_track_peak"),
+            album_gain: find("replaygain_album_gain"),
+            album_peak: find("replaygain_album_peak"),
         }
+    }
 
-        album
+    /// Parses gains like `-6.54 dB` and `+1.2dB` as well as plain peaks like
+    /// `0.988312`.
+    fn parse_replay_gain(value: &str) -> Option<f32> {
+        let value = value.trim().to_ascii_lowercase();
+        let value = value.strip_suffix("db").unwrap_or(&value).trim_end();
+
+        value
+            .trim_start_matches('+')
+            .parse::<f32>()
+            .ok()
+            .filter(|gain| gain.is_finite())
     }
 
-    async fn get_or_insert_artist(&mut self, name: &str) -> String {
-        let mut unique_name = sanitize(name);
+    /// Finds the track number and total number of tracks on the disc, which,
+    /// like disc numbers, may be given together in the `N/M` form.
+    fn find_track(dict: &DictionaryRef) -> (Option<u32>, Option<u32>) {
+        let captures = dict
+            .get("track")
+            .or_else(|| dict.get("TRACK"))
+            .or_else(|| dict.get("trkn"))
+            .or_else(|| dict.get("ITRK"))
+            .and_then(|track_str| TRACK_INFO_TRACK_PATTERN.captures(track_str));
+        let track = captures
+            .as_ref()
+            .and_then(|c| c.name("track"))
+            .and_then(|track_str| Song::parse_count(track_str.as_str()));
 
-        if self.artists.contains_key(&unique_name) {
-            let found = self
-                .artists
-                .get(&unique_name)
-                .expect("BUG: Missing found artist");
-            let borrowed = found.read().await;
-            if borrowed.name == name {
-                return borrowed.unique_name.clone();
-            }
+        let track_total = dict
+            .get("tracktotal")
+            .or_else(|| dict.get("TRACKTOTAL"))
+            .or_else(|| dict.get("totaltracks"))
+            .or_else(|| dict.get("TOTALTRACKS"))
+            .and_then(|total_str| Song::parse_count(total_str.trim()))
+            .or_else(|| {
+                captures
+                    .as_ref()
+                    .and_then(|c| c.name("total"))
+                    .and_then(|total_str| Song::parse_count(total_str.as_str()))
+            });
 
-            let mut index = 1u32;
-            let mut found_name = format!("{}-{}", unique_name, index);
-            while self.artists.contains_key(&found_name) {
-                let found = self
-                    .artists
-                    .get(&found_name)
-                    .expect("BUG: Missing found artist");
-                let borrowed = found.read().await;
-                if borrowed.name == name {
-                    return borrowed.unique_name.clone();
-                }
+        (track, track_total)
+    }
 
-                index += 1;
-                found_name = format!("{}-{}", unique_name, index);
-            }
-            unique_name = found_name;
-        }
+    /// Finds the disc number and total number of discs, which may either have
+    /// their own tags or be given together in the `N/M` form.
+    fn find_disc(dict: &DictionaryRef) -> (Option<u32>, Option<u32>) {
+        let captures = dict
+            .get("disc")
+            .or_else(|| dict.get("DISC"))
+            .or_else(|| dict.get("discnumber"))
+            .or_else(|| dict.get("DISCNUMBER"))
+            .or_else(|| dict.get("disk"))
+            .and_then(|disc_str| DISC_INFO_PATTERN.captures(disc_str));
+        let disc = captures
+            .as_ref()
+            .and_then(|c| c.name("disc"))
+            .and_then(|disc_str| Song::parse_count(disc_str.as_str()));
 
-        let artist = Arc::new(RwLock::new(Artist {
-            name: name.to_string(),
-            unique_name: unique_name.clone(),
-            albums: Default::default(),
-            cover_url: None,
-        }));
+        let disc_total = dict
+            .get("disctotal")
+            .or_else(|| dict.get("DISCTOTAL"))
+            .or_else(|| dict.get("totaldiscs"))
+            .or_else(|| dict.get("TOTALDISCS"))
+            .and_then(|total_str| Song::parse_count(total_str.trim()))
+            .or_else(|| {
+                captures
+                    .as_ref()
+                    .and_then(|c| c.name("total"))
+                    .and_then(|total_str| Song::parse_count(total_str.as_str()))
+            });
 
-        // we couldn't find the artist, so we'll insert a new one
-        self.artists.insert(unique_name.clone(), artist.clone());
+        (disc, disc_total)
+    }
 
-        unique_name
+    /// Parses a track or disc number or total, which start at 1.
+    fn parse_count(count_str: &str) -> Option<u32> {
+        count_str.parse().ok().filter(|c| *c != 0)
+    }
+}
+
+impl Album {
+    /// Total number of tracks across all discs, if every disc with tracked
+    /// songs has a total track tag, so clients can tell when tracks are missing.
+    fn total_tracks(&self) -> Option<u32> {
+        if self.discs.is_empty() {
+            return None;
+        }
+
+        self.discs
+            .keys()
+            .map(|disc| self.track_totals.get(disc).copied())
+            .sum()
     }
 
-    async fn insert_cover(
-        album: &mut Album,
+    /// Places a song at its disc and track, or after all tracked songs, sorted
+    /// by `name`, if it has no track number.
+    fn place_song(
+        &mut self,
+        song: Arc<RwLock<Song>>,
+        name: &str,
         path: &Path,
-        base: &Path,
-        files_url: &str,
-    ) -> Result<()> {
-        let rating = Index::rate_cover(&path)?;
-        if rating > album.cover_rating {
-            let cover_url = Some(find_url(path, base, files_url)?);
-            album.cover_url = cover_url.clone();
-            album.cover_rating = rating;
+        disc: Option<u32>,
+        track: Option<u32>,
+    ) {
+        match track {
+            Some(track) => {
+                self.tracked = true;
 
-            // update all songs for the current album
-            for song in album.songs.iter() {
-                if let Some(song) = song {
-                    song.write().await.cover_url = cover_url.clone();
+                let disc = self.discs.entry(disc.unwrap_or(1)).or_default();
+                if disc.len() < track as usize {
+                    disc.resize(track as usize, None);
                 }
+                disc[(track - 1) as usize] = Some(song);
+            }
+            None => {
+                self.untracked
+                    .insert((name.to_lowercase(), path.to_path_buf()), song);
             }
         }
 
-        Ok(())
+        self.songs = self
+            .discs
+            .values()
+            .flatten()
+            .cloned()
+            .chain(self.untracked.values().cloned().map(Some))
+            .collect();
     }
 
-    fn rate_cover(path: &Path) -> Result<u32> {
-        let file_name = path
-            .file_name()
-            .chain_err(indexing_error!(path, "rating cover"))?;
-        let name = file_name.to_string_lossy();
-        let mut value = 1u32;
+    /// A single is an album containing only one song without track info.
+    fn is_single(&self) -> bool {
+        self.songs.len() == 1 && !self.tracked
+    }
 
-        if name.contains("cover") {
-            value += 100;
-        }
+    /// Finds the closest song after or before the given song, skipping gaps in
+    /// the track list and optionally wrapping around at the ends.
+    fn neighbor(&self, song_name: &str, forward: bool, wrap: bool) -> Option<Arc<RwLock<Song>>> {
+        let song = self.songs_by_name.get(song_name)?;
+        let position = self
+            .songs
+            .iter()
+            .position(|s| s.as_ref().map_or(false, |s| Arc::ptr_eq(s, song)))?;
+
+        let before = &self.songs[..position];
+        let after = &self.songs[position + 1..];
+
+        let found = if forward {
+            let wrapped = if wrap { before } else { &[] };
+            after.iter().chain(wrapped.iter()).flatten().next()
+        } else {
+            let wrapped = if wrap { after } else { &[] };
+            before
+                .iter()
+                .rev()
+                .chain(wrapped.iter().rev())
+                .flatten()
+                .next()
+        };
+
+        found.cloned()
+    }
+}
 
-        if name.contains("small") {
-            value += 20;
+impl Index {
+    /// Creates an index without any songs.
+    fn empty(config: &Config, base_url: &str, ratings: Arc<Ratings>) -> Index {
+        Index {
+            artists: Default::default(),
+            artist_list: Default::default(),
+            albums: Default::default(),
+            album_list: Default::default(),
+            urls: FileUrls::new(config, base_url),
+            names: UniqueNames::load(config),
+            ratings,
+            config: config.clone(),
+            base_url: base_url.to_string(),
+            build_time: Default::default(),
+            modified: SystemTime::now(),
+            content_hash: Default::default(),
+            duplicates: Default::default(),
+            artist_keys: Default::default(),
+            walked: Default::default(),
         }
+    }
 
-        Ok(value)
+    /// Indexes the library. Songs that are unchanged since they were recorded
+    /// in the manifest aren't probed again, unless they are in `reprobe`.
+    pub async fn index<S: AsRef<str>>(
+        config: &Config,
+        base_url: S,
+        ratings: Arc<Ratings>,
+        reprobe: &[PathBuf],
+        progress: &IndexProgress,
+    ) -> Result<Index> {
+        Index::index_walking(config, base_url, ratings, reprobe, progress, Walk::All).await
     }
 
-    async fn gen_cover(album: &Album) -> Result<Option<PathBuf>> {
-        trace!("Generating cover for {}", album.unique_name);
-        for song in album.songs.iter() {
-            if let Some(song) = song {
-                let song_path = song.read().await.path.clone();
-                let song_path_2 = song_path.clone();
-                trace!("Scanning song: {}", &song_path.to_string_lossy());
+    async fn index_walking<S: AsRef<str>>(
+        config: &Config,
+        base_url: S,
+        ratings: Arc<Ratings>,
+        reprobe: &[PathBuf],
+        progress: &IndexProgress,
+        walk: Walk<'_>,
+    ) -> Result<Index> {
+        let base_dir = &config.base_dir;
+        let media_include = &config.media_include_patterns;
+        let media_exclude = &config.media_exclude_patterns;
+        let cover_include = &config.cover_include_patterns;
+        let cover_exclude = &config.cover_exclude_patterns;
+        let document_include = &config.document_include_patterns;
+        let document_exclude = &config.document_exclude_patterns;
 
-                let cover: Result<_> = tokio::task::spawn_blocking(move || {
-                    let frame = Index::read_frame(&song_path)?;
-                    if let Some(frame) = frame {
-                        let path = Index::make_cover_path(&song_path)?;
-                        let data = Index::fit_frame(&frame);
+        info!("Indexing {}", base_dir.to_string_lossy());
+        let index_start_time = SystemTime::now();
 
-                        image::save_buffer(
-                            &path,
-                            &data,
-                            frame.width(),
-                            frame.height(),
-                            ColorType::Rgba8,
-                        )
-                        .chain_err(indexing_error!(&song_path, "Writing cover image to file"))?;
+        let mut index = Index::empty(config, base_url.as_ref(), ratings);
 
-                        Ok(Some(path))
-                    } else {
-                        Ok(None)
-                    }
-                })
-                .await
-                .chain_err(indexing_error!(
-                    &song_path_2,
-                    "Running cover image extraction off-thread"
-                ))?;
-                let cover = cover?;
+        let manifest = Manifest::load(config).await;
+        manifest.forget(reprobe);
 
-                if cover.is_some() {
-                    return Ok(cover);
+        let mut song_count = 0u32;
+        let mut skipped_count = 0u32;
+        // song counts of each album in each directory
+        let mut dir_albums: BTreeMap<PathBuf, BTreeMap<String, u32>> = BTreeMap::new();
+        let mut dir_covers: BTreeMap<PathBuf, Vec<PathBuf>> = BTreeMap::new();
+        let mut dir_documents: BTreeMap<PathBuf, Vec<PathBuf>> = BTreeMap::new();
+        let mut dir_metadata: HashMap<PathBuf, Option<DirectoryMetadata>> = HashMap::new();
+        // covers named by album metadata or a .cover file, which always win
+        let mut explicit_covers: BTreeMap<String, PathBuf> = BTreeMap::new();
+        let mut media_files = vec![];
+
+        index.walked = match walk {
+            Walk::All => {
+                debug!("Traversing music directory...");
+                Index::walk(base_dir.to_path_buf(), false).await?
+            }
+            Walk::Dirs { dirs, previous } => {
+                debug!("Traversing {} directories again...", dirs.len());
+                let mut rewalked = vec![];
+                for dir in dirs {
+                    rewalked.extend(Index::walk(dir.to_path_buf(), true).await?);
                 }
+                Index::merge_walks(previous, dirs, rewalked)
             }
-        }
+        };
 
-        Ok(None)
-    }
+        debug!("Browsing results...");
+        for path in index.walked.iter() {
+            let path_str = path.to_string_lossy();
+            trace!("Visiting {}", path_str);
 
-    fn read_frame(song_path: &Path) -> Result<Option<frame::Video>> {
-        let mut input = format::input(&song_path)
-            .chain_err(indexing_error!(song_path, "Opening song file for cover"))?;
+            let parent = match path.parent() {
+                Some(parent) => parent.to_path_buf(),
+                None => continue,
+            };
 
-        // open the song stream
-        let stream = match input.streams().best(media::Type::Video) {
-            None => {
-                trace!(
-                    "{} does not have a video stream",
-                    song_path.to_string_lossy()
-                );
-                return Ok(None);
+            if media_include.is_match(&path_str) && !media_exclude.is_match(&path_str) {
+                trace!("Found media file.");
+                media_files.push(path.to_path_buf());
+            } else if document_include.is_match(&path_str) && !document_exclude.is_match(&path_str)
+            {
+                // documents are checked first so they never become covers
+                trace!("Found document file.");
+                dir_documents
+                    .entry(parent)
+                    .or_default()
+                    .push(path.to_path_buf());
+            } else if cover_include.is_match(&path_str) && !cover_exclude.is_match(&path_str) {
+                trace!("Found cover file.");
+                dir_covers
+                    .entry(parent)
+                    .or_default()
+                    .push(path.to_path_buf());
             }
-            Some(it) => it,
-        };
+        }
 
-        // create the decoder
-        let mut decoder = stream.codec().decoder().video().chain_err(indexing_error!(
-            song_path,
-            "Finding decoder for song file cover"
-        ))?;
+        progress
+            .media_files
+            .store(media_files.len() as u32, Ordering::Relaxed);
+
+        debug!("Reading songs...");
+        // songs are read concurrently but inserted in walk order, so the index
+        // comes out the same regardless of which reads finish first
+        let manifest_ref = &manifest;
+        let mut songs = stream::iter(media_files)
+            .map(|path| async move {
+                let metadata = Song::read_metadata(&path, config, manifest_ref).await;
+                (path, metadata)
+            })
+            .buffered(config.index_concurrency.max(1));
+
+        while let Some((path, metadata)) = songs.next().await {
+            progress.files_scanned.fetch_add(1, Ordering::Relaxed);
+            let parent = path
+                .parent()
+                .expect("BUG: Walked file has no parent")
+                .to_path_buf();
+
+            let song =
+                metadata.and_then(|metadata| Song::parse(&path, metadata, config, &mut index.urls));
+            let mut song = match song {
+                Ok(song) => song,
+                Err(e) if !config.strict_indexing => {
+                    warn!("Skipping {}: {}", path.to_string_lossy(), e.display_chain());
+                    skipped_count += 1;
+                    continue;
+                }
+                Err(e) => return Err(e),
+            };
+            let found = Index::find_directory_metadata(&mut dir_metadata, base_dir, &parent);
+            if let Some((metadata_dir, metadata)) = found {
+                song.apply_metadata(metadata_dir, metadata);
+            }
+            debug!("Loaded metadata: {:?}", &song);
+            let song = index.insert_song(song).await?;
+            song_count += 1;
+            progress.songs_found.fetch_add(1, Ordering::Relaxed);
+
+            let album_unique_name = song.read().await.album.unique_name.clone();
+            if let Some((metadata_dir, metadata)) = found {
+                let mut album = index.albums[&album_unique_name].write().await;
+                album.year = metadata.year;
+                album.genres = metadata.genre.iter().cloned().collect();
+
+                if let Some(cover) = &metadata.cover {
+                    explicit_covers.insert(album_unique_name.clone(), metadata_dir.join(cover));
+                }
+
+                // songs in subdirectories also get the covers and documents
+                // next to the metadata file
+                if metadata_dir != parent {
+                    *dir_albums
+                        .entry(metadata_dir.to_path_buf())
+                        .or_default()
+                        .entry(album_unique_name.clone())
+                        .or_default() += 1;
+                }
+            }
+            *dir_albums
+                .entry(parent)
+                .or_default()
+                .entry(album_unique_name)
+                .or_default() += 1;
+        }
+        drop(songs);
+
+        debug!("Assigning covers...");
+        for (dir, covers) in dir_covers.iter() {
+            // covers are only used by albums with songs in the same directory
+            let albums = match dir_albums.get(dir) {
+                Some(albums) => albums,
+                None => continue,
+            };
+
+            let chosen: Vec<&String> = match config.shared_directory_covers {
+                SharedDirectoryCovers::All => albums.keys().collect(),
+                SharedDirectoryCovers::MostSongs => albums
+                    .iter()
+                    .max_by(|(name1, count1), (name2, count2)| {
+                        // prefer the first album by name when counts are equal
+                        count1.cmp(count2).then_with(|| name2.cmp(name1))
+                    })
+                    .map(|(name, _)| name)
+                    .into_iter()
+                    .collect(),
+            };
+
+            for album_name in chosen {
+                let mut album = index.albums[album_name].write().await;
+                for cover in covers.iter() {
+                    trace!(
+                        "Inserting cover into album: {}: {}",
+                        album_name,
+                        cover.to_string_lossy()
+                    );
+                    Index::insert_cover(&mut album, cover, config, &mut index.urls).await?;
+                }
+            }
+        }
+
+        for (dir, albums) in dir_albums.iter() {
+            if let Some(cover) = metadata::load_cover_file(dir) {
+                // album metadata naming a cover takes precedence
+                for album_name in albums.keys() {
+                    explicit_covers
+                        .entry(album_name.clone())
+                        .or_insert_with(|| cover.clone());
+                }
+            }
+        }
+
+        explicit_covers.retain(|album_name, cover| {
+            let found = cover.is_file();
+            if !found {
+                warn!(
+                    "Album metadata cover of {} not found: {}",
+                    album_name,
+                    cover.to_string_lossy()
+                );
+            }
+            found
+        });
+        for (album_name, cover) in explicit_covers.iter() {
+            let mut album = index.albums[album_name].write().await;
+            let rating = CoverRating {
+                priority: u32::MAX,
+                score: u32::MAX,
+            };
+            Index::set_cover(&mut album, cover, rating, &mut index.urls).await?;
+        }
+
+        debug!("Assigning documents...");
+        for (dir, documents) in dir_documents.iter() {
+            let albums = match dir_albums.get(dir) {
+                Some(albums) => albums,
+                None => continue,
+            };
+
+            for album_name in albums.keys() {
+                let mut album = index.albums[album_name].write().await;
+                for document in documents.iter() {
+                    album.documents.push(index.urls.url(document)?);
+                }
+            }
+        }
+
+        index.find_album_tags().await;
+
+        info!("Probed {} new or changed songs", manifest.probed());
+        if let Err(e) = manifest.save().await {
+            warn!("Unable to save manifest: {}", e);
+        }
+        if let Err(e) = index.names.save() {
+            warn!("Unable to save unique names: {}", e);
+        }
+
+        index.sort_lists();
+
+        info!(
+            "Indexed {} songs, skipping {}, with {} duplicate tracks, in {:?}",
+            song_count,
+            skipped_count,
+            index.duplicates.len(),
+            SystemTime::now().duration_since(index_start_time).unwrap()
+        );
+
+        info!("{} Albums loaded.", index.albums.len());
+
+        debug!(
+            "Albums: {:?}",
+            &stream::iter(&index.album_list)
+                .then(|rc| async move { rc.read().await.unique_name.clone() })
+                .collect::<Vec<_>>()
+                .await
+        );
+
+        info!("{} Artists loaded.", index.artists.len());
+
+        debug!(
+            "Artists: {:?}",
+            &stream::iter(&index.artist_list)
+                .then(|rc| async move { rc.read().await.unique_name.clone() })
+                .collect::<Vec<_>>()
+                .await
+        );
+
+        info!("Generating album covers...");
+        let cover_gen_start_time = SystemTime::now();
+        let mut covers_generated = 0u32;
+        for album in index.album_list.iter() {
+            let (current_cover, explicit) = {
+                let album = album.read().await;
+                (
+                    album.cover_path.clone(),
+                    explicit_covers.contains_key(&album.unique_name),
+                )
+            };
+            if current_cover.is_some() && (!config.prefer_embedded_covers || explicit) {
+                continue;
+            }
+
+            let cover_path = {
+                // we don't want to be holding this lock when we insert the cover
+                let album = album.read().await;
+                Index::gen_cover(&album, config).await?
+            };
+            if let Some(cover_path) = cover_path {
+                let mut album = album.write().await;
+                if let Some(current_cover) = current_cover {
+                    if !Index::is_cover_as_large(&cover_path, &current_cover) {
+                        continue;
+                    }
+
+                    // clear the cover file so the embedded art replaces it
+                    album.cover_path = None;
+                    album.cover_rating = Default::default();
+                }
+
+                Index::insert_cover(&mut album, &cover_path, config, &mut index.urls).await?;
+                album.cover_generated = true;
+                covers_generated += 1;
+                progress.covers_generated.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        info!(
+            "Generated {} covers in {:?}",
+            covers_generated,
+            SystemTime::now()
+                .duration_since(cover_gen_start_time)
+                .unwrap()
+        );
+
+        debug!("Reading cover dimensions...");
+        index.read_cover_sizes().await?;
+
+        if config.group_singles {
+            info!("Grouping singles...");
+            let singles = index.group_singles().await;
+            info!("Grouped {} singles", singles);
+        }
+
+        debug!("Assigning artist covers...");
+        index.assign_artist_covers(&dir_covers, config).await?;
+
+        let orphaned_cover_dirs = Index::find_cover_only_dirs(&dir_covers, &dir_albums);
+        if !orphaned_cover_dirs.is_empty() {
+            info!(
+                "Skipped covers in {} directories without songs:",
+                orphaned_cover_dirs.len()
+            );
+            for dir in orphaned_cover_dirs {
+                info!("  {}", dir.to_string_lossy());
+            }
+        }
+
+        index.content_hash = index.hash_content().await;
+        index.build_time = SystemTime::now()
+            .duration_since(index_start_time)
+            .unwrap_or_default();
+
+        Ok(index)
+    }
+
+    /// Finds the directories with covers but no songs. Directories holding
+    /// album directories are left out, since their images can still be artist
+    /// covers.
+    fn find_cover_only_dirs<'a, T>(
+        dir_covers: &'a BTreeMap<PathBuf, Vec<PathBuf>>,
+        dir_albums: &BTreeMap<PathBuf, T>,
+    ) -> Vec<&'a Path> {
+        let album_parents: HashSet<&Path> =
+            dir_albums.keys().filter_map(|dir| dir.parent()).collect();
+        dir_covers
+            .keys()
+            .filter(|dir| !dir_albums.contains_key(*dir) && !album_parents.contains(dir.as_path()))
+            .map(|dir| dir.as_path())
+            .collect()
+    }
+
+    /// Fills in the tags of albums that weren't given by their metadata file
+    /// from their songs. Albums get the earliest year of their songs and all of
+    /// their songs' genres, the most common first.
+    async fn find_album_tags(&self) {
+        for album in self.albums.values() {
+            let mut album = album.write().await;
+
+            let mut year: Option<u32> = None;
+            let mut genres: BTreeMap<String, u32> = BTreeMap::new();
+            for song in album.songs.iter().flatten() {
+                let song = song.read().await;
+                if let Some(song_year) = song.year {
+                    year = Some(year.map_or(song_year, |year| year.min(song_year)));
+                }
+                for genre in song.genres.iter() {
+                    *genres.entry(genre.clone()).or_default() += 1;
+                }
+            }
+
+            if album.year.is_none() {
+                album.year = year;
+            }
+
+            if album.genres.is_empty() {
+                // ties go to the alphabetically first genre
+                let mut genres: Vec<_> = genres.into_iter().collect();
+                genres.sort_by(|(_, a), (_, b)| b.cmp(a));
+                album.genres = genres.into_iter().map(|(genre, _)| genre).collect();
+            }
+        }
+    }
+
+    /// Reads the dimensions of each album's chosen cover. Only the image headers
+    /// are read, and covers that can't be read are left without dimensions.
+    async fn read_cover_sizes(&self) -> Result<()> {
+        for album in self.album_list.iter() {
+            let cover_path = match &album.read().await.cover_path {
+                Some(cover_path) => cover_path.clone(),
+                None => continue,
+            };
+
+            let cover_path_moved = cover_path.clone();
+            let size =
+                tokio::task::spawn_blocking(move || image::image_dimensions(&cover_path_moved))
+                    .await
+                    .chain_err(path_error!(
+                        IndexingError,
+                        cover_path,
+                        "reading cover dimensions off-thread"
+                    ))?;
+
+            match size {
+                Ok(size) => album.write().await.cover_size = Some(size),
+                Err(e) => warn!(
+                    "Unable to read cover dimensions of {}: {}",
+                    cover_path.to_string_lossy(),
+                    e
+                ),
+            }
+        }
+
+        Ok(())
+    }
+
+    fn sort_lists(&mut self) {
+        debug!("Sorting artists...");
+        self.artist_list.clear();
+        self.artist_list.reserve(self.artists.len());
+        let mut artist_names = self.artists.keys().collect::<Vec<_>>();
+        artist_names.sort();
+        for artist_name in artist_names {
+            self.artist_list.push(self.artists[artist_name].clone());
+        }
+
+        debug!("Sorting albums...");
+        self.album_list.clear();
+        self.album_list.reserve(self.albums.len());
+        let mut album_names = self.albums.keys().collect::<Vec<_>>();
+        album_names.sort();
+        for album_name in album_names {
+            self.album_list.push(self.albums[album_name].clone());
+        }
+    }
+
+    /// Moves the songs of all single-song albums into a synthetic "Singles"
+    /// album belonging to the song's first artist. Returns the number of songs
+    /// moved.
+    async fn group_singles(&mut self) -> u32 {
+        let mut singles_albums: HashMap<String, Arc<RwLock<Album>>> = HashMap::new();
+        let mut moved = 0u32;
+
+        for album_arc in self.album_list.clone() {
+            let mut album = album_arc.write().await;
+            if !album.is_single() {
+                continue;
+            }
+
+            let song = album.songs[0]
+                .clone()
+                .expect("BUG: Single album has no song");
+            let artist_ref = match album.artists.first() {
+                Some(artist_ref) => artist_ref.clone(),
+                None => continue,
+            };
+
+            trace!(
+                "Moving single {} into singles for {}",
+                album.unique_name,
+                artist_ref.unique_name
+            );
+
+            self.albums.remove(&album.unique_name);
+            for other in album.artists.iter() {
+                self.artists[&other.unique_name]
+                    .write()
+                    .await
+                    .albums
+                    .remove(&album.unique_name);
+            }
+
+            if !singles_albums.contains_key(&artist_ref.unique_name) {
+                let mut unique_name = format!("{}-singles", artist_ref.unique_name);
+                let mut index = 1u32;
+                while self.albums.contains_key(&unique_name) {
+                    unique_name = format!("{}-singles-{}", artist_ref.unique_name, index);
+                    index += 1;
+                }
+
+                let singles = Arc::new(RwLock::new(Album {
+                    name: SINGLES_ALBUM_NAME.to_string(),
+                    unique_name: unique_name.clone(),
+                    search_key: search_key(SINGLES_ALBUM_NAME),
+                    artists: vec![artist_ref.clone()],
+                    songs: Default::default(),
+                    discs: Default::default(),
+                    untracked: Default::default(),
+                    songs_by_name: Default::default(),
+                    cover_url: album.cover_url.clone(),
+                    cover_path: album.cover_path.clone(),
+                    cover_size: album.cover_size,
+                    cover_rating: album.cover_rating,
+                    cover_generated: album.cover_generated,
+                    tracked: false,
+                    year: None,
+                    genres: vec![],
+                    disc_total: None,
+                    track_totals: Default::default(),
+                    documents: vec![],
+                    path: album.path.clone(),
+                }));
+
+                self.albums.insert(unique_name.clone(), singles.clone());
+                self.artists[&artist_ref.unique_name]
+                    .write()
+                    .await
+                    .albums
+                    .insert(unique_name, singles.clone());
+                singles_albums.insert(artist_ref.unique_name.clone(), singles);
+            }
+
+            let mut singles = singles_albums[&artist_ref.unique_name].write().await;
+            {
+                let mut song = song.write().await;
+
+                // different singles may well share a name
+                let mut unique_name = song.unique_name.clone();
+                let mut index = 1u32;
+                while singles.songs_by_name.contains_key(&unique_name) {
+                    unique_name = format!("{}-{}", song.unique_name, index);
+                    index += 1;
+                }
+                song.unique_name = unique_name;
+
+                song.album = AlbumRef {
+                    name: singles.name.clone(),
+                    unique_name: singles.unique_name.clone(),
+                };
+            }
+
+            let (song_name, name, path) = {
+                let song = song.read().await;
+                (
+                    song.unique_name.clone(),
+                    song.name.clone(),
+                    song.path.clone(),
+                )
+            };
+            singles.place_song(song.clone(), &name, &path, None, None);
+            singles.songs_by_name.insert(song_name, song);
+            moved += 1;
+        }
+
+        self.sort_lists();
+
+        moved
+    }
+
+    async fn insert_song(&mut self, mut song: Song) -> Result<Arc<RwLock<Song>>> {
+        for artist in song.artists.iter_mut().chain(song.album_artists.iter_mut()) {
+            artist.unique_name = self.get_or_insert_artist(&artist.name).await;
+        }
+
+        // compilations belong to their album artist instead of every track artist
+        let album_artists = if song.album_artists.is_empty() {
+            &song.artists
+        } else {
+            &song.album_artists
+        };
+        let album = self
+            .get_or_insert_album(
+                &song.album.name,
+                album_artists,
+                song.path
+                    .parent()
+                    .chain_err(path_error!(IndexingError, song.path, "getting song path"))?
+                    .to_path_buf(),
+            )
+            .await;
+
+        {
+            // casing variants of an album name use the first seen casing
+            let album = album.read().await;
+            song.album.name = album.name.clone();
+            song.album.unique_name = album.unique_name.clone();
+            // covers found later are stamped onto the song by insert_cover
+            song.cover_url = album.cover_url.clone();
+        }
+
+        if let Some(disc_total) = song.disc_total.or(song.disc) {
+            let mut album = album.write().await;
+            album.disc_total = album.disc_total.max(Some(disc_total));
+        }
+
+        if let Some(track_total) = song.track_total {
+            let mut album = album.write().await;
+            let total = album
+                .track_totals
+                .entry(song.disc.unwrap_or(1))
+                .or_insert(0);
+            *total = (*total).max(track_total);
+        }
+
+        let song_name = song.unique_name.clone();
+        let name = song.name.clone();
+        let path = song.path.clone();
+        let disc = song.disc;
+        let track = song.track;
+        let song = Arc::new(RwLock::new(song));
+
+        let mut album = album.write().await;
+        let existing = track.and_then(|track| {
+            album
+                .discs
+                .get(&disc.unwrap_or(1))
+                .and_then(|songs| songs.get(track as usize - 1).cloned().flatten())
+        });
+        if let (Some(existing_song), Some(track)) = (existing, track) {
+            let existing = existing_song.read().await;
+            let new = song.read().await;
+            let keep_new = self.config.duplicate_tracks == DuplicateTracks::HigherBitRate
+                && new.bit_rate > existing.bit_rate;
+            let (kept, dropped) = if keep_new {
+                (&*new, &*existing)
+            } else {
+                (&*existing, &*new)
+            };
+
+            warn!(
+                "{} and {} are both track {} of {}, keeping {}",
+                existing.path.to_string_lossy(),
+                new.path.to_string_lossy(),
+                track,
+                album.name,
+                kept.path.to_string_lossy()
+            );
+            self.duplicates.push(DuplicateTrack {
+                album: new.album.clone(),
+                disc: disc.unwrap_or(1),
+                track,
+                kept: DuplicateSong::from_song(kept),
+                dropped: DuplicateSong::from_song(dropped),
+            });
+
+            // dropped songs aren't served, and usually share the kept song's
+            // unique name
+            let dropped_name = existing.unique_name.clone();
+            drop(new);
+            drop(existing);
+            if !keep_new {
+                return Ok(song);
+            }
+            let is_dropped = album
+                .songs_by_name
+                .get(&dropped_name)
+                .map_or(false, |song| Arc::ptr_eq(song, &existing_song));
+            if is_dropped {
+                album.songs_by_name.remove(&dropped_name);
+            }
+        }
+        album.place_song(song.clone(), &name, &path, disc, track);
+        album.songs_by_name.insert(song_name, song.clone());
+
+        Ok(song)
+    }
+
+    async fn get_or_insert_album(
+        &mut self,
+        name: &str,
+        artists: &[ArtistRef],
+        path: PathBuf,
+    ) -> Arc<RwLock<Album>> {
+        let mut unique_name = self
+            .names
+            .album(name)
+            .map_or_else(|| sanitize(name), |stored| stored.to_string());
+
+        if self.album_taken(&unique_name, name) {
+            if let Some(found) = self.albums.get(&unique_name).cloned() {
+                if same_album_name(&found.read().await.name, name) {
+                    for artist_ref in artists {
+                        if found
+                            .read()
+                            .await
+                            .artists
+                            .iter()
+                            .find(|a| a.unique_name == artist_ref.unique_name)
+                            .is_none()
+                        {
+                            found.write().await.artists.push(artist_ref.clone());
+                            self.artists[&artist_ref.unique_name]
+                                .write()
+                                .await
+                                .albums
+                                .insert(unique_name.clone(), found.clone());
+                        }
+                    }
+
+                    return found;
+                }
+            }
+
+            let mut index = 1u32;
+            let mut found_name = format!("{}-{}", unique_name, index);
+            while self.album_taken(&found_name, name) {
+                if let Some(found) = self.albums.get(&found_name).cloned() {
+                    if same_album_name(&found.read().await.name, name) {
+                        for artist_ref in artists {
+                            if found
+                                .read()
+                                .await
+                                .artists
+                                .iter()
+                                .find(|a| a.unique_name == artist_ref.unique_name)
+                                .is_none()
+                            {
+                                found.write().await.artists.push(artist_ref.clone());
+                                self.artists[&artist_ref.unique_name]
+                                    .write()
+                                    .await
+                                    .albums
+                                    .insert(found_name.clone(), found.clone());
+                            }
+                        }
+
+                        return found;
+                    }
+                }
+
+                index += 1;
+                found_name = format!("{}-{}", unique_name, index);
+            }
+            unique_name = found_name;
+        }
+
+        let album = Arc::new(RwLock::new(Album {
+            name: name.to_string(),
+            unique_name: unique_name.clone(),
+            search_key: search_key(name),
+            artists: artists.to_vec(),
+            songs: Default::default(),
+            discs: Default::default(),
+            untracked: Default::default(),
+            songs_by_name: Default::default(),
+            cover_url: None,
+            cover_path: None,
+            cover_size: None,
+            cover_rating: Default::default(),
+            cover_generated: false,
+            tracked: false,
+            year: None,
+            genres: vec![],
+            disc_total: None,
+            track_totals: Default::default(),
+            documents: vec![],
+            path,
+        }));
+
+        self.albums.insert(unique_name.clone(), album.clone());
+        self.names.insert_album(name, &unique_name);
+        for artist_ref in artists {
+            self.artists
+                .get_mut(&artist_ref.unique_name)
+                .expect("BUG: Missing newly inserted artist")
+                .write()
+                .await
+                .albums
+                .insert(unique_name.clone(), album.clone());
+        }
+
+        album
+    }
+
+    async fn get_or_insert_artist(&mut self, name: &str) -> String {
+        let key = artist_key(name, &self.config.artist_articles);
+        if let Some(unique_name) = self.artist_keys.get(&key) {
+            return unique_name.clone();
+        }
+
+        let mut unique_name = self
+            .names
+            .artist(name)
+            .map_or_else(|| sanitize(name), |stored| stored.to_string());
+
+        if self.artist_taken(&unique_name, name) {
+            if let Some(found) = self.artists.get(&unique_name) {
+                let borrowed = found.read().await;
+                if borrowed.name == name {
+                    return borrowed.unique_name.clone();
+                }
+            }
+
+            let mut index = 1u32;
+            let mut found_name = format!("{}-{}", unique_name, index);
+            while self.artist_taken(&found_name, name) {
+                if let Some(found) = self.artists.get(&found_name) {
+                    let borrowed = found.read().await;
+                    if borrowed.name == name {
+                        return borrowed.unique_name.clone();
+                    }
+                }
+
+                index += 1;
+                found_name = format!("{}-{}", unique_name, index);
+            }
+            unique_name = found_name;
+        }
+
+        let artist = Arc::new(RwLock::new(Artist {
+            name: name.to_string(),
+            unique_name: unique_name.clone(),
+            search_key: search_key(name),
+            albums: Default::default(),
+            cover_url: None,
+        }));
+
+        // we couldn't find the artist, so we'll insert a new one
+        self.artists.insert(unique_name.clone(), artist.clone());
+        self.artist_keys.insert(key, unique_name.clone());
+        self.names.insert_artist(name, &unique_name);
+
+        unique_name
+    }
+
+    /// Checks whether `unique_name` can't be given to a new album called `name`.
+    fn album_taken(&self, unique_name: &str, name: &str) -> bool {
+        self.albums.contains_key(unique_name) || self.names.album_reserved(unique_name, name)
+    }
+
+    /// Checks whether `unique_name` can't be given to a new artist called `name`.
+    fn artist_taken(&self, unique_name: &str, name: &str) -> bool {
+        self.artists.contains_key(unique_name) || self.names.artist_reserved(unique_name, name)
+    }
+
+    async fn insert_cover(
+        album: &mut Album,
+        path: &Path,
+        config: &Config,
+        urls: &mut FileUrls,
+    ) -> Result<()> {
+        let rating = Index::rate_cover(&path, config)?;
+        let wins = match &album.cover_path {
+            Some(current) if rating == album.cover_rating => {
+                Index::wins_cover_tie(config.cover_tiebreak, path, current)
+            }
+            _ => rating > album.cover_rating,
+        };
+
+        if wins {
+            Index::set_cover(album, path, rating, urls).await?;
+        }
+
+        Ok(())
+    }
+
+    async fn set_cover(
+        album: &mut Album,
+        path: &Path,
+        rating: CoverRating,
+        urls: &mut FileUrls,
+    ) -> Result<()> {
+        let cover_url = Some(urls.url(path)?);
+        album.cover_url = cover_url.clone();
+        album.cover_path = Some(path.to_path_buf());
+        album.cover_rating = rating;
+
+        // update all songs for the current album, including untracked ones
+        for song in album.songs_by_name.values() {
+            song.write().await.cover_url = cover_url.clone();
+        }
+
+        Ok(())
+    }
+
+    /// Finds the album metadata for songs in `dir`: the nearest metadata file
+    /// in `dir` or, if it covers its subdirectories, in one of its ancestors
+    /// below `base_dir`. Returns the metadata file's directory with it.
+    fn find_directory_metadata<'a>(
+        cache: &'a mut HashMap<PathBuf, Option<DirectoryMetadata>>,
+        base_dir: &Path,
+        dir: &Path,
+    ) -> Option<(&'a Path, &'a DirectoryMetadata)> {
+        let mut found = None;
+        for ancestor in dir.ancestors().take_while(|a| a.starts_with(base_dir)) {
+            let metadata = cache
+                .entry(ancestor.to_path_buf())
+                .or_insert_with(|| DirectoryMetadata::load(ancestor));
+            if let Some(metadata) = metadata {
+                // the nearest metadata file is the album boundary
+                if ancestor == dir || metadata.subdirectories {
+                    found = Some(ancestor.to_path_buf());
+                }
+                break;
+            }
+        }
+
+        let (path, metadata) = cache.get_key_value(&found?)?;
+        Some((path.as_path(), metadata.as_ref()?))
+    }
+
+    /// Decides whether a new cover should replace the current cover when both
+    /// have the same rating.
+    fn wins_cover_tie(tiebreak: CoverTiebreak, new: &Path, current: &Path) -> bool {
+        let by_name = (new.file_name(), new) < (current.file_name(), current);
+
+        match tiebreak {
+            CoverTiebreak::FirstFound => false,
+            CoverTiebreak::LargestFile => {
+                let new_len = new.metadata().map(|m| m.len()).unwrap_or(0);
+                let current_len = current.metadata().map(|m| m.len()).unwrap_or(0);
+                new_len > current_len || (new_len == current_len && by_name)
+            }
+            CoverTiebreak::FileName => by_name,
+        }
+    }
+
+    /// Builds a new index of the library with the settings of this one. Songs
+    /// unchanged since they were recorded in the manifest aren't probed again,
+    /// unless they are in `reprobe`.
+    ///
+    /// With `dirs`, only the files directly in those directories are walked
+    /// again and the rest of the library is taken from this index's walk, so
+    /// files added or removed elsewhere aren't noticed.
+    pub async fn reindex(
+        &self,
+        reprobe: &[PathBuf],
+        dirs: Option<&[PathBuf]>,
+        progress: &IndexProgress,
+    ) -> Result<Index> {
+        let walk = match dirs {
+            Some(dirs) => Walk::Dirs {
+                dirs,
+                previous: &self.walked,
+            },
+            None => Walk::All,
+        };
+
+        Index::index_walking(
+            &self.config,
+            &self.base_url,
+            self.ratings.clone(),
+            reprobe,
+            progress,
+            walk,
+        )
+        .await
+    }
+
+    /// Walks `dir`, or only the files directly in it if `shallow` is set.
+    /// Entries that can't be read are skipped.
+    async fn walk(dir: PathBuf, shallow: bool) -> Result<Vec<PathBuf>> {
+        let walked: Vec<_> = tokio::task::spawn_blocking(move || {
+            let mut walker = walkdir::WalkDir::new(&dir).follow_links(true);
+            if shallow {
+                walker = walker.min_depth(1).max_depth(1);
+            }
+
+            // symlinks can make a directory reachable more than once, so each
+            // directory is only walked at its first path
+            let mut visited = HashSet::new();
+            walker
+                .into_iter()
+                .filter_entry(|entry| {
+                    if !entry.file_type().is_dir() {
+                        return true;
+                    }
+
+                    match entry.path().canonicalize() {
+                        Ok(canonical) if !visited.insert(canonical.clone()) => {
+                            warn!(
+                                "Skipping {}, which links to the already indexed {}",
+                                entry.path().to_string_lossy(),
+                                canonical.to_string_lossy()
+                            );
+                            false
+                        }
+                        _ => true,
+                    }
+                })
+                .collect()
+        })
+        .await
+        .chain_err(|| ErrorKind::IndexingError(None, "doing initial music directory traversal"))?;
+
+        let mut paths = vec![];
+        for entry in walked {
+            match entry {
+                Ok(entry) => paths.push(entry.into_path()),
+                Err(err) if err.loop_ancestor().is_some() => {
+                    warn!(
+                        "Skipping symlink loop at {}, which links to its ancestor {}",
+                        err.path()
+                            .map_or_else(|| "Unknown".into(), |path| path.to_string_lossy()),
+                        err.loop_ancestor()
+                            .map_or_else(|| "Unknown".into(), |path| path.to_string_lossy())
+                    );
+                }
+                Err(err) => {
+                    warn!(
+                        "Encountered invalid file while scanning. Path: {}",
+                        if let Some(entry) = err.path() {
+                            entry.to_string_lossy()
+                        } else {
+                            "Unknown".into()
+                        }
+                    );
+                }
+            }
+        }
+
+        Ok(paths)
+    }
+
+    /// Replaces the files directly in `dirs` of a previous walk with the ones
+    /// found walking those directories again. The new files take the place of
+    /// the first replaced one, so albums keep their order in the library.
+    fn merge_walks(previous: &[PathBuf], dirs: &[PathBuf], rewalked: Vec<PathBuf>) -> Vec<PathBuf> {
+        let mut merged = Vec::with_capacity(previous.len() + rewalked.len());
+        let mut rewalked = Some(rewalked);
+
+        for path in previous {
+            let replaced = path
+                .parent()
+                .map_or(false, |parent| dirs.iter().any(|dir| dir == parent));
+            if !replaced {
+                merged.push(path.clone());
+            } else if let Some(rewalked) = rewalked.take() {
+                merged.extend(rewalked);
+            }
+        }
+        merged.extend(rewalked.into_iter().flatten());
+
+        merged
+    }
+
+    /// Counts the songs of all albums, including untracked ones.
+    pub async fn song_count(&self) -> usize {
+        let mut song_count = 0;
+        for album in self.album_list.iter() {
+            song_count += album.read().await.songs_by_name.len();
+        }
+        song_count
+    }
+
+    /// The entity tag of responses built from this index and the current
+    /// ratings.
+    pub fn etag(&self) -> String {
+        format!("\"{}-{}\"", self.content_hash, self.ratings.revision())
+    }
+
+    /// When responses built from this index and the current ratings last
+    /// changed.
+    pub fn modified(&self) -> SystemTime {
+        self.ratings
+            .modified()
+            .map_or(self.modified, |ratings| ratings.max(self.modified))
+    }
+
+    /// Hashes the json of every album, artist and song.
+    async fn hash_content(&self) -> String {
+        // ratings change without reindexing, so they are left out
+        let no_ratings = Ratings::empty();
+        let mut hasher = Sha256::new();
+        let mut update = |json: serde_json::Result<Vec<u8>>| {
+            hasher.update(json.expect("BUG: Unable to encode index json"));
+        };
+
+        for album in self.album_list.iter() {
+            let album = album.read().await;
+            update(serde_json::to_vec(
+                &AlbumJson::from_album(&album, &no_ratings).await,
+            ));
+            for song in album.songs.iter().flatten() {
+                update(serde_json::to_vec(&SongJson::from_song(
+                    &*song.read().await,
+                    &no_ratings,
+                    &self.config,
+                )));
+            }
+        }
+        for artist in self.artist_list.iter() {
+            let mut json = ArtistJson::from_artist(&*artist.read().await).await;
+            // artists list their albums in map order
+            json.albums
+                .sort_by(|a, b| a.unique_name.cmp(&b.unique_name));
+            update(serde_json::to_vec(&json));
+        }
+
+        // half a sha256 is still plenty to tell indexes apart
+        let mut hash = format!("{:x}", hasher.finalize());
+        hash.truncate(32);
+        hash
+    }
+
+    /// Checks whether a file system event reported by the watcher can change
+    /// the index.
+    pub fn is_affected_by(&self, event: &DebouncedEvent) -> bool {
+        match event {
+            // removed directories can't be told apart from files anymore
+            DebouncedEvent::Remove(path) | DebouncedEvent::Rename(path, _) => {
+                !self.is_written_by_indexer(path)
+            }
+            // directories moved into the library only report themselves
+            DebouncedEvent::Create(path) => {
+                self.is_library_file(path) || (path.is_dir() && !self.is_written_by_indexer(path))
+            }
+            DebouncedEvent::Write(path) => self.is_library_file(path),
+            DebouncedEvent::Rescan => true,
+            DebouncedEvent::Error(e, path) => {
+                warn!("File watcher error at {:?}: {}", path, e);
+                false
+            }
+            // notices come before the debounced event for the same change
+            DebouncedEvent::NoticeWrite(_)
+            | DebouncedEvent::NoticeRemove(_)
+            | DebouncedEvent::Chmod(_) => false,
+        }
+    }
+
+    /// Checks whether `path` is somewhere indexing writes to, so that the
+    /// watcher doesn't reindex because of the index's own files: the cache
+    /// dirs and extracted covers, including their temporary files.
+    fn is_written_by_indexer(&self, path: &Path) -> bool {
+        let config = &self.config;
+        path.starts_with(&config.cache_dir)
+            || config
+                .cover_cache_dir
+                .as_ref()
+                .map_or(false, |dir| path.starts_with(dir))
+            || path.file_name().map_or(false, |name| {
+                name.to_string_lossy().contains(GENERATED_COVER_MARKER)
+            })
+    }
+
+    fn is_library_file(&self, path: &Path) -> bool {
+        let config = &self.config;
+        if self.is_written_by_indexer(path) {
+            return false;
+        }
+        if path
+            .file_name()
+            .map_or(false, |name| name == METADATA_FILE || name == COVER_FILE)
+        {
+            return true;
+        }
+
+        let path_str = path.to_string_lossy();
+        let matches = |include: &RegexSet, exclude: &RegexSet| {
+            include.is_match(&path_str) && !exclude.is_match(&path_str)
+        };
+        matches(
+            &config.media_include_patterns,
+            &config.media_exclude_patterns,
+        ) || matches(
+            &config.cover_include_patterns,
+            &config.cover_exclude_patterns,
+        ) || matches(
+            &config.document_include_patterns,
+            &config.document_exclude_patterns,
+        )
+    }
+
+    /// Finds the file a file id refers to.
+    pub fn file_path(&self, id: &str) -> Option<&Path> {
+        self.urls.ids.get(id).map(|path| path.as_path())
+    }
+
+    async fn find_song(&self, album_name: &str, song_name: &str) -> Result<Arc<RwLock<Song>>> {
+        if let Some(album) = self.albums.get(album_name) {
+            if let Some(song) = album.read().await.songs_by_name.get(song_name) {
+                return Ok(song.clone());
+            }
+        } else {
+            bail!(ErrorKind::NoSuchResource("album", album_name.to_string()));
+        }
+
+        bail!(ErrorKind::NoSuchResource("song", song_name.to_string()))
+    }
+
+    /// Gives artists the best artist image in the directories containing their
+    /// album directories. Artists without one optionally get the best rated
+    /// cover of their albums instead.
+    async fn assign_artist_covers(
+        &mut self,
+        dir_covers: &BTreeMap<PathBuf, Vec<PathBuf>>,
+        config: &Config,
+    ) -> Result<()> {
+        for artist in self.artists.values() {
+            let mut artist = artist.write().await;
+
+            let mut best: Option<((u32, CoverRating), &PathBuf)> = None;
+            for album in artist.albums.values() {
+                let album = album.read().await;
+                // images next to all artists' directories belong to no artist
+                let dir = match album.path.parent() {
+                    Some(dir) if dir != config.base_dir => dir,
+                    _ => continue,
+                };
+
+                for cover in dir_covers.get(dir).into_iter().flatten() {
+                    let rating = match Index::rate_artist_cover(cover, config)? {
+                        Some(rating) => rating,
+                        None => continue,
+                    };
+                    let wins = match best {
+                        Some((best_rating, best_path)) if rating == best_rating => {
+                            Index::wins_cover_tie(config.cover_tiebreak, cover, best_path)
+                        }
+                        Some((best_rating, _)) => rating > best_rating,
+                        None => true,
+                    };
+                    if wins {
+                        best = Some((rating, cover));
+                    }
+                }
+            }
+
+            if let Some((_, path)) = best {
+                artist.cover_url = Some(self.urls.url(path)?);
+            } else if config.artist_cover_fallback {
+                let mut best: Option<(CoverRating, String, Option<String>)> = None;
+                for album in artist.albums.values() {
+                    let album = album.read().await;
+                    if album.cover_url.is_none() {
+                        continue;
+                    }
+
+                    // ties go to the first album by unique name so the choice
+                    // doesn't depend on map order
+                    let wins = match &best {
+                        Some((rating, unique_name, _)) => {
+                            (album.cover_rating, Reverse(&album.unique_name))
+                                > (*rating, Reverse(unique_name))
+                        }
+                        None => true,
+                    };
+                    if wins {
+                        best = Some((
+                            album.cover_rating,
+                            album.unique_name.clone(),
+                            album.cover_url.clone(),
+                        ));
+                    }
+                }
+
+                artist.cover_url = best.and_then(|(_, _, cover_url)| cover_url);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Rates an image as an artist image. Images that don't match any of the
+    /// artist image globs aren't artist images.
+    fn rate_artist_cover(path: &Path, config: &Config) -> Result<Option<(u32, CoverRating)>> {
+        let file_name =
+            path.file_name()
+                .chain_err(path_error!(IndexingError, path, "rating artist cover"))?;
+        let name = file_name.to_string_lossy();
+
+        let position = match config
+            .artist_covers
+            .iter()
+            .position(|pattern| pattern.is_match(&name))
+        {
+            Some(position) => position,
+            None => return Ok(None),
+        };
+        let priority = (config.artist_covers.len() - position) as u32;
+
+        Ok(Some((priority, Index::rate_cover(path, config)?)))
+    }
+
+    fn rate_cover(path: &Path, config: &Config) -> Result<CoverRating> {
+        let file_name =
+            path.file_name()
+                .chain_err(path_error!(IndexingError, path, "rating cover"))?;
+        let name = file_name.to_string_lossy();
+
+        let priority = config
+            .cover_priority
+            .iter()
+            .position(|pattern| pattern.is_match(&name))
+            .map_or(0, |index| (config.cover_priority.len() - index) as u32);
+
+        let mut value = 1u32;
+
+        for (substring, weight) in config.cover_rating_rules.iter() {
+            if name.contains(substring.as_str()) {
+                value += weight;
+            }
+        }
+
+        Ok(CoverRating {
+            priority,
+            score: value,
+        })
+    }
+
+    async fn gen_cover(album: &Album, config: &Config) -> Result<Option<PathBuf>> {
+        trace!("Generating cover for {}", album.unique_name);
+        let format = config.generated_cover_format;
+        let cover_dir = config.cover_cache_dir.clone();
+        for song in album.songs.iter() {
+            if let Some(song) = song {
+                let song_path = song.read().await.path.clone();
+                let song_path_2 = song_path.clone();
+                let cover_dir = cover_dir.clone();
+                let album_name = album.unique_name.clone();
+                trace!("Scanning song: {}", &song_path.to_string_lossy());
+
+                let cover: Result<_> = ffmpeg::spawn_blocking(move || {
+                    let path = Index::make_cover_path(
+                        &song_path,
+                        cover_dir.as_deref(),
+                        &album_name,
+                        format,
+                    )?;
+                    if is_fresh(&path, &song_path) {
+                        trace!("Reusing cover: {}", path.to_string_lossy());
+                        return Ok(Some(path));
+                    }
+
+                    let frame = Index::read_frame(&song_path)?;
+                    if let Some(frame) = frame {
+                        let data = Index::fit_frame(&frame);
+
+                        Index::save_cover(&path, &data, frame.width(), frame.height(), format)?;
+
+                        Ok(Some(path))
+                    } else {
+                        Ok(None)
+                    }
+                })
+                .await
+                .chain_err(path_error!(
+                    IndexingError,
+                    &song_path_2,
+                    "Running cover image extraction off-thread"
+                ))?;
+                let cover = cover?;
+
+                if cover.is_some() {
+                    return Ok(cover);
+                }
+            }
+        }
+
+        Ok(None)
+    }
+
+    fn read_frame(song_path: &Path) -> Result<Option<frame::Video>> {
+        let mut input = format::input(&song_path).chain_err(path_error!(
+            IndexingError,
+            song_path,
+            "Opening song file for cover"
+        ))?;
+
+        // open the song stream
+        let stream = match input.streams().best(media::Type::Video) {
+            None => {
+                trace!(
+                    "{} does not have a video stream",
+                    song_path.to_string_lossy()
+                );
+                return Ok(None);
+            }
+            Some(it) => it,
+        };
+
+        // create the decoder
+        let mut decoder = stream.codec().decoder().video().chain_err(path_error!(
+            IndexingError,
+            song_path,
+            "Finding decoder for song file cover"
+        ))?;
         decoder
             .set_parameters(stream.parameters())
-            .chain_err(indexing_error!(
+            .chain_err(path_error!(
+                IndexingError,
+                song_path,
+                "Setting parameters for song file cover decoder"
+            ))?;
+        let stream_index = stream.index();
+
+        // create the converter to convert the cover to RGBA color space
+        let mut converter_2 = software::converter(
+            (decoder.width(), decoder.height()),
+            decoder.format(),
+            format::Pixel::RGBA,
+        )
+        .chain_err(path_error!(
+            IndexingError,
+            song_path,
+            "Creating cover image converter for song file"
+        ))?;
+
+        let mut decoded = frame::Video::empty();
+        let mut converted = frame::Video::empty();
+
+        // find the cover packet
+        for (stream, mut packet) in input.packets() {
+            if stream.index() == stream_index {
+                packet.rescale_ts(stream.time_base(), decoder.time_base());
+
+                // decode the cover
+                if let Ok(true) = decoder.decode(&packet, &mut decoded) {
+                    let timestamp = decoded.timestamp();
+                    decoded.set_pts(timestamp);
+
+                    trace!("Frame dimensions: {}x{}", decoded.width(), decoded.height());
+                    trace!("Frame colorspace: {:?}", decoded.color_space());
+                    trace!("Frame pixel format: {:?}", decoded.format());
+
+                    // convert the cover to RGBA color space
+                    converter_2
+                        .run(&decoded, &mut converted)
+                        .chain_err(path_error!(
+                            IndexingError,
+                            song_path,
+                            "Converting song cover"
+                        ))?;
+
+                    converted.set_pts(decoded.pts());
+
+                    return Ok(Some(converted));
+                }
+            }
+        }
+
+        Ok(None)
+    }
+
+    /// Checks whether a cover has at least as many pixels as another. Covers
+    /// that can't be read lose.
+    fn is_cover_as_large(cover_path: &Path, other_path: &Path) -> bool {
+        let area = |path: &Path| {
+            image::image_dimensions(path)
+                .ok()
+                .map(|(width, height)| width as u64 * height as u64)
+        };
+
+        match (area(cover_path), area(other_path)) {
+            (Some(area), Some(other_area)) => area >= other_area,
+            (Some(_), None) => true,
+            (None, _) => false,
+        }
+    }
+
+    /// Encodes an extracted cover and writes it to `path` atomically, so
+    /// nobody reads a partial cover.
+    fn save_cover(
+        path: &Path,
+        data: &[u8],
+        width: u32,
+        height: u32,
+        format: GeneratedCoverFormat,
+    ) -> Result<()> {
+        let mut encoded = vec![];
+        match format {
+            GeneratedCoverFormat::Jpg => {
+                JpegEncoder::new(&mut encoded).write_image(data, width, height, ColorType::Rgba8)
+            }
+            GeneratedCoverFormat::Png => {
+                PngEncoder::new(&mut encoded).write_image(data, width, height, ColorType::Rgba8)
+            }
+            GeneratedCoverFormat::Webp => {
+                encoded = webp::Encoder::from_rgba(data, width, height)
+                    .encode(WEBP_QUALITY)
+                    .to_vec();
+                Ok(())
+            }
+        }
+        .chain_err(path_error!(IndexingError, path, "Encoding cover image"))?;
+
+        write_atomic(path, &encoded).chain_err(path_error!(
+            IndexingError,
+            path,
+            "Writing cover image to file"
+        ))
+    }
+
+    /// Gets where to write the cover extracted from a song: into a directory
+    /// named after the album's unique name in `cover_dir` if given, so songs of
+    /// different albums with the same file name don't share a cover, or else
+    /// next to the song.
+    fn make_cover_path(
+        song_path: &Path,
+        cover_dir: Option<&Path>,
+        album_name: &str,
+        format: GeneratedCoverFormat,
+    ) -> Result<PathBuf> {
+        let filename = format!(
+            "{}{}{}",
+            song_path
+                .file_name()
+                .expect(
+                    "BUG: Encountered song with no file
+                            name"
+                )
+                .to_string_lossy(),
+            GENERATED_COVER_MARKER,
+            format.extension()
+        );
+        trace!("Writing cover to: {}", &filename);
+        if let Some(cover_dir) = cover_dir {
+            let album_dir = cover_dir.join(album_name);
+            fs::create_dir_all(&album_dir).chain_err(path_error!(
+                IndexingError,
+                album_dir,
+                "creating cover cache dir"
+            ))?;
+            return Ok(album_dir.join(filename));
+        }
+
+        Ok(song_path
+            .parent()
+            .chain_err(path_error!(
+                IndexingError,
+                song_path,
+                "Getting song parent directory
+                            for cover generation"
+            ))?
+            .join(filename))
+    }
+
+    fn fit_frame(frame: &frame::Video) -> Cow<[u8]> {
+        let width = frame.width() as usize;
+        let height = frame.height() as usize;
+        let extra = frame.data(0).len() - width * height * 4;
+        if extra == 0 {
+            Cow::Borrowed(frame.data(0))
+        } else {
+            let offset = extra / height / 4;
+            warn!(
+                "Encountered cover image with {} pixels of garbage data",
+                offset
+            );
+            let data = frame.data(0);
+            let mut new_data = vec![];
+            new_data.reserve(width * height * 4);
+            for y in 0..(frame.height() as usize) {
+                new_data.extend_from_slice(
+                    &data[(y * (width + offset) * 4)..(y * (width + offset) * 4 + width * 4)],
+                )
+            }
+            Cow::Owned(new_data)
+        }
+    }
+}
+
+impl FileUrls {
+    fn new(config: &Config, files_url: &str) -> FileUrls {
+        FileUrls {
+            scheme: config.url_scheme,
+            base_dir: config.base_dir.clone(),
+            cover_cache_dir: config.cover_cache_dir.clone(),
+            files_url: files_url.to_string(),
+            ids: Default::default(),
+        }
+    }
+
+    fn url(&mut self, path: &Path) -> Result<String> {
+        match self.scheme {
+            UrlScheme::Path => match &self.cover_cache_dir {
+                Some(dir) if path.starts_with(dir) => find_url(path, dir, GENERATED_COVERS_URL),
+                _ => find_url(path, &self.base_dir, &self.files_url),
+            },
+            UrlScheme::Id => {
+                let mut id = hash_file(path).chain_err(path_error!(
+                    IndexingError,
+                    path,
+                    "hashing file id"
+                ))?;
+                // half a sha256 is still plenty to avoid collisions
+                id.truncate(32);
+
+                let url = format!("{}/{}", self.files_url, id);
+                self.ids.insert(id, path.to_path_buf());
+                Ok(url)
+            }
+        }
+    }
+}
+
+/// Album names differing only in casing, like "Wish" and "WISH", name the same
+/// album.
+fn same_album_name(a: &str, b: &str) -> bool {
+    a.to_lowercase() == b.to_lowercase()
+}
+
+/// Turns a name into a URL-safe unique name. Non-ASCII characters are
+/// transliterated first, so "Café del Mar" becomes `cafe-del-mar` and names in
+/// other scripts don't collapse into dashes.
+fn sanitize(s: &str) -> String {
+    deunicode_with_tofu(s, "-")
+        .replace(
+            |c: char| !((c >= 'A' && c <= 'Z') || (c >= 'a' && c <= 'z') || (c >= '0' && c <= '9')),
+            "-",
+        )
+        .to_ascii_lowercase()
+}
+
+/// Trims a name and collapses runs of whitespace inside it into single spaces.
+fn collapse_whitespace(name: &str) -> String {
+    name.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+/// Gets the name an artist is grouped by, which leaves out a leading article
+/// from `articles`. The first name seen of an artist is the one displayed.
+fn artist_key(name: &str, articles: &[String]) -> String {
+    let mut words = name.splitn(2, ' ');
+    match (words.next(), words.next()) {
+        (Some(first), Some(rest)) if articles.contains(&first.to_lowercase()) => rest.to_string(),
+        _ => name.to_string(),
+    }
+}
+
+/// Folds a name for searching, ignoring case and accents, so "beyonce" finds
+/// "Beyoncé". Display names are left as they are.
+fn search_key(name: &str) -> String {
+    let mut key = String::with_capacity(name.len());
+    for c in name.chars() {
+        match deunicode_char(c) {
+            Some(folded) => key.push_str(folded),
+            None => key.push(c),
+        }
+    }
+    key.to_lowercase()
+}
+
+fn find_url(path: &Path, base: &Path, files_url: &str) -> Result<String> {
+    let stripped =
+        path.strip_prefix(base)
+            .chain_err(path_error!(IndexingError, path, "formatting url"))?;
+    Ok(format!(
+        "{}/{}",
+        files_url,
+        utf8_percent_encode(&stripped.to_slash_lossy(), &PATH_SET)
+    ))
+}
+
+pub fn apply_services(
+    config: &Config,
+) -> Scope<
+    impl ServiceFactory<
+        Config = (),
+        Request = ServiceRequest,
+        Response = ServiceResponse,
+        Error = actix_web::Error,
+        InitError = (),
+    >,
+> {
+    let mut scope = web::scope("/index")
+        .data(config.clone())
+        .service(get_index_progress)
+        .service(get_all)
+        .service(get_incomplete)
+        .service(get_albums)
+        .service(get_songs)
+        .service(get_artists)
+        .service(get_search)
+        .service(get_stats)
+        .service(get_duplicates)
+        .service(get_random_songs)
+        .service(get_album)
+        .service(get_album_cover)
+        .service(get_album_covers_zip)
+        .service(get_album_playlist)
+        .service(get_album_rating)
+        .service(put_album_rating)
+        .service(get_artist)
+        .service(get_song)
+        .service(get_song_hls)
+        .service(get_song_lyrics)
+        .service(get_song_embedded_cover)
+        .service(get_song_rating)
+        .service(put_song_rating)
+        .service(get_song_qualities)
+        .service(get_song_transcode)
+        .service(get_next_song)
+        .service(get_previous_song);
+
+    if config.admin_token.is_some() {
+        scope = scope.service(post_reindex).service(post_album_reindex);
+    }
+
+    if config.expose_file_paths {
+        warn!("Song json includes file paths. These expose server file paths.");
+    }
+
+    if config.debug {
+        warn!("Debug endpoints are enabled. These expose server file paths.");
+        scope = scope.service(get_debug_dump);
+    }
+
+    scope.wrap(IndexCaching)
+}
+
+/// Streams indexing progress as server-sent events, sending a `progress` event
+/// whenever it changes and a `done` event once no index is running.
+#[get("/progress")]
+async fn get_index_progress(index: web::Data<IndexHandle>) -> HttpResponse {
+    let progress = index.progress.clone();
+    let events = stream::unfold(Some(None), move |last| {
+        let progress = progress.clone();
+        async move {
+            // `None` once the done event was sent
+            let last: Option<IndexProgressJson> = last?;
+            loop {
+                let current = progress.to_json();
+                let event = if !current.indexing {
+                    "done"
+                } else if last.as_ref() != Some(&current) {
+                    "progress"
+                } else {
+                    tokio::time::delay_for(PROGRESS_INTERVAL).await;
+                    continue;
+                };
+
+                let data = format!(
+                    "event: {}\ndata: {}\n\n",
+                    event,
+                    serde_json::to_string(&current).unwrap()
+                );
+                let next = Some(current).filter(|current| current.indexing);
+                return Some((Ok::<_, actix_web::Error>(Bytes::from(data)), next.map(Some)));
+            }
+        }
+    });
+
+    HttpResponseBuilder::new(StatusCode::OK)
+        .content_type("text/event-stream")
+        .header(header::CACHE_CONTROL, "no-store")
+        .encoding(ContentEncoding::Identity)
+        .streaming(Box::pin(events))
+}
+
+#[get("/all")]
+async fn get_all(index: web::Data<IndexHandle>, config: web::Data<Config>) -> Result<HttpResponse> {
+    let index = index.get().await;
+    let song_count = index.song_count().await;
+    if song_count > config.index_all_max_songs {
+        bail!(ErrorKind::IndexTooLarge(
+            song_count,
+            config.index_all_max_songs
+        ));
+    }
+
+    let mut artists = vec![];
+    for artist in index.artist_list.iter() {
+        let artist = artist.read().await;
+        artists.push(ArtistJson::from_artist(&artist).await);
+    }
+
+    let mut albums = vec![];
+    let mut songs = Vec::with_capacity(song_count);
+    for album in index.album_list.iter() {
+        let album = album.read().await;
+        albums.push(AlbumJson::from_album(&album, &index.ratings).await);
+
+        for song in album.songs.iter().flatten() {
+            songs.push(SongJson::from_song(
+                &*song.read().await,
+                &index.ratings,
+                &index.config,
+            ));
+        }
+    }
+
+    Ok(HttpResponseBuilder::new(StatusCode::OK).json(w_ok(AllJson {
+        artists,
+        albums,
+        songs,
+    })))
+}
+
+#[derive(Deserialize)]
+struct IncompleteQuery {
+    /// Comma-separated tags to filter by. Defaults to any tag.
+    missing: Option<String>,
+}
+
+#[get("/incomplete")]
+async fn get_incomplete(
+    index: web::Data<IndexHandle>,
+    query: web::Query<IncompleteQuery>,
+) -> Result<HttpResponse> {
+    let index = index.get().await;
+    let filter = match &query.missing {
+        Some(missing) => MissingTags::parse(missing)?,
+        None => MissingTags(!0),
+    };
+
+    let mut songs = vec![];
+    for album in index.album_list.iter() {
+        let album = album.read().await;
+        for song in album.songs.iter().flatten() {
+            let song = song.read().await;
+            if song.missing.intersects(filter) {
+                songs.push(IncompleteSongJson::from_song(&song));
+            }
+        }
+    }
+
+    Ok(HttpResponseBuilder::new(StatusCode::OK).json(w_ok(songs)))
+}
+
+/// Lists every song in the library, ordered by album unique name and then
+/// track. Only the songs of the requested page are converted.
+#[get("/songs")]
+async fn get_songs(index: web::Data<IndexHandle>, query: web::Query<PageQuery>) -> HttpResponse {
+    let index = index.get().await;
+    let (offset, limit) = query.bounds();
+
+    let mut total = 0;
+    let mut songs = vec![];
+    for album in index.album_list.iter() {
+        let album = album.read().await;
+        for song in album.songs.iter().flatten() {
+            if total >= offset && songs.len() < limit {
+                songs.push(SongJson::from_song(
+                    &*song.read().await,
+                    &index.ratings,
+                    &index.config,
+                ));
+            }
+            total += 1;
+        }
+    }
+
+    HttpResponseBuilder::new(StatusCode::OK).json(w_ok(PageJson {
+        total,
+        offset,
+        limit,
+        items: songs,
+    }))
+}
+
+#[get("/albums")]
+async fn get_albums(
+    index: web::Data<IndexHandle>,
+    config: web::Data<Config>,
+    query: web::Query<PageQuery>,
+    sort: web::Query<AlbumSortQuery>,
+) -> HttpResponse {
+    let index = index.get().await;
+    // album_list is shared, so sorting happens on a list of references
+    let mut listed = vec![];
+    for album in index.album_list.iter() {
+        let album_ref = album.read().await;
+        if album_ref.songs_by_name.len() >= config.min_album_songs {
+            listed.push((sort.sort.map(|sort| sort.key(&album_ref)), album));
+        }
+    }
+    // the sort is stable, so albums with the same key stay in listing order
+    listed.sort_by(|(a, _), (b, _)| a.cmp(b));
+
+    let (offset, limit) = query.bounds();
+    let mut albums = vec![];
+    for (_, album) in listed.iter().skip(offset).take(limit) {
+        albums.push(AlbumJson::from_album(&album.read().await, &index.ratings).await);
+    }
+
+    HttpResponseBuilder::new(StatusCode::OK).json(w_ok(PageJson {
+        total: listed.len(),
+        offset,
+        limit,
+        items: albums,
+    }))
+}
+
+#[get("/artists")]
+async fn get_artists(index: web::Data<IndexHandle>, query: web::Query<PageQuery>) -> HttpResponse {
+    let index = index.get().await;
+    let mut listed = vec![];
+    for artist in index.artist_list.iter() {
+        // artists only featured on compilations don't own any albums
+        if !artist.read().await.albums.is_empty() {
+            listed.push(artist);
+        }
+    }
+
+    let (offset, limit) = query.bounds();
+    let mut artists = vec![];
+    for artist in listed.iter().skip(offset).take(limit) {
+        artists.push(ArtistJson::from_artist(&artist.read().await).await);
+    }
+
+    HttpResponseBuilder::new(StatusCode::OK).json(w_ok(PageJson {
+        total: listed.len(),
+        offset,
+        limit,
+        items: artists,
+    }))
+}
+
+#[derive(Deserialize)]
+struct PageQuery {
+    #[serde(default)]
+    offset: usize,
+    limit: Option<usize>,
+}
+
+impl PageQuery {
+    /// Gets the offset and limit of the page, capping the limit. Offsets past
+    /// the end give an empty page.
+    fn bounds(&self) -> (usize, usize) {
+        let limit = self.limit.unwrap_or(DEFAULT_PAGE_LIMIT).min(MAX_PAGE_LIMIT);
+        (self.offset, limit)
+    }
+}
+
+#[derive(Deserialize)]
+struct AlbumSortQuery {
+    /// Defaults to listing order, which is by unique name.
+    sort: Option<AlbumSort>,
+}
+
+#[derive(Debug, Copy, Clone, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum AlbumSort {
+    Name,
+    Year,
+    Artist,
+}
+
+impl AlbumSort {
+    /// Gets the key albums are sorted by. Albums missing what they are sorted
+    /// by come last.
+    fn key(self, album: &Album) -> (bool, u32, String) {
+        match self {
+            AlbumSort::Name => (false, 0, album.name.to_lowercase()),
+            AlbumSort::Year => (album.year.is_none(), album.year.unwrap_or(0), String::new()),
+            AlbumSort::Artist => match album.artists.first() {
+                Some(artist) => (false, 0, artist.name.to_lowercase()),
+                None => (true, 0, String::new()),
+            },
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct SearchQuery {
+    q: String,
+}
+
+/// Searches the names of artists, albums and songs, ignoring case. Names
+/// starting with the query come first, followed by names containing it, each in
+/// listing order.
+#[get("/search")]
+async fn get_search(
+    index: web::Data<IndexHandle>,
+    query: web::Query<SearchQuery>,
+) -> Result<HttpResponse> {
+    let index = index.get().await;
+    let q = search_key(query.q.trim());
+    if q.is_empty() {
+        bail!(ErrorKind::InvalidQuery("q must not be empty".to_string()));
+    }
+
+    let mut artists = vec![];
+    for artist in index.artist_list.iter() {
+        if let Some(rank) = search_rank(&artist.read().await.search_key, &q) {
+            artists.push((rank, artist.clone()));
+        }
+    }
+
+    let mut albums = vec![];
+    let mut songs = vec![];
+    for album in index.album_list.iter() {
+        let album_ref = album.read().await;
+        if let Some(rank) = search_rank(&album_ref.search_key, &q) {
+            albums.push((rank, album.clone()));
+        }
+
+        for song in album_ref.songs.iter().flatten() {
+            if let Some(rank) = search_rank(&song.read().await.search_key, &q) {
+                songs.push((rank, song.clone()));
+            }
+        }
+    }
+
+    // sorting is stable, so listing order is kept within each rank
+    artists.sort_by_key(|(rank, _)| *rank);
+    albums.sort_by_key(|(rank, _)| *rank);
+    songs.sort_by_key(|(rank, _)| *rank);
+
+    let mut json = SearchJson {
+        artists: vec![],
+        albums: vec![],
+        songs: vec![],
+    };
+    for (_, artist) in artists.into_iter().take(SEARCH_LIMIT) {
+        json.artists
+            .push(ArtistJson::from_artist(&artist.read().await).await);
+    }
+    for (_, album) in albums.into_iter().take(SEARCH_LIMIT) {
+        json.albums
+            .push(AlbumJson::from_album(&album.read().await, &index.ratings).await);
+    }
+    for (_, song) in songs.into_iter().take(SEARCH_LIMIT) {
+        json.songs.push(SongJson::from_song(
+            &*song.read().await,
+            &index.ratings,
+            &index.config,
+        ));
+    }
+
+    Ok(HttpResponseBuilder::new(StatusCode::OK).json(w_ok(json)))
+}
+
+/// Ranks how well a search key matches a folded search query, lower is better.
+fn search_rank(key: &str, query: &str) -> Option<u8> {
+    if key.starts_with(query) {
+        Some(0)
+    } else if key.contains(query) {
+        Some(1)
+    } else {
+        None
+    }
+}
+
+#[get("/album/{album_name}")]
+async fn get_album(
+    index: web::Data<IndexHandle>,
+    web::Path(album_name): web::Path<String>,
+) -> Result<HttpResponse> {
+    let index = index.get().await;
+    if let Some(album) = index.albums.get(&album_name) {
+        let album = album.read().await;
+
+        Ok(HttpResponseBuilder::new(StatusCode::OK)
+            .json(w_ok(AlbumJson::from_album(&album, &index.ratings).await)))
+    } else {
+        bail!(ErrorKind::NoSuchResource("album", album_name));
+    }
+}
+
+#[derive(Deserialize)]
+struct CoverQuery {
+    #[serde(default)]
+    size: CoverSize,
+}
+
+/// Gets the url of an album's cover scaled down to `size`.
+fn cover_size_url(album_name: &str, size: CoverSize) -> String {
+    format!(
+        "{}/album/{}/cover?size={}",
+        INDEX_URL,
+        album_name,
+        size.name()
+    )
+}
+
+/// Serves an album's cover, scaled down to `size` if given. `size` is either
+/// one of the named sizes or a maximum dimension in pixels.
+#[get("/album/{album_name}/cover")]
+async fn get_album_cover(
+    req: HttpRequest,
+    index: web::Data<IndexHandle>,
+    covers: web::Data<CoverCache>,
+    web::Path(album_name): web::Path<String>,
+    query: web::Query<CoverQuery>,
+) -> Result<impl Responder> {
+    let index = index.get().await;
+    let cover_path = match index.albums.get(&album_name) {
+        Some(album) => album.read().await.cover_path.clone(),
+        None => bail!(ErrorKind::NoSuchResource("album", album_name)),
+    }
+    .ok_or_else(|| ErrorKind::NoSuchResource("cover", album_name.clone()))?;
+
+    let accepts_webp = accepts_webp(&req);
+    let file = if accepts_webp || query.size != CoverSize::Original {
+        let format = if accepts_webp {
+            CoverFormat::WebP
+        } else {
+            CoverFormat::Jpeg
+        };
+        let path = covers
+            .get(&album_name, &cover_path, query.size, format)
+            .await?;
+        NamedFile::open(&path)
+            .chain_err(path_error!(IndexingError, path, "opening converted cover"))?
+            .set_content_type(format.content_type().parse().unwrap())
+    } else {
+        NamedFile::open(&cover_path)
+            .chain_err(|| ErrorKind::NoSuchResource("cover", album_name.clone()))?
+    };
+
+    Ok(file.with_header(header::VARY, "Accept"))
+}
+
+fn accepts_webp(req: &HttpRequest) -> bool {
+    req.headers()
+        .get(header::ACCEPT)
+        .and_then(|accept| accept.to_str().ok())
+        .map_or(false, |accept| accept.contains("image/webp"))
+}
+
+/// Bundles an album's cover and its scaled down versions into a zip, for tools
+/// syncing covers to other devices. The zip is streamed as it is written.
+#[get("/album/{album_name}/covers.zip")]
+async fn get_album_covers_zip(
+    index: web::Data<IndexHandle>,
+    covers: web::Data<CoverCache>,
+    web::Path(album_name): web::Path<String>,
+) -> Result<HttpResponse> {
+    let index = index.get().await;
+    let cover_path = match index.albums.get(&album_name) {
+        Some(album) => album.read().await.cover_path.clone(),
+        None => bail!(ErrorKind::NoSuchResource("album", album_name)),
+    }
+    .ok_or_else(|| ErrorKind::NoSuchResource("cover", album_name.clone()))?;
+
+    let extension = cover_path
+        .extension()
+        .map(|ext| ext.to_string_lossy().to_lowercase())
+        .unwrap_or_default();
+    let mut files = vec![(format!("cover.{}", extension), cover_path.clone())];
+    for &size in [CoverSize::Medium, CoverSize::Thumb].iter() {
+        let path = covers
+            .get(&album_name, &cover_path, size, CoverFormat::Jpeg)
+            .await?;
+        files.push((format!("cover-{}.jpg", size.name()), path));
+    }
+
+    let disposition = format!("attachment; filename=\"{}-covers.zip\"", album_name);
+    let (tx, rx) = mpsc::channel(ZIP_BUFFER_CHUNKS);
+    let mut error_tx = tx.clone();
+    zip::spawn(move || {
+        let mut zip = ZipWriter::new(BodyWriter::new(tx));
+        let res = files
+            .iter()
+            .try_for_each(|(name, path)| zip.add_file(name, path))
+            .and_then(|_| zip.finish().map(|_| ()));
+
+        let res = res.chain_err(path_error!(IndexingError, cover_path, "writing covers zip"));
+        if let Err(e) = res {
+            // the error ends the response, leaving the client with a broken zip
+            let _ = executor::block_on(error_tx.send(Err(e)));
+        }
+    });
+
+    Ok(HttpResponseBuilder::new(StatusCode::OK)
+        .content_type("application/zip")
+        .header(header::CONTENT_DISPOSITION, disposition)
+        .streaming(rx))
+}
+
+#[get("/album/{album_name}/rating")]
+async fn get_album_rating(
+    index: web::Data<IndexHandle>,
+    web::Path(album_name): web::Path<String>,
+) -> Result<HttpResponse> {
+    let index = index.get().await;
+    if !index.albums.contains_key(&album_name) {
+        bail!(ErrorKind::NoSuchResource("album", album_name));
+    }
+    let rating = index.ratings.album(&album_name);
+
+    Ok(HttpResponseBuilder::new(StatusCode::OK).json(w_ok(RatingJson::from_rating(&rating))))
+}
+
+/// Rates an album or marks it as a favorite, taking the same json as song
+/// ratings.
+#[put("/album/{album_name}/rating")]
+async fn put_album_rating(
+    index: web::Data<IndexHandle>,
+    web::Path(album_name): web::Path<String>,
+    update: web::Json<RatingUpdate>,
+) -> Result<HttpResponse> {
+    update.validate()?;
+    let index = index.get().await;
+    if !index.albums.contains_key(&album_name) {
+        bail!(ErrorKind::NoSuchResource("album", album_name));
+    }
+    let rating = index
+        .ratings
+        .update_album(&album_name, |rating| update.apply(rating))
+        .await?;
+
+    Ok(HttpResponseBuilder::new(StatusCode::OK).json(w_ok(RatingJson::from_rating(&rating))))
+}
+
+/// Gets an extended M3U playlist of an album's songs in track order, so the
+/// album can be opened directly in players like VLC.
+#[get("/album/{album_name}/playlist.m3u8")]
+async fn get_album_playlist(
+    req: HttpRequest,
+    index: web::Data<IndexHandle>,
+    web::Path(album_name): web::Path<String>,
+) -> Result<HttpResponse> {
+    // players open the playlist on their own, so song urls have to be absolute
+    let origin = {
+        let info = req.connection_info();
+        format!("{}://{}", info.scheme(), info.host())
+    };
+
+    let index = index.get().await;
+    let album = index
+        .albums
+        .get(&album_name)
+        .ok_or_else(|| ErrorKind::NoSuchResource("album", album_name.clone()))?;
+    let album = album.read().await;
+
+    let mut playlist = "#EXTM3U\n".to_string();
+    for song in album.songs.iter().flatten() {
+        let song = song.read().await;
+        let artists: Vec<_> = song.artists.iter().map(|a| a.name.as_str()).collect();
+        // -1 is the conventional length of songs with an unknown length
+        let duration = song.duration.map_or(-1, |duration| duration.round() as i64);
+
+        playlist.push_str(&format!(
+            "#EXTINF:{},{} - {}\n{}{}\n",
+            duration,
+            artists.join(", "),
+            song.name,
+            origin,
+            song.url
+        ));
+    }
+
+    let disposition = format!("inline; filename=\"{}.m3u8\"", album_name);
+    Ok(HttpResponseBuilder::new(StatusCode::OK)
+        .content_type("audio/x-mpegurl; charset=utf-8")
+        .header(header::CONTENT_DISPOSITION, disposition)
+        .body(playlist))
+}
+
+#[get("/artist/{artist_name}")]
+async fn get_artist(
+    index: web::Data<IndexHandle>,
+    web::Path(artist_name): web::Path<String>,
+) -> Result<HttpResponse> {
+    let index = index.get().await;
+    if let Some(artist) = index.artists.get(&artist_name) {
+        let artist = artist.read().await;
+
+        Ok(HttpResponseBuilder::new(StatusCode::OK)
+            .json(w_ok(ArtistJson::from_artist(&artist).await)))
+    } else {
+        bail!(ErrorKind::NoSuchResource("artist", artist_name));
+    }
+}
+
+#[get("/album/{album_name}/{song_name}")]
+async fn get_song(
+    index: web::Data<IndexHandle>,
+    web::Path((album_name, song_name)): web::Path<(String, String)>,
+) -> Result<HttpResponse> {
+    let index = index.get().await;
+    if let Some(album) = index.albums.get(&album_name) {
+        let album = album.read().await;
+        if let Some(song) = album.songs_by_name.get(&song_name) {
+            let song = song.read().await;
+
+            Ok(
+                HttpResponseBuilder::new(StatusCode::OK).json(w_ok(SongJson::from_song(
+                    &song,
+                    &index.ratings,
+                    &index.config,
+                ))),
+            )
+        } else {
+            bail!(ErrorKind::NoSuchResource("song", song_name))
+        }
+    } else {
+        bail!(ErrorKind::NoSuchResource("album", album_name));
+    }
+}
+
+#[get("/album/{album_name}/{song_name}/hls/{file_name}")]
+async fn get_song_hls(
+    index: web::Data<IndexHandle>,
+    transcodes: web::Data<TranscodeCache>,
+    web::Path((album_name, song_name, file_name)): web::Path<(String, String, String)>,
+) -> Result<NamedFile> {
+    let index = index.get().await;
+    let song_path = index
+        .find_song(&album_name, &song_name)
+        .await?
+        .read()
+        .await
+        .path
+        .clone();
+    let hls_dir = transcodes.hls(&song_path).await?;
+
+    transcodes.hls_file(&hls_dir, &file_name)
+}
+
+/// Gets a song's lyrics as plain text.
+#[get("/album/{album_name}/{song_name}/lyrics")]
+async fn get_song_lyrics(
+    index: web::Data<IndexHandle>,
+    web::Path((album_name, song_name)): web::Path<(String, String)>,
+) -> Result<HttpResponse> {
+    let index = index.get().await;
+    let song_path = index
+        .find_song(&album_name, &song_name)
+        .await?
+        .read()
+        .await
+        .path
+        .clone();
+
+    match Song::read_lyrics(&song_path, &index.config).await? {
+        Some(lyrics) => Ok(HttpResponseBuilder::new(StatusCode::OK)
+            .content_type("text/plain; charset=utf-8")
+            .body(lyrics)),
+        None => bail!(ErrorKind::NoSuchResource("lyrics", song_name)),
+    }
+}
+
+/// Serves the cover embedded in a song, decoded at request time so it works
+/// without generating cover files in the music directory.
+#[get("/album/{album_name}/{song_name}/embedded-cover")]
+async fn get_song_embedded_cover(
+    req: HttpRequest,
+    index: web::Data<IndexHandle>,
+    covers: web::Data<CoverCache>,
+    web::Path((album_name, song_name)): web::Path<(String, String)>,
+) -> Result<HttpResponse> {
+    let index = index.get().await;
+    let song_path = index
+        .find_song(&album_name, &song_name)
+        .await?
+        .read()
+        .await
+        .path
+        .clone();
+
+    let format = if accepts_webp(&req) {
+        CoverFormat::WebP
+    } else {
+        CoverFormat::Jpeg
+    };
+    let modified = song_path
+        .metadata()
+        .and_then(|metadata| metadata.modified())
+        .chain_err(path_error!(
+            IndexingError,
+            song_path,
+            "reading song modification time"
+        ))?;
+
+    let data = match covers.get_embedded(&song_path, modified, format) {
+        Some(data) => data,
+        None => {
+            let path_moved = song_path.clone();
+            let data = ffmpeg::spawn_blocking(move || -> Result<_> {
+                let frame = match Index::read_frame(&path_moved)? {
+                    Some(frame) => frame,
+                    None => return Ok(None),
+                };
+                let pixels = Index::fit_frame(&frame).into_owned();
+                let image = RgbaImage::from_raw(frame.width(), frame.height(), pixels).ok_or_else(
+                    path_error!(IndexingError, path_moved, "reading embedded cover pixels"),
+                )?;
+                let data = covers::encode(&DynamicImage::ImageRgba8(image), format).chain_err(
+                    path_error!(IndexingError, path_moved, "encoding embedded cover"),
+                )?;
+
+                Ok(Some(Bytes::from(data)))
+            })
+            .await
+            .chain_err(path_error!(
+                IndexingError,
                 song_path,
-                "Setting parameters for song file cover decoder"
-            ))?;
-        let stream_index = stream.index();
+                "reading embedded cover off-thread"
+            ))??
+            .ok_or_else(|| ErrorKind::NoSuchResource("embedded cover", song_name.clone()))?;
+
+            covers.insert_embedded(&song_path, modified, format, data.clone());
+            data
+        }
+    };
+
+    Ok(HttpResponseBuilder::new(StatusCode::OK)
+        .content_type(format.content_type())
+        .header(header::VARY, "Accept")
+        .body(data))
+}
+
+#[derive(Deserialize)]
+struct RatingUpdate {
+    rating: Option<u8>,
+    favorite: Option<bool>,
+}
+
+impl RatingUpdate {
+    fn validate(&self) -> Result<()> {
+        match self.rating {
+            Some(rating) if rating > MAX_RATING => bail!(ErrorKind::InvalidQuery(format!(
+                "rating must be at most {}",
+                MAX_RATING
+            ))),
+            _ => Ok(()),
+        }
+    }
+
+    /// Changes only the given fields of a rating.
+    fn apply(&self, rating: &mut Rating) {
+        if let Some(stars) = self.rating {
+            rating.rating = Some(stars);
+        }
+        if let Some(favorite) = self.favorite {
+            rating.favorite = favorite;
+        }
+    }
+}
 
-        // create the converter to convert the cover to RGBA color space
-        let mut converter_2 = software::converter(
-            (decoder.width(), decoder.height()),
-            decoder.format(),
-            format::Pixel::RGBA,
-        )
-        .chain_err(indexing_error!(
-            song_path,
-            "Creating cover image converter for song file"
-        ))?;
+#[get("/album/{album_name}/{song_name}/rating")]
+async fn get_song_rating(
+    index: web::Data<IndexHandle>,
+    web::Path((album_name, song_name)): web::Path<(String, String)>,
+) -> Result<HttpResponse> {
+    let index = index.get().await;
+    index.find_song(&album_name, &song_name).await?;
+    let rating = index.ratings.song(&album_name, &song_name);
 
-        let mut decoded = frame::Video::empty();
-        let mut converted = frame::Video::empty();
+    Ok(HttpResponseBuilder::new(StatusCode::OK).json(w_ok(RatingJson::from_rating(&rating))))
+}
 
-        // find the cover packet
-        for (stream, mut packet) in input.packets() {
-            if stream.index() == stream_index {
-                packet.rescale_ts(stream.time_base(), decoder.time_base());
+/// Rates a song or marks it as a favorite, taking
+/// `{"rating": 0..5, "favorite": bool}` where both fields are optional.
+#[put("/album/{album_name}/{song_name}/rating")]
+async fn put_song_rating(
+    index: web::Data<IndexHandle>,
+    web::Path((album_name, song_name)): web::Path<(String, String)>,
+    update: web::Json<RatingUpdate>,
+) -> Result<HttpResponse> {
+    update.validate()?;
+    let index = index.get().await;
+    index.find_song(&album_name, &song_name).await?;
+    let rating = index
+        .ratings
+        .update_song(&album_name, &song_name, |rating| update.apply(rating))
+        .await?;
 
-                // decode the cover
-                if let Ok(true) = decoder.decode(&packet, &mut decoded) {
-                    let timestamp = decoded.timestamp();
-                    decoded.set_pts(timestamp);
+    Ok(HttpResponseBuilder::new(StatusCode::OK).json(w_ok(RatingJson::from_rating(&rating))))
+}
 
-                    trace!("Frame dimensions: {}x{}", decoded.width(), decoded.height());
-                    trace!("Frame colorspace: {:?}", decoded.color_space());
-                    trace!("Frame pixel format: {:?}", decoded.format());
+/// Gets the url of a song's HLS playlist.
+fn hls_playlist_url(album_name: &str, song_name: &str) -> String {
+    format!(
+        "{}/album/{}/{}/hls/{}",
+        INDEX_URL, album_name, song_name, HLS_PLAYLIST
+    )
+}
 
-                    // convert the cover to RGBA color space
-                    converter_2
-                        .run(&decoded, &mut converted)
-                        .chain_err(indexing_error!(song_path, "Converting song cover"))?;
+/// Lists the renditions of a song: the original file plus any transcodes that
+/// are already cached. Transcodes are never started by this.
+#[get("/album/{album_name}/{song_name}/qualities")]
+async fn get_song_qualities(
+    index: web::Data<IndexHandle>,
+    transcodes: web::Data<TranscodeCache>,
+    web::Path((album_name, song_name)): web::Path<(String, String)>,
+) -> Result<HttpResponse> {
+    let index = index.get().await;
+    let song = index.find_song(&album_name, &song_name).await?;
+    let song = song.read().await;
 
-                    converted.set_pts(decoded.pts());
+    let mut qualities = vec![QualityJson {
+        format: song
+            .path
+            .extension()
+            .map(|ext| ext.to_string_lossy().to_lowercase())
+            .unwrap_or_default(),
+        bit_rate: song.bit_rate,
+        url: song.url.clone(),
+        original: true,
+    }];
 
-                    return Ok(Some(converted));
-                }
-            }
+    if transcodes.has_hls(&song.path) {
+        qualities.push(QualityJson {
+            format: "hls".to_string(),
+            bit_rate: Some(transcodes.hls_bit_rate()),
+            url: hls_playlist_url(&album_name, &song_name),
+            original: false,
+        });
+    }
+
+    Ok(HttpResponseBuilder::new(StatusCode::OK).json(w_ok(qualities)))
+}
+
+#[derive(Deserialize)]
+struct TranscodeQuery {
+    format: String,
+    /// Bit rate in kbit/s. Defaults to one suited to the format.
+    bitrate: Option<usize>,
+}
+
+/// Streams a song transcoded to mp3 or opus while it is being transcoded, for
+/// clients that can't stream the original file.
+#[get("/transcode/{album_name}/{song_name}")]
+async fn get_song_transcode(
+    index: web::Data<IndexHandle>,
+    transcodes: web::Data<TranscodeCache>,
+    web::Path((album_name, song_name)): web::Path<(String, String)>,
+    query: web::Query<TranscodeQuery>,
+) -> Result<HttpResponse> {
+    let format = StreamFormat::parse(&query.format)
+        .ok_or_else(|| ErrorKind::InvalidQuery(format!("unsupported format: {}", query.format)))?;
+    let bit_rate = query.bitrate.unwrap_or_else(|| format.default_bit_rate());
+    if !(MIN_TRANSCODE_BIT_RATE..=MAX_TRANSCODE_BIT_RATE).contains(&bit_rate) {
+        bail!(ErrorKind::InvalidQuery(format!(
+            "bitrate must be between {} and {}",
+            MIN_TRANSCODE_BIT_RATE, MAX_TRANSCODE_BIT_RATE
+        )));
+    }
+
+    let index = index.get().await;
+    let song_path = index
+        .find_song(&album_name, &song_name)
+        .await?
+        .read()
+        .await
+        .path
+        .clone();
+    let body = transcodes.stream(&song_path, format, bit_rate * 1000)?;
+
+    Ok(HttpResponseBuilder::new(StatusCode::OK)
+        .content_type(format.content_type())
+        .encoding(ContentEncoding::Identity)
+        .streaming(body))
+}
+
+#[derive(Deserialize)]
+struct NeighborQuery {
+    #[serde(default)]
+    wrap: bool,
+}
+
+#[get("/album/{album_name}/{song_name}/next")]
+async fn get_next_song(
+    index: web::Data<IndexHandle>,
+    web::Path((album_name, song_name)): web::Path<(String, String)>,
+    query: web::Query<NeighborQuery>,
+) -> Result<HttpResponse> {
+    let index = index.get().await;
+    get_neighbor(&index, &album_name, &song_name, true, query.wrap).await
+}
+
+#[get("/album/{album_name}/{song_name}/prev")]
+async fn get_previous_song(
+    index: web::Data<IndexHandle>,
+    web::Path((album_name, song_name)): web::Path<(String, String)>,
+    query: web::Query<NeighborQuery>,
+) -> Result<HttpResponse> {
+    let index = index.get().await;
+    get_neighbor(&index, &album_name, &song_name, false, query.wrap).await
+}
+
+async fn get_neighbor(
+    index: &Index,
+    album_name: &str,
+    song_name: &str,
+    forward: bool,
+    wrap: bool,
+) -> Result<HttpResponse> {
+    if let Some(album) = index.albums.get(album_name) {
+        let album = album.read().await;
+        if let Some(song) = album.neighbor(song_name, forward, wrap) {
+            let song = song.read().await;
+
+            Ok(
+                HttpResponseBuilder::new(StatusCode::OK).json(w_ok(SongJson::from_song(
+                    &song,
+                    &index.ratings,
+                    &index.config,
+                ))),
+            )
+        } else if album.songs_by_name.contains_key(song_name) {
+            bail!(ErrorKind::NoSuchResource("neighbor", song_name.to_string()))
+        } else {
+            bail!(ErrorKind::NoSuchResource("song", song_name.to_string()))
         }
+    } else {
+        bail!(ErrorKind::NoSuchResource("album", album_name.to_string()));
+    }
+}
 
-        Ok(None)
+#[derive(Deserialize)]
+struct RandomQuery {
+    count: Option<usize>,
+    /// Only picks songs from this album.
+    album_unique_name: Option<String>,
+}
+
+/// Picks distinct songs at random, each song being equally likely, for shuffle
+/// playback.
+#[get("/random/songs")]
+async fn get_random_songs(
+    index: web::Data<IndexHandle>,
+    query: web::Query<RandomQuery>,
+) -> Result<HttpResponse> {
+    let index = index.get().await;
+    let albums = match &query.album_unique_name {
+        Some(album_name) => match index.albums.get(album_name) {
+            Some(album) => vec![album.clone()],
+            None => bail!(ErrorKind::NoSuchResource("album", album_name.clone())),
+        },
+        None => index.album_list.clone(),
+    };
+
+    let mut candidates = vec![];
+    for album in albums.iter() {
+        candidates.extend(album.read().await.songs.iter().flatten().cloned());
     }
 
-    fn make_cover_path(song_path: &Path) -> Result<PathBuf> {
-        let filename = format!(
-            "{}-ms1-cover-small-generated.jpg",
-            song_path
-                .file_name()
-                .expect(
-                    "BUG: Encountered song with no file
-                            name"
-                )
-                .to_string_lossy()
-        );
-        trace!("Writing cover to: {}", &filename);
-        Ok(song_path
-            .parent()
-            .chain_err(indexing_error!(
-                song_path,
-                "Getting song parent directory
-                            for cover generation"
-            ))?
-            .join(filename))
+    let count = query
+        .count
+        .unwrap_or(DEFAULT_RANDOM_COUNT)
+        .min(MAX_RANDOM_COUNT);
+    let picked: Vec<_> = candidates
+        .choose_multiple(&mut rand::thread_rng(), count)
+        .cloned()
+        .collect();
+
+    let mut songs = vec![];
+    for song in picked {
+        songs.push(SongJson::from_song(
+            &*song.read().await,
+            &index.ratings,
+            &index.config,
+        ));
     }
 
-    fn fit_frame(frame: &frame::Video) -> Cow<[u8]> {
-        let width = frame.width() as usize;
-        let height = frame.height() as usize;
-        let extra = frame.data(0).len() - width * height * 4;
-        if extra == 0 {
-            Cow::Borrowed(frame.data(0))
+    // every response is different, so none of them can be reused
+    Ok(HttpResponseBuilder::new(StatusCode::OK)
+        .header(header::CACHE_CONTROL, "no-store")
+        .json(w_ok(songs)))
+}
+
+/// Summarizes the library. Until the first index is built this describes an
+/// empty library, so readiness is checked with `/readyz` instead.
+#[get("/stats")]
+async fn get_stats(index: web::Data<IndexHandle>) -> HttpResponse {
+    let index = index.get().await;
+    let mut stats = StatsJson {
+        artists: index.artists.len(),
+        albums: index.albums.len(),
+        songs: 0,
+        file_covers: 0,
+        generated_covers: 0,
+        missing_covers: 0,
+        duration: 0.0,
+        build_time: index.build_time.as_secs_f64(),
+    };
+
+    for album in index.album_list.iter() {
+        let album = album.read().await;
+        if album.cover_url.is_none() {
+            stats.missing_covers += 1;
+        } else if album.cover_generated {
+            stats.generated_covers += 1;
         } else {
-            let offset = extra / height / 4;
-            warn!(
-                "Encountered cover image with {} pixels of garbage data",
-                offset
-            );
-            let data = frame.data(0);
-            let mut new_data = vec![];
-            new_data.reserve(width * height * 4);
-            for y in 0..(frame.height() as usize) {
-                new_data.extend_from_slice(
-                    &data[(y * (width + offset) * 4)..(y * (width + offset) * 4 + width * 4)],
-                )
-            }
-            Cow::Owned(new_data)
+            stats.file_covers += 1;
+        }
+
+        stats.songs += album.songs_by_name.len();
+        for song in album.songs_by_name.values() {
+            stats.duration += song.read().await.duration.unwrap_or(0.0);
         }
     }
+
+    HttpResponseBuilder::new(StatusCode::OK).json(w_ok(stats))
 }
 
-fn sanitize(s: &str) -> String {
-    s.replace(
-        |c: char| !((c >= 'A' && c <= 'Z') || (c >= 'a' && c <= 'z') || (c >= '0' && c <= '9')),
-        "-",
+/// Reindexes the library, only probing new and changed files. Requires the
+/// admin token.
+#[post("/reindex")]
+async fn post_reindex(
+    req: HttpRequest,
+    index: web::Data<IndexHandle>,
+    config: web::Data<Config>,
+) -> Result<HttpResponse> {
+    require_admin(&req, &config)?;
+
+    info!("Reindexing...");
+    let index = index.reindex().await?;
+
+    Ok(
+        HttpResponseBuilder::new(StatusCode::OK).json(w_ok(ReindexJson {
+            artists: index.artists.len(),
+            albums: index.albums.len(),
+        })),
     )
-    .to_ascii_lowercase()
 }
 
-fn find_url(path: &Path, base: &Path, files_url: &str) -> Result<String> {
-    let stripped = path
-        .strip_prefix(base)
-        .chain_err(indexing_error!(path, "formatting url"))?;
-    Ok(format!(
-        "{}/{}",
-        files_url,
-        utf8_percent_encode(&stripped.to_slash_lossy(), &PATH_SET)
-    ))
-}
+/// Reindexes one album, walking its directories again and probing its songs
+/// even if they look unchanged. Requires the admin token.
+#[post("/album/{album_name}/reindex")]
+async fn post_album_reindex(
+    req: HttpRequest,
+    index: web::Data<IndexHandle>,
+    config: web::Data<Config>,
+    web::Path(album_name): web::Path<String>,
+) -> Result<HttpResponse> {
+    require_admin(&req, &config)?;
 
-fn paths_eq(p1: &Option<PathBuf>, p2: &Option<&Path>) -> bool {
-    if p1.is_none() {
-        return p2.is_none();
-    }
-    if p2.is_none() {
-        return false;
-    }
+    info!("Reindexing album {}...", album_name);
+    let index = index.reindex_album(&album_name).await?;
 
-    p1.as_ref().unwrap() == p2.unwrap()
+    Ok(
+        HttpResponseBuilder::new(StatusCode::OK).json(w_ok(ReindexJson {
+            artists: index.artists.len(),
+            albums: index.albums.len(),
+        })),
+    )
 }
 
-pub fn apply_services() -> Scope {
-    web::scope("/index")
-        .service(get_albums)
-        .service(get_artists)
-        .service(get_album)
-        .service(get_artist)
-        .service(get_song)
+/// Lists the songs that claimed a track another song of their album already
+/// had, and which of the two is listed.
+#[get("/diagnostics/duplicates")]
+async fn get_duplicates(index: web::Data<IndexHandle>) -> HttpResponse {
+    let index = index.get().await;
+    HttpResponseBuilder::new(StatusCode::OK).json(w_ok(&index.duplicates))
 }
 
-#[get("/albums")]
-async fn get_albums(index: web::Data<Index>) -> HttpResponse {
+#[get("/debug/dump")]
+async fn get_debug_dump(index: web::Data<IndexHandle>) -> HttpResponse {
+    let index = index.get().await;
     let mut albums = vec![];
     for album in index.album_list.iter() {
         let album = album.read().await;
-        albums.push(AlbumJson::from_album(&album).await);
+        albums.push(AlbumDumpJson::from_album(&album).await);
     }
 
     HttpResponseBuilder::new(StatusCode::OK).json(w_ok(albums))
 }
 
-#[get("/artists")]
-async fn get_artists(index: web::Data<Index>) -> HttpResponse {
-    let mut artists = vec![];
-    for artist in index.artist_list.iter() {
-        let artist = artist.read().await;
-        artists.push(ArtistJson::from_artist(&artist).await);
+// the json types are described in src/openapi.json, which has to be kept in sync
+
+#[derive(Serialize)]
+struct SongJson {
+    name: String,
+    unique_name: String,
+    album: AlbumRef,
+    artists: Vec<ArtistRef>,
+    track: Option<u32>,
+    disc: Option<u32>,
+    year: Option<u32>,
+    genres: Vec<String>,
+    composer: Option<String>,
+    comment: Option<String>,
+    replay_gain: ReplayGain,
+    /// Length in seconds, if known.
+    duration: Option<f64>,
+    /// Bit rate in bit/s, if known.
+    bit_rate: Option<u64>,
+    /// Sample rate in Hz, if known.
+    sample_rate: Option<u32>,
+    channels: Option<u16>,
+    cover_url: Option<String>,
+    cover_thumb_url: Option<String>,
+    cover_medium_url: Option<String>,
+    url: String,
+    /// From 0 to 5 stars, if rated.
+    rating: Option<u8>,
+    favorite: bool,
+    /// Absolute path of the song's file, only set with `expose-file-paths`.
+    file_path: Option<PathBuf>,
+}
+
+impl SongJson {
+    fn from_song(song: &Song, ratings: &Ratings, config: &Config) -> SongJson {
+        let rating = ratings.song(&song.album.unique_name, &song.unique_name);
+        SongJson {
+            name: song.name.clone(),
+            unique_name: song.unique_name.clone(),
+            album: song.album.clone(),
+            artists: song.artists.clone(),
+            track: song.track.clone(),
+            disc: song.disc,
+            year: song.year,
+            genres: song.genres.clone(),
+            composer: song.composer.clone(),
+            comment: song.comment.clone(),
+            replay_gain: song.replay_gain,
+            duration: song.duration,
+            bit_rate: song.bit_rate,
+            sample_rate: song.sample_rate,
+            channels: song.channels,
+            cover_url: song.cover_url.clone(),
+            cover_thumb_url: song
+                .cover_url
+                .as_ref()
+                .map(|_| cover_size_url(&song.album.unique_name, CoverSize::Thumb)),
+            cover_medium_url: song
+                .cover_url
+                .as_ref()
+                .map(|_| cover_size_url(&song.album.unique_name, CoverSize::Medium)),
+            url: song.url.clone(),
+            rating: rating.rating,
+            favorite: rating.favorite,
+            file_path: Some(song.path.clone()).filter(|_| config.expose_file_paths),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct QualityJson {
+    format: String,
+    /// Bit rate in bit/s, if known.
+    bit_rate: Option<u64>,
+    url: String,
+    original: bool,
+}
+
+#[derive(Serialize)]
+struct IncompleteSongJson {
+    name: String,
+    unique_name: String,
+    album: AlbumRef,
+    url: String,
+    missing: Vec<&'static str>,
+}
+
+impl IncompleteSongJson {
+    fn from_song(song: &Song) -> IncompleteSongJson {
+        IncompleteSongJson {
+            name: song.name.clone(),
+            unique_name: song.unique_name.clone(),
+            album: song.album.clone(),
+            url: song.url.clone(),
+            missing: song.missing.names(),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct AlbumJson {
+    name: String,
+    unique_name: String,
+    artists: Vec<ArtistRef>,
+    songs: Vec<Option<SongRef>>,
+    /// The tracks of each disc, only listed for albums with more than one disc.
+    discs: Vec<DiscJson>,
+    cover_url: Option<String>,
+    cover_thumb_url: Option<String>,
+    cover_medium_url: Option<String>,
+    cover_width: Option<u32>,
+    cover_height: Option<u32>,
+    tracked: bool,
+    is_single: bool,
+    year: Option<u32>,
+    /// The most common genre first.
+    genres: Vec<String>,
+    /// Only set for albums with more than one disc.
+    disc_total: Option<u32>,
+    /// Total length in seconds of the songs with a known length.
+    duration: Option<f64>,
+    /// Number of tracks the album should have, which can be more than the
+    /// number of songs found for incomplete albums.
+    total_tracks: Option<u32>,
+    /// Album gain in dB, taken from the first song tagged with it.
+    replay_gain: Option<f32>,
+    replay_peak: Option<f32>,
+    documents: Vec<String>,
+    /// From 0 to 5 stars, if rated.
+    rating: Option<u8>,
+    favorite: bool,
+}
+
+impl AlbumJson {
+    async fn from_album(album: &Album, ratings: &Ratings) -> AlbumJson {
+        let rating = ratings.album(&album.unique_name);
+        let mut duration = None;
+        let mut replay_gain = None;
+        let mut replay_peak = None;
+        for song in album.songs.iter().flatten() {
+            let song = song.read().await;
+            if let Some(song_duration) = song.duration {
+                *duration.get_or_insert(0.0) += song_duration;
+            }
+            replay_gain = replay_gain.or(song.replay_gain.album_gain);
+            replay_peak = replay_peak.or(song.replay_gain.album_peak);
+        }
+
+        AlbumJson {
+            name: album.name.clone(),
+            unique_name: album.unique_name.clone(),
+            artists: album.artists.clone(),
+            songs: song_refs(&album.songs).await,
+            discs: if album.discs.len() > 1 {
+                stream::iter(&album.discs)
+                    .then(|(&disc, songs)| async move {
+                        DiscJson {
+                            disc,
+                            songs: song_refs(songs).await,
+                        }
+                    })
+                    .collect()
+                    .await
+            } else {
+                vec![]
+            },
+            cover_url: album.cover_url.clone(),
+            cover_thumb_url: album
+                .cover_url
+                .as_ref()
+                .map(|_| cover_size_url(&album.unique_name, CoverSize::Thumb)),
+            cover_medium_url: album
+                .cover_url
+                .as_ref()
+                .map(|_| cover_size_url(&album.unique_name, CoverSize::Medium)),
+            cover_width: album.cover_size.map(|(width, _)| width),
+            cover_height: album.cover_size.map(|(_, height)| height),
+            tracked: album.tracked,
+            is_single: album.is_single(),
+            year: album.year,
+            genres: album.genres.clone(),
+            disc_total: album.disc_total.filter(|&total| total > 1),
+            duration,
+            total_tracks: album.total_tracks(),
+            replay_gain,
+            replay_peak,
+            documents: album.documents.clone(),
+            rating: rating.rating,
+            favorite: rating.favorite,
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct DiscJson {
+    disc: u32,
+    songs: Vec<Option<SongRef>>,
+}
+
+async fn song_refs(songs: &[Option<Arc<RwLock<Song>>>]) -> Vec<Option<SongRef>> {
+    stream::iter(songs)
+        .then(|song| async move {
+            if let Some(song) = song {
+                let song = song.read().await;
+                Some(SongRef {
+                    name: song.name.clone(),
+                    unique_name: song.unique_name.clone(),
+                })
+            } else {
+                None
+            }
+        })
+        .collect()
+        .await
+}
+
+#[derive(Serialize)]
+struct ArtistJson {
+    name: String,
+    unique_name: String,
+    albums: Vec<AlbumRef>,
+    cover_url: Option<String>,
+}
+
+impl ArtistJson {
+    async fn from_artist(artist: &Artist) -> ArtistJson {
+        let mut albums = vec![];
+
+        for album in artist.albums.values() {
+            let album = album.read().await;
+            albums.push(AlbumRef {
+                name: album.name.clone(),
+                unique_name: album.unique_name.clone(),
+            })
+        }
+
+        ArtistJson {
+            name: artist.name.clone(),
+            unique_name: artist.unique_name.clone(),
+            albums,
+            cover_url: artist.cover_url.clone(),
+        }
     }
-
-    HttpResponseBuilder::new(StatusCode::OK).json(w_ok(artists))
 }
 
-#[get("/album/{album_name}")]
-async fn get_album(
-    index: web::Data<Index>,
-    web::Path(album_name): web::Path<String>,
-) -> Result<HttpResponse> {
-    if let Some(album) = index.albums.get(&album_name) {
-        let album = album.read().await;
+#[derive(Serialize)]
+struct RatingJson {
+    rating: Option<u8>,
+    favorite: bool,
+}
 
-        Ok(
-            HttpResponseBuilder::new(StatusCode::OK)
-                .json(w_ok(AlbumJson::from_album(&album).await)),
-        )
-    } else {
-        bail!(ErrorKind::NoSuchResource);
+impl RatingJson {
+    fn from_rating(rating: &Rating) -> RatingJson {
+        RatingJson {
+            rating: rating.rating,
+            favorite: rating.favorite,
+        }
     }
 }
 
-#[get("/artist/{artist_name}")]
-async fn get_artist(
-    index: web::Data<Index>,
-    web::Path(artist_name): web::Path<String>,
-) -> Result<HttpResponse> {
-    if let Some(artist) = index.artists.get(&artist_name) {
-        let artist = artist.read().await;
+/// The whole index. Albums and artists only refer to their songs and albums,
+/// so each song is only included once.
+#[derive(Serialize)]
+struct AllJson {
+    artists: Vec<ArtistJson>,
+    albums: Vec<AlbumJson>,
+    songs: Vec<SongJson>,
+}
 
-        Ok(HttpResponseBuilder::new(StatusCode::OK)
-            .json(w_ok(ArtistJson::from_artist(&artist).await)))
-    } else {
-        bail!(ErrorKind::NoSuchResource);
-    }
+#[derive(Serialize)]
+struct StatsJson {
+    artists: usize,
+    albums: usize,
+    songs: usize,
+    /// Albums with a cover image file.
+    file_covers: usize,
+    /// Albums with a cover extracted from their songs' embedded art.
+    generated_covers: usize,
+    missing_covers: usize,
+    /// Total length in seconds of the songs with a known length.
+    duration: f64,
+    /// Seconds it took to build the index.
+    build_time: f64,
 }
 
-#[get("/album/{album_name}/{song_name}")]
-async fn get_song(
-    index: web::Data<Index>,
-    web::Path((album_name, song_name)): web::Path<(String, String)>,
-) -> Result<HttpResponse> {
-    if let Some(album) = index.albums.get(&album_name) {
-        let album = album.read().await;
-        if let Some(song) = album.songs_by_name.get(&song_name) {
-            let song = song.read().await;
+#[derive(Serialize)]
+struct ReindexJson {
+    artists: usize,
+    albums: usize,
+}
 
-            Ok(HttpResponseBuilder::new(StatusCode::OK).json(w_ok(SongJson::from_song(&song))))
-        } else {
-            bail!(ErrorKind::NoSuchResource)
-        }
-    } else {
-        bail!(ErrorKind::NoSuchResource);
-    }
+/// One page of a listing.
+#[derive(Serialize)]
+struct PageJson<T> {
+    /// Number of items in the whole listing.
+    total: usize,
+    offset: usize,
+    limit: usize,
+    items: Vec<T>,
 }
 
 #[derive(Serialize)]
-struct SongJson {
+struct SearchJson {
+    artists: Vec<ArtistJson>,
+    albums: Vec<AlbumJson>,
+    songs: Vec<SongJson>,
+}
+
+#[derive(Serialize)]
+struct AlbumDumpJson {
     name: String,
     unique_name: String,
-    album: AlbumRef,
+    path: PathBuf,
     artists: Vec<ArtistRef>,
-    track: Option<u32>,
-    cover_url: Option<String>,
-    url: String,
-}
-
-impl SongJson {
-    fn from_song(song: &Song) -> SongJson {
-        SongJson {
-            name: song.name.clone(),
-            unique_name: song.unique_name.clone(),
-            album: song.album.clone(),
-            artists: song.artists.clone(),
-            track: song.track.clone(),
-            cover_url: song.cover_url.clone(),
-            url: song.url.clone(),
-        }
-    }
+    songs: Vec<Option<SongDumpJson>>,
+    cover_path: Option<PathBuf>,
+    cover_rating: CoverRating,
+    tracked: bool,
 }
 
 #[derive(Serialize)]
-struct AlbumJson {
+struct SongDumpJson {
     name: String,
     unique_name: String,
+    path: PathBuf,
     artists: Vec<ArtistRef>,
-    songs: Vec<Option<SongRef>>,
-    cover_url: Option<String>,
-    tracked: bool,
+    track: Option<u32>,
 }
 
-impl AlbumJson {
-    async fn from_album(album: &Album) -> AlbumJson {
-        AlbumJson {
+impl AlbumDumpJson {
+    async fn from_album(album: &Album) -> AlbumDumpJson {
+        AlbumDumpJson {
             name: album.name.clone(),
             unique_name: album.unique_name.clone(),
+            path: album.path.clone(),
             artists: album.artists.clone(),
             songs: stream::iter(&album.songs)
                 .then(|song| async move {
                     if let Some(song) = song {
                         let song = song.read().await;
-                        Some(SongRef {
+                        Some(SongDumpJson {
                             name: song.name.clone(),
                             unique_name: song.unique_name.clone(),
+                            path: song.path.clone(),
+                            artists: song.artists.clone(),
+                            track: song.track,
                         })
                     } else {
                         None
@@ -956,37 +4467,795 @@ impl AlbumJson {
                 })
                 .collect()
                 .await,
-            cover_url: album.cover_url.clone(),
+            cover_path: album.cover_path.clone(),
+            cover_rating: album.cover_rating,
             tracked: album.tracked,
         }
     }
 }
 
-#[derive(Serialize)]
-struct ArtistJson {
-    name: String,
-    unique_name: String,
-    albums: Vec<AlbumRef>,
-    cover_url: Option<String>,
-}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::rt::System;
+    use ffmpeg4::Dictionary;
 
-impl ArtistJson {
-    async fn from_artist(artist: &Artist) -> ArtistJson {
-        let mut albums = vec![];
+    fn test_index(config: &Config) -> Index {
+        Index::empty(config, "/cdn/files", Arc::new(Ratings::empty()))
+    }
 
-        for album in artist.albums.values() {
-            let album = album.read().await;
-            albums.push(AlbumRef {
-                name: album.name.clone(),
-                unique_name: album.unique_name.clone(),
+    /// Makes a song of `album` stored in `/music/<album>/<file>`.
+    fn test_song(album: &str, name: &str, file: &str, track: Option<u32>) -> Song {
+        Song {
+            name: name.to_string(),
+            unique_name: sanitize(file),
+            search_key: search_key(name),
+            album: AlbumRef {
+                name: album.to_string(),
+                unique_name: sanitize(album),
+            },
+            artists: vec![ArtistRef {
+                name: "Artist".to_string(),
+                unique_name: "artist".to_string(),
+            }],
+            album_artists: vec![],
+            track,
+            track_total: None,
+            cover_url: None,
+            url: format!("/cdn/files/{}/{}", album, file),
+            path: Path::new("/music").join(album).join(file),
+            missing: Default::default(),
+            year: None,
+            genres: vec![],
+            disc: None,
+            disc_total: None,
+            composer: None,
+            comment: None,
+            replay_gain: Default::default(),
+            bit_rate: None,
+            sample_rate: None,
+            channels: None,
+            duration: None,
+        }
+    }
+
+    /// Writes a short silent WAV file with RIFF INFO tags, like `INAM` for the
+    /// title and `IPRD` for the album, which ffmpeg reads as metadata.
+    fn write_wav(path: &Path, tags: &[(&str, &str)]) {
+        let mut info = b"INFO".to_vec();
+        for (id, value) in tags {
+            let mut value = value.as_bytes().to_vec();
+            value.push(0);
+            info.extend_from_slice(id.as_bytes());
+            info.extend_from_slice(&(value.len() as u32).to_le_bytes());
+            // chunks are padded to an even length
+            if value.len() % 2 == 1 {
+                value.push(0);
+            }
+            info.extend_from_slice(&value);
+        }
+
+        // a tenth of a second of 8 kHz 16 bit mono pcm
+        let samples = vec![0u8; 1600];
+        let mut chunks = vec![];
+        chunks.extend_from_slice(b"fmt ");
+        chunks.extend_from_slice(&16u32.to_le_bytes());
+        chunks.extend_from_slice(&1u16.to_le_bytes());
+        chunks.extend_from_slice(&1u16.to_le_bytes());
+        chunks.extend_from_slice(&8000u32.to_le_bytes());
+        chunks.extend_from_slice(&16000u32.to_le_bytes());
+        chunks.extend_from_slice(&2u16.to_le_bytes());
+        chunks.extend_from_slice(&16u16.to_le_bytes());
+        chunks.extend_from_slice(b"LIST");
+        chunks.extend_from_slice(&(info.len() as u32).to_le_bytes());
+        chunks.extend_from_slice(&info);
+        chunks.extend_from_slice(b"data");
+        chunks.extend_from_slice(&(samples.len() as u32).to_le_bytes());
+        chunks.extend_from_slice(&samples);
+
+        let mut wav = b"RIFF".to_vec();
+        wav.extend_from_slice(&(chunks.len() as u32 + 4).to_le_bytes());
+        wav.extend_from_slice(b"WAVE");
+        wav.extend_from_slice(&chunks);
+        fs::write(path, wav).unwrap();
+    }
+
+    /// Indexes the library described by the `[general]` table `general`.
+    fn index_library(general: &str) -> Index {
+        let config = Config::from_toml(&format!("[general]\n{}", general)).unwrap();
+        System::new("test")
+            .block_on(async move {
+                let progress = IndexProgress::default();
+                Index::index(
+                    &config,
+                    "/cdn/files",
+                    Arc::new(Ratings::empty()),
+                    &[],
+                    &progress,
+                )
+                .await
             })
+            .unwrap()
+    }
+
+    /// Gets the cover of every album by unique name.
+    fn album_covers(index: &Index) -> BTreeMap<String, Option<PathBuf>> {
+        executor::block_on(async {
+            let mut covers = BTreeMap::new();
+            for (name, album) in index.albums.iter() {
+                covers.insert(name.clone(), album.read().await.cover_path.clone());
+            }
+            covers
+        })
+    }
+
+    /// Gets the file names of an album's songs in play order.
+    async fn song_files(album: &Album) -> Vec<Option<String>> {
+        let mut files = vec![];
+        for song in album.songs.iter() {
+            files.push(match song {
+                Some(song) => Some(
+                    song.read()
+                        .await
+                        .path
+                        .file_name()
+                        .unwrap()
+                        .to_string_lossy()
+                        .to_string(),
+                ),
+                None => None,
+            });
         }
+        files
+    }
 
-        ArtistJson {
-            name: artist.name.clone(),
-            unique_name: artist.unique_name.clone(),
-            albums,
-            cover_url: artist.cover_url.clone(),
+    #[test]
+    fn cover_size_url_is_under_cdn_index() {
+        assert_eq!(
+            cover_size_url("some-album", CoverSize::Thumb),
+            "/cdn/index/album/some-album/cover?size=thumb"
+        );
+    }
+
+    #[test]
+    fn hls_playlist_url_is_under_cdn_index() {
+        assert_eq!(
+            hls_playlist_url("some-album", "some-song"),
+            format!("/cdn/index/album/some-album/some-song/hls/{}", HLS_PLAYLIST)
+        );
+    }
+
+    #[test]
+    fn sanitize_makes_url_safe_names() {
+        assert_eq!(sanitize("Abbey Road"), "abbey-road");
+        assert_eq!(sanitize("AC/DC"), "ac-dc");
+        assert_eq!(sanitize("Hello, World!"), "hello--world-");
+        assert_eq!(sanitize("Café del Mar"), "cafe-del-mar");
+        assert_eq!(sanitize("1999"), "1999");
+    }
+
+    #[test]
+    fn colliding_album_names_get_numbered_unique_names() {
+        let config = Config::from_toml("").unwrap();
+        let mut index = test_index(&config);
+
+        executor::block_on(async {
+            // both sanitize to `wish-`
+            let first = index
+                .get_or_insert_album("Wish?", &[], PathBuf::from("a"))
+                .await;
+            let second = index
+                .get_or_insert_album("Wish!", &[], PathBuf::from("b"))
+                .await;
+            assert_eq!(first.read().await.unique_name, "wish-");
+            assert_eq!(second.read().await.unique_name, "wish--1");
+
+            // differently cased names find the album they collided into
+            let second_upper = index
+                .get_or_insert_album("WISH!", &[], PathBuf::from("c"))
+                .await;
+            assert!(Arc::ptr_eq(&second, &second_upper));
+            let first_lower = index
+                .get_or_insert_album("wish?", &[], PathBuf::from("d"))
+                .await;
+            assert!(Arc::ptr_eq(&first, &first_lower));
+
+            let third = index
+                .get_or_insert_album("Wish.", &[], PathBuf::from("e"))
+                .await;
+            assert_eq!(third.read().await.unique_name, "wish--2");
+        });
+
+        assert_eq!(index.albums.len(), 3);
+        assert!(index.album_taken("wish--1", "Wish."));
+        assert!(!index.album_taken("wish--3", "Wish."));
+    }
+
+    #[test]
+    fn collapse_whitespace_trims_and_collapses() {
+        assert_eq!(collapse_whitespace("The  Beatles"), "The Beatles");
+        assert_eq!(collapse_whitespace(" Beatles "), "Beatles");
+        assert_eq!(collapse_whitespace("\tThe \n Beatles"), "The Beatles");
+        assert_eq!(collapse_whitespace("The Beatles"), "The Beatles");
+        assert_eq!(collapse_whitespace("   "), "");
+    }
+
+    #[test]
+    fn artist_key_leaves_out_leading_articles() {
+        let articles = vec!["the".to_string()];
+        assert_eq!(artist_key("The Beatles", &articles), "Beatles");
+        assert_eq!(artist_key("the Beatles", &articles), "Beatles");
+        assert_eq!(artist_key("Beatles", &articles), "Beatles");
+        assert_eq!(
+            artist_key(&collapse_whitespace("The  Beatles"), &articles),
+            artist_key(&collapse_whitespace(" Beatles "), &articles)
+        );
+        // a name that is only an article is kept whole
+        assert_eq!(artist_key("The", &articles), "The");
+        // only whole words are articles
+        assert_eq!(
+            artist_key("Theory of a Deadman", &articles),
+            "Theory of a Deadman"
+        );
+        assert_eq!(artist_key("The Beatles", &[]), "The Beatles");
+    }
+
+    #[test]
+    fn artists_differing_by_an_article_are_merged() {
+        let config = Config::from_toml("[general]\nartist-articles = [\"The\"]").unwrap();
+        let mut index = test_index(&config);
+
+        executor::block_on(async {
+            let first = index.get_or_insert_artist("The Beatles").await;
+            let second = index.get_or_insert_artist("Beatles").await;
+            assert_eq!(first, second);
+            assert_eq!(first, "the-beatles");
+
+            let the = index.get_or_insert_artist("The").await;
+            assert_ne!(the, first);
+        });
+
+        assert_eq!(index.artists.len(), 2);
+    }
+
+    #[test]
+    fn covers_are_stamped_on_songs_regardless_of_order() {
+        let config = Config::from_toml("[general]\nbase-dir = \"/music\"").unwrap();
+        let cover = Path::new("/music/Album/cover.jpg");
+        let songs = vec![
+            test_song("Album", "Two", "02.flac", Some(2)),
+            test_song("Album", "Bonus", "bonus.flac", None),
+            test_song("Album", "One", "01.flac", Some(1)),
+        ];
+
+        executor::block_on(async {
+            for reversed in [false, true].iter() {
+                let mut songs = songs.clone();
+                if *reversed {
+                    songs.reverse();
+                }
+
+                // the cover is found after the first, second or last song
+                for cover_after in 1..=songs.len() {
+                    let mut index = test_index(&config);
+                    for (i, song) in songs.iter().enumerate() {
+                        index.insert_song(song.clone()).await.unwrap();
+                        if i + 1 == cover_after {
+                            let album = index.albums["album"].clone();
+                            let mut album = album.write().await;
+                            Index::insert_cover(&mut album, cover, &index.config, &mut index.urls)
+                                .await
+                                .unwrap();
+                        }
+                    }
+
+                    let album = index.albums["album"].read().await;
+                    assert_eq!(
+                        song_files(&album).await,
+                        vec![
+                            Some("01.flac".to_string()),
+                            Some("02.flac".to_string()),
+                            Some("bonus.flac".to_string()),
+                        ]
+                    );
+
+                    assert!(album.cover_url.is_some());
+                    assert_eq!(album.songs_by_name.len(), 3);
+                    for song in album.songs_by_name.values() {
+                        assert_eq!(song.read().await.cover_url, album.cover_url);
+                    }
+                }
+            }
+        });
+    }
+
+    #[test]
+    fn untracked_songs_are_ordered_by_name_then_path() {
+        let config = Config::from_toml("[general]\nbase-dir = \"/music\"").unwrap();
+        let mut index = test_index(&config);
+
+        executor::block_on(async {
+            for song in vec![
+                test_song("Album", "charlie", "c.flac", None),
+                test_song("Album", "Bravo", "b2.flac", None),
+                test_song("Album", "alpha", "a.flac", None),
+                test_song("Album", "bravo", "b1.flac", None),
+            ] {
+                index.insert_song(song).await.unwrap();
+            }
+
+            let album = index.albums["album"].read().await;
+            assert!(!album.tracked);
+            assert_eq!(
+                song_files(&album).await,
+                vec![
+                    Some("a.flac".to_string()),
+                    Some("b1.flac".to_string()),
+                    Some("b2.flac".to_string()),
+                    Some("c.flac".to_string()),
+                ]
+            );
+        });
+    }
+
+    #[test]
+    fn albums_sharing_a_directory_share_its_cover() {
+        let dir = tempfile::tempdir().unwrap();
+        let music = dir.path().join("music");
+        let eps = music.join("EPs");
+        fs::create_dir_all(&eps).unwrap();
+        for (file, title, album, track) in [
+            ("a1.wav", "A One", "First EP", "1"),
+            ("a2.wav", "A Two", "First EP", "2"),
+            ("b1.wav", "B One", "Second EP", "1"),
+        ]
+        .iter()
+        {
+            write_wav(
+                &eps.join(file),
+                &[
+                    ("INAM", *title),
+                    ("IPRD", *album),
+                    ("IART", "Artist"),
+                    ("ITRK", *track),
+                ],
+            );
+        }
+        let cover = eps.join("cover.png");
+        RgbaImage::new(1, 1).save(&cover).unwrap();
+
+        let library = format!(
+            "base-dir = {:?}\ncache-dir = {:?}\n",
+            music,
+            dir.path().join("cache")
+        );
+
+        let index = index_library(&format!("{}shared-directory-covers = \"all\"", library));
+        let mut expected = BTreeMap::new();
+        expected.insert("first-ep".to_string(), Some(cover.clone()));
+        expected.insert("second-ep".to_string(), Some(cover.clone()));
+        assert_eq!(album_covers(&index), expected);
+
+        let index = index_library(&format!(
+            "{}shared-directory-covers = \"most-songs\"",
+            library
+        ));
+        expected.insert("second-ep".to_string(), None);
+        assert_eq!(album_covers(&index), expected);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn symlink_loops_are_walked_once() {
+        let dir = tempfile::tempdir().unwrap();
+        let music = dir.path().join("music");
+        let album = music.join("Album");
+        fs::create_dir_all(&album).unwrap();
+        write_wav(
+            &album.join("one.wav"),
+            &[("INAM", "One"), ("IPRD", "Album"), ("IART", "Artist")],
+        );
+        std::os::unix::fs::symlink("..", album.join("loop")).unwrap();
+
+        let index = index_library(&format!(
+            "base-dir = {:?}\ncache-dir = {:?}\n",
+            music,
+            dir.path().join("cache")
+        ));
+
+        assert_eq!(index.albums.len(), 1);
+        let album = executor::block_on(index.albums["album"].read());
+        assert_eq!(album.songs_by_name.len(), 1);
+    }
+
+    #[test]
+    fn cover_only_dirs_are_found() {
+        let mut dir_covers = BTreeMap::new();
+        let mut dir_albums = BTreeMap::new();
+        for dir in [
+            "/music/Artist",
+            "/music/Artist/Album",
+            "/music/Scans",
+            "/music/Other/Art",
+        ]
+        .iter()
+        {
+            dir_covers.insert(PathBuf::from(dir), vec![Path::new(dir).join("cover.jpg")]);
+        }
+        dir_albums.insert(PathBuf::from("/music/Artist/Album"), ());
+        dir_albums.insert(PathBuf::from("/music/Singles"), ());
+
+        // artist directories above albums and album directories are not
+        // cover-only
+        assert_eq!(
+            Index::find_cover_only_dirs(&dir_covers, &dir_albums),
+            vec![Path::new("/music/Other/Art"), Path::new("/music/Scans")]
+        );
+
+        dir_albums.clear();
+        assert_eq!(
+            Index::find_cover_only_dirs(&dir_covers, &dir_albums).len(),
+            dir_covers.len()
+        );
+    }
+
+    #[test]
+    fn itunes_atoms_are_found() {
+        let dict: Dictionary = [
+            ("©nam", "Title"),
+            ("©alb", "Album"),
+            ("©ART", "Artist"),
+            ("aART", "Album Artist"),
+            ("trkn", "3/12"),
+            ("©day", "2001-05-01T07:00:00Z"),
+            ("©gen", "Rock"),
+            ("©wrt", "Composer"),
+            ("©cmt", "Comment"),
+        ]
+        .iter()
+        .collect();
+
+        assert_eq!(Song::find_title(&dict), Some("Title".to_string()));
+        assert_eq!(Song::find_album(&dict), Some("Album".to_string()));
+        assert_eq!(
+            Song::find_all(&dict, ARTIST_KEYS),
+            vec!["Artist".to_string()]
+        );
+        assert_eq!(
+            Song::find_album_artist(&dict),
+            Some("Album Artist".to_string())
+        );
+        assert_eq!(Song::find_track(&dict), (Some(3), Some(12)));
+        assert_eq!(Song::find_year(&dict), Some(2001));
+        assert_eq!(Song::find_all(&dict, GENRE_KEYS), vec!["Rock".to_string()]);
+        assert_eq!(Song::find_composer(&dict), Some("Composer".to_string()));
+        assert_eq!(Song::find_comment(&dict), Some("Comment".to_string()));
+    }
+
+    #[test]
+    fn riff_info_tags_are_found() {
+        let dict: Dictionary = [
+            ("INAM", "Title"),
+            ("IPRD", "Album"),
+            ("IART", "Artist"),
+            ("ITRK", "4"),
+            ("ICRD", "1999"),
+            ("IGNR", "Jazz"),
+            ("ICMT", "Comment"),
+        ]
+        .iter()
+        .collect();
+
+        assert_eq!(Song::find_title(&dict), Some("Title".to_string()));
+        assert_eq!(Song::find_album(&dict), Some("Album".to_string()));
+        assert_eq!(
+            Song::find_all(&dict, ARTIST_KEYS),
+            vec!["Artist".to_string()]
+        );
+        assert_eq!(Song::find_track(&dict), (Some(4), None));
+        assert_eq!(Song::find_year(&dict), Some(1999));
+        assert_eq!(Song::find_all(&dict, GENRE_KEYS), vec!["Jazz".to_string()]);
+        assert_eq!(Song::find_comment(&dict), Some("Comment".to_string()));
+    }
+
+    #[test]
+    fn common_tag_names_win_over_fallbacks() {
+        let dict: Dictionary = [
+            ("©nam", "Atom Title"),
+            ("title", "Title"),
+            ("IART", "Info Artist"),
+            ("artist", "Artist"),
+        ]
+        .iter()
+        .collect();
+
+        assert_eq!(Song::find_title(&dict), Some("Title".to_string()));
+        assert_eq!(
+            Song::find_all(&dict, ARTIST_KEYS),
+            vec!["Artist".to_string()]
+        );
+
+        let empty = Dictionary::new();
+        assert_eq!(Song::find_title(&empty), None);
+        assert_eq!(Song::find_track(&empty), (None, None));
+        assert!(Song::find_all(&empty, ARTIST_KEYS).is_empty());
+    }
+
+    /// Checks that `value` has every property the openapi schema `name`
+    /// requires, no undocumented ones and only nulls where they are allowed,
+    /// following references to other schemas.
+    fn assert_matches_schema(openapi: &serde_json::Value, value: &serde_json::Value, name: &str) {
+        let schema = &openapi["components"]["schemas"][name];
+        let properties = schema["properties"].as_object().unwrap();
+        let object = value.as_object().unwrap();
+
+        for key in schema["required"].as_array().unwrap() {
+            let key = key.as_str().unwrap();
+            assert!(object.contains_key(key), "{}.{} is missing", name, key);
+        }
+
+        let schema_ref = |property: &serde_json::Value| {
+            property["$ref"]
+                .as_str()
+                .or_else(|| property["allOf"][0]["$ref"].as_str())
+                .map(|path| path.trim_start_matches("#/components/schemas/").to_string())
+        };
+        for (key, value) in object.iter() {
+            let property = properties
+                .get(key)
+                .unwrap_or_else(|| panic!("{}.{} is not documented", name, key));
+            let items = value.as_array().map(|items| (&property["items"], items));
+
+            if value.is_null() {
+                assert_eq!(property["nullable"], true, "{}.{} is null", name, key);
+            } else if let Some(ref_name) = schema_ref(property) {
+                assert_matches_schema(openapi, value, &ref_name);
+            } else if let Some((item_schema, items)) = items {
+                for item in items.iter() {
+                    if item.is_null() {
+                        assert_eq!(item_schema["nullable"], true, "{}.{} has a null", name, key);
+                    } else if let Some(ref_name) = schema_ref(item_schema) {
+                        assert_matches_schema(openapi, item, &ref_name);
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn json_matches_the_openapi_schemas() {
+        let openapi: serde_json::Value =
+            serde_json::from_str(include_str!("../openapi.json")).unwrap();
+
+        let dir = tempfile::tempdir().unwrap();
+        let album_dir = dir.path().join("music").join("Album");
+        fs::create_dir_all(&album_dir).unwrap();
+        // the gap at track 2 leaves a null in the album's songs
+        for (file, title, track) in [("1.wav", "One", "1"), ("3.wav", "Three", "3")].iter() {
+            write_wav(
+                &album_dir.join(file),
+                &[
+                    ("INAM", *title),
+                    ("IPRD", "Album"),
+                    ("IART", "Artist"),
+                    ("ITRK", *track),
+                ],
+            );
+        }
+        RgbaImage::new(1, 1)
+            .save(album_dir.join("cover.png"))
+            .unwrap();
+        let index = index_library(&format!(
+            "base-dir = {:?}\ncache-dir = {:?}\n",
+            dir.path().join("music"),
+            dir.path().join("cache")
+        ));
+
+        executor::block_on(async {
+            let album = index.albums.get("album").unwrap().read().await;
+            let json = serde_json::to_value(AlbumJson::from_album(&album, &index.ratings).await);
+            assert_matches_schema(&openapi, &json.unwrap(), "Album");
+
+            for song in album.songs.iter().flatten() {
+                let song = song.read().await;
+                let json = SongJson::from_song(&song, &index.ratings, &index.config);
+                assert_matches_schema(&openapi, &serde_json::to_value(json).unwrap(), "Song");
+            }
+
+            let artist = index.artists.get("artist").unwrap().read().await;
+            let json = serde_json::to_value(ArtistJson::from_artist(&artist).await);
+            assert_matches_schema(&openapi, &json.unwrap(), "Artist");
+        });
+    }
+
+    #[test]
+    fn the_indexers_own_files_dont_trigger_reindexing() {
+        let config = Config::from_toml(
+            "[general]\nbase-dir = \"/music\"\ncache-dir = \"/cache\"\n\
+             cover-cache-dir = \"/covers\"",
+        )
+        .unwrap();
+        let index = test_index(&config);
+        let affects = |event: DebouncedEvent| index.is_affected_by(&event);
+        let path = PathBuf::from;
+
+        assert!(affects(DebouncedEvent::Write(path("/music/Album/1.flac"))));
+        assert!(affects(DebouncedEvent::Create(path(
+            "/music/Album/cover.jpg"
+        ))));
+        assert!(affects(DebouncedEvent::Remove(path("/music/Album/1.flac"))));
+
+        assert!(!affects(DebouncedEvent::Write(path(
+            "/cache/manifest.json"
+        ))));
+        assert!(!affects(DebouncedEvent::Remove(path(
+            "/cache/covers/a.jpg"
+        ))));
+        assert!(!affects(DebouncedEvent::Create(path(
+            "/covers/album/1.flac-ms1-cover-small-generated.jpg"
+        ))));
+        assert!(!affects(DebouncedEvent::Remove(path("/covers/album"))));
+        // extracted next to the song without a cover-cache-dir
+        assert!(!affects(DebouncedEvent::Create(path(
+            "/music/Album/1.flac-ms1-cover-small-generated.jpg"
+        ))));
+        assert!(!affects(DebouncedEvent::Rename(
+            path("/music/Album/.1.flac-ms1-cover-small-generated.jpg.12-0.tmp"),
+            path("/music/Album/1.flac-ms1-cover-small-generated.jpg")
+        )));
+    }
+
+    #[test]
+    fn generated_covers_are_kept_per_album() {
+        let dir = tempfile::tempdir().unwrap();
+        let covers = dir.path().join("covers");
+        let cover_path = |album: &str| {
+            let song = Path::new("/music").join(album).join("01.flac");
+            Index::make_cover_path(
+                &song,
+                Some(&covers),
+                &sanitize(album),
+                GeneratedCoverFormat::Jpg,
+            )
+            .unwrap()
+        };
+
+        let a = cover_path("Album A");
+        let b = cover_path("Album B");
+        assert_ne!(a, b);
+        assert_eq!(
+            a,
+            covers
+                .join("album-a")
+                .join("01.flac-ms1-cover-small-generated.jpg")
+        );
+        assert!(covers.join("album-b").is_dir());
+
+        let next_to_song = Index::make_cover_path(
+            Path::new("/music/Album A/01.flac"),
+            None,
+            "album-a",
+            GeneratedCoverFormat::Jpg,
+        )
+        .unwrap();
+        assert_eq!(
+            next_to_song,
+            Path::new("/music/Album A/01.flac-ms1-cover-small-generated.jpg")
+        );
+    }
+
+    #[test]
+    fn dropped_duplicate_tracks_are_not_served() {
+        for (duplicate_tracks, kept) in
+            [("keep-first", "low.mp3"), ("higher-bit-rate", "high.flac")].iter()
+        {
+            let config = Config::from_toml(&format!(
+                "[general]\nbase-dir = \"/music\"\nduplicate-tracks = \"{}\"",
+                duplicate_tracks
+            ))
+            .unwrap();
+            let mut index = test_index(&config);
+
+            executor::block_on(async {
+                for (file, bit_rate) in [("low.mp3", 128_000), ("high.flac", 900_000)].iter() {
+                    let mut song = test_song("Album", "Intro", file, Some(1));
+                    song.unique_name = sanitize("Intro");
+                    song.bit_rate = Some(*bit_rate);
+                    index.insert_song(song).await.unwrap();
+                }
+                let outro = test_song("Album", "Outro", "outro.flac", Some(2));
+                index.insert_song(outro).await.unwrap();
+
+                let album = index.albums["album"].read().await;
+                assert_eq!(
+                    song_files(&album).await,
+                    vec![Some(kept.to_string()), Some("outro.flac".to_string())]
+                );
+                assert_eq!(album.songs_by_name.len(), 2);
+                let served = album.songs_by_name["intro"].read().await;
+                assert_eq!(served.path, Path::new("/music/Album").join(kept));
+                drop(served);
+                assert!(album.neighbor("intro", true, false).is_some());
+            });
+        }
+    }
+
+    #[test]
+    fn rewalked_dirs_replace_their_files_in_place() {
+        let paths = |paths: &[&str]| paths.iter().map(PathBuf::from).collect::<Vec<_>>();
+        let previous = paths(&[
+            "/music",
+            "/music/A",
+            "/music/A/1.flac",
+            "/music/A/2.flac",
+            "/music/B",
+            "/music/B/1.flac",
+        ]);
+
+        assert_eq!(
+            Index::merge_walks(
+                &previous,
+                &paths(&["/music/A"]),
+                paths(&["/music/A/1.flac", "/music/A/3.flac"])
+            ),
+            paths(&[
+                "/music",
+                "/music/A",
+                "/music/A/1.flac",
+                "/music/A/3.flac",
+                "/music/B",
+                "/music/B/1.flac",
+            ])
+        );
+
+        // a directory with nothing left in it before adds its files at the end
+        assert_eq!(
+            Index::merge_walks(
+                &previous,
+                &paths(&["/music/C"]),
+                paths(&["/music/C/1.flac"])
+            ),
+            [previous.clone(), paths(&["/music/C/1.flac"])].concat()
+        );
+    }
+
+    #[test]
+    fn album_reindexes_only_walk_the_albums_dirs() {
+        let dir = tempfile::tempdir().unwrap();
+        let music = dir.path().join("music");
+        for album in ["A", "B"].iter() {
+            fs::create_dir_all(music.join(album)).unwrap();
+            write_wav(
+                &music.join(album).join("1.wav"),
+                &[("INAM", "One"), ("IPRD", album), ("IART", "Artist")],
+            );
         }
+
+        let index = index_library(&format!(
+            "base-dir = {:?}\ncache-dir = {:?}\n",
+            music,
+            dir.path().join("cache")
+        ));
+
+        for album in ["A", "B"].iter() {
+            write_wav(
+                &music.join(album).join("2.wav"),
+                &[("INAM", "Two"), ("IPRD", album), ("IART", "Artist")],
+            );
+        }
+
+        let dirs = vec![music.join("A")];
+        let index = System::new("test")
+            .block_on(async move {
+                let progress = IndexProgress::default();
+                index.reindex(&[], Some(&dirs), &progress).await
+            })
+            .unwrap();
+
+        let song_count = |name: &str| {
+            executor::block_on(async { index.albums[name].read().await.songs_by_name.len() })
+        };
+        assert_eq!(song_count("a"), 2);
+        assert_eq!(song_count("b"), 1);
     }
 }
@@ -0,0 +1,279 @@
+use crate::{
+    cdn::index::SongMetadata,
+    config::Config,
+    error::{Result, ResultExt},
+    path_error,
+    util::{fs::write_atomic, hash::hash_file},
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{HashMap, HashSet},
+    fs,
+    fs::File,
+    future::Future,
+    io::BufReader,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU32, Ordering},
+        Mutex,
+    },
+    time::UNIX_EPOCH,
+};
+
+const MANIFEST_FILE: &str = "manifest.json";
+
+/// Bump this whenever `ManifestEntry` or `SongMetadata` change so old manifests
+/// get discarded instead of failing to decode.
+const MANIFEST_VERSION: u32 = 12;
+
+#[derive(Debug, Serialize)]
+struct ManifestFile {
+    version: u32,
+    entries: HashMap<PathBuf, ManifestEntry>,
+}
+
+/// A manifest of any version. Its entries are only decoded once the version is
+/// known to match.
+#[derive(Debug, Deserialize)]
+struct VersionedManifestFile {
+    version: u32,
+    entries: serde_json::Value,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct ManifestEntry {
+    size: u64,
+    /// Modification time in nanoseconds since the unix epoch.
+    modified: u64,
+    /// Hash of the file's size and its first and last few kilobytes.
+    hash: String,
+    metadata: SongMetadata,
+}
+
+/// Keeps track of song metadata between indexing runs so unchanged files don't
+/// have to be probed again.
+///
+/// A file counts as unchanged if its size and modification time match the
+/// manifest. If only the modification time changed, the file is hashed to check
+/// whether its contents changed too.
+///
+/// Songs can be looked up concurrently. The locks are never held while a song
+/// is hashed or probed.
+pub struct Manifest {
+    path: PathBuf,
+    entries: Mutex<HashMap<PathBuf, ManifestEntry>>,
+    seen: Mutex<HashSet<PathBuf>>,
+    probed: AtomicU32,
+}
+
+impl Manifest {
+    /// Loads the manifest from the configured cache file. A missing, unreadable
+    /// or outdated manifest results in an empty one.
+    pub async fn load(config: &Config) -> Manifest {
+        let path = config
+            .cache_file
+            .clone()
+            .unwrap_or_else(|| config.cache_dir.join(MANIFEST_FILE));
+        let path_moved = path.clone();
+
+        let res: Result<_> = tokio::task::spawn_blocking(move || Manifest::read(&path_moved))
+            .await
+            .chain_err(path_error!(
+                IndexingError,
+                path,
+                "loading manifest off-thread"
+            ))
+            .and_then(|res| res);
+
+        let entries = match res {
+            Ok(Some(entries)) => entries,
+            Ok(None) => Default::default(),
+            Err(e) => {
+                warn!("Unable to load manifest, probing all files: {}", e);
+                Default::default()
+            }
+        };
+
+        Manifest {
+            path,
+            entries: Mutex::new(entries),
+            seen: Default::default(),
+            probed: Default::default(),
+        }
+    }
+
+    /// Gets the metadata of a song, only running `probe` if the song changed
+    /// since it was last recorded.
+    pub async fn get_or_probe<F, Fut>(&self, path: &Path, probe: F) -> Result<SongMetadata>
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = Result<SongMetadata>>,
+    {
+        self.seen.lock().unwrap().insert(path.to_path_buf());
+
+        let stat =
+            path.metadata()
+                .chain_err(path_error!(IndexingError, path, "reading file metadata"))?;
+        let size = stat.len();
+        let modified = stat
+            .modified()
+            .chain_err(path_error!(
+                IndexingError,
+                path,
+                "reading modification time"
+            ))?
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos() as u64;
+
+        if let Some(entry) = self.entries.lock().unwrap().get(path) {
+            if entry.size == size && entry.modified == modified {
+                return Ok(entry.metadata.clone());
+            }
+        }
+
+        let path_moved = path.to_path_buf();
+        let hash = tokio::task::spawn_blocking(move || hash_file(&path_moved))
+            .await
+            .chain_err(path_error!(IndexingError, path, "hashing file off-thread"))?
+            .chain_err(path_error!(IndexingError, path, "hashing file"))?;
+
+        if let Some(entry) = self.entries.lock().unwrap().get_mut(path) {
+            if entry.size == size && entry.hash == hash {
+                trace!("{} was touched but not changed", path.to_string_lossy());
+                entry.modified = modified;
+                return Ok(entry.metadata.clone());
+            }
+        }
+
+        let metadata = probe().await?;
+        self.probed.fetch_add(1, Ordering::Relaxed);
+        self.entries.lock().unwrap().insert(
+            path.to_path_buf(),
+            ManifestEntry {
+                size,
+                modified,
+                hash,
+                metadata: metadata.clone(),
+            },
+        );
+
+        Ok(metadata)
+    }
+
+    /// Forgets the recorded metadata of files so they get probed again.
+    pub fn forget(&self, paths: &[PathBuf]) {
+        let mut entries = self.entries.lock().unwrap();
+        for path in paths {
+            entries.remove(path);
+        }
+    }
+
+    /// Gets the number of files that actually had to be probed.
+    pub fn probed(&self) -> u32 {
+        self.probed.load(Ordering::Relaxed)
+    }
+
+    /// Saves the manifest to the cache file, forgetting any files that
+    /// weren't seen since it was loaded.
+    pub async fn save(self) -> Result<()> {
+        let Manifest {
+            path,
+            entries,
+            seen,
+            ..
+        } = self;
+        let mut entries = entries.into_inner().unwrap();
+        let seen = seen.into_inner().unwrap();
+        entries.retain(|path, _| seen.contains(path));
+
+        let path_moved = path.clone();
+        tokio::task::spawn_blocking(move || {
+            Manifest::write(
+                &path_moved,
+                &ManifestFile {
+                    version: MANIFEST_VERSION,
+                    entries,
+                },
+            )
+        })
+        .await
+        .chain_err(path_error!(
+            IndexingError,
+            path,
+            "saving manifest off-thread"
+        ))?
+    }
+
+    /// Reads the entries of the manifest at `path`, if there is one of the
+    /// current version.
+    fn read(path: &Path) -> Result<Option<HashMap<PathBuf, ManifestEntry>>> {
+        if !path.exists() {
+            return Ok(None);
+        }
+
+        let file =
+            File::open(path).chain_err(path_error!(IndexingError, path, "opening manifest"))?;
+        let manifest: VersionedManifestFile = serde_json::from_reader(BufReader::new(file))
+            .chain_err(path_error!(IndexingError, path, "decoding manifest"))?;
+
+        if manifest.version != MANIFEST_VERSION {
+            info!("Discarding manifest from a different version.");
+            return Ok(None);
+        }
+
+        let entries = serde_json::from_value(manifest.entries).chain_err(path_error!(
+            IndexingError,
+            path,
+            "decoding manifest entries"
+        ))?;
+
+        Ok(Some(entries))
+    }
+
+    fn write(path: &Path, manifest: &ManifestFile) -> Result<()> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).chain_err(path_error!(
+                IndexingError,
+                parent,
+                "creating cache dir"
+            ))?;
+        }
+
+        let data = serde_json::to_vec(manifest).chain_err(path_error!(
+            IndexingError,
+            path,
+            "encoding manifest"
+        ))?;
+        write_atomic(path, &data).chain_err(path_error!(IndexingError, path, "writing manifest"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn manifests_of_other_versions_are_discarded() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(MANIFEST_FILE);
+
+        // entries of older versions don't have to decode as current ones
+        fs::write(
+            &path,
+            format!(
+                r#"{{"version":{},"entries":{{"/music/1.flac":{{"size":"big"}}}}}}"#,
+                MANIFEST_VERSION - 1
+            ),
+        )
+        .unwrap();
+        assert!(Manifest::read(&path).unwrap().is_none());
+
+        fs::write(
+            &path,
+            format!(r#"{{"version":{},"entries":{{}}}}"#, MANIFEST_VERSION),
+        )
+        .unwrap();
+        assert!(Manifest::read(&path).unwrap().unwrap().is_empty());
+    }
+}
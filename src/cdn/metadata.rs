@@ -0,0 +1,186 @@
+use serde::Deserialize;
+use std::{
+    collections::HashSet,
+    fs,
+    path::{Path, PathBuf},
+    result,
+};
+
+/// Name of the file in an album directory describing that album.
+pub const METADATA_FILE: &str = ".metadata.json";
+/// Name of the file in an album directory naming the image to use as the
+/// album's cover.
+pub const COVER_FILE: &str = ".cover";
+
+/// Authoritative album metadata read from a `.metadata.json` file, overriding
+/// the tags of the songs in the same directory.
+///
+/// With `subdirectories` set, the directory is an album boundary: songs in its
+/// subdirectories, like the disc folders of a box set, belong to the album too
+/// unless they have a metadata file of their own. Tracks in subdirectories are
+/// listed by their path relative to the metadata file, e.g. `"CD1/01.flac"`.
+/// `cover` names an image, relative to the metadata file, that is always used
+/// as the album's cover.
+///
+/// ```json
+/// {
+///   "album": "Album Name",
+///   "artists": ["First Artist", "Second Artist"],
+///   "year": 2001,
+///   "genre": "Rock",
+///   "cover": "front.jpg",
+///   "tracks": [
+///     { "file": "01 - first.flac", "title": "First Song" },
+///     { "file": "02 - second.flac", "artists": ["Guest Artist"] }
+///   ]
+/// }
+/// ```
+///
+/// Only `album` is required. The position of a file in `tracks` determines its
+/// track number. If `tracks` is given, songs in the directory that aren't
+/// listed keep their tagged title but get no track number.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct DirectoryMetadata {
+    pub album: String,
+    #[serde(default)]
+    pub artists: Vec<String>,
+    pub year: Option<u32>,
+    pub genre: Option<String>,
+    pub cover: Option<String>,
+    #[serde(default)]
+    pub subdirectories: bool,
+    #[serde(default)]
+    pub tracks: Vec<TrackMetadata>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TrackMetadata {
+    /// The song's path, relative to the metadata file's directory.
+    pub file: String,
+    pub title: Option<String>,
+    /// Overrides the album artists for this song.
+    #[serde(default)]
+    pub artists: Vec<String>,
+}
+
+impl DirectoryMetadata {
+    /// Loads the metadata file in `dir`. Returns `None` if there is no metadata
+    /// file or if it is invalid, in which case a warning is logged so that the
+    /// songs' tags get used instead.
+    pub fn load(dir: &Path) -> Option<DirectoryMetadata> {
+        let path = dir.join(METADATA_FILE);
+        if !path.is_file() {
+            return None;
+        }
+
+        let res = fs::read_to_string(&path)
+            .map_err(|e| e.to_string())
+            .and_then(|s| serde_json::from_str::<DirectoryMetadata>(&s).map_err(|e| e.to_string()))
+            .and_then(|metadata| metadata.validate().map(|_| metadata));
+
+        match res {
+            Ok(metadata) => {
+                debug!("Loaded album metadata: {}", path.to_string_lossy());
+                Some(metadata)
+            }
+            Err(e) => {
+                warn!(
+                    "Ignoring invalid album metadata {}: {}",
+                    path.to_string_lossy(),
+                    e
+                );
+                None
+            }
+        }
+    }
+
+    /// Finds the track number and metadata of a song file by its path relative
+    /// to the metadata file's directory, separated by `/`.
+    pub fn track(&self, file: &str) -> Option<(u32, &TrackMetadata)> {
+        self.tracks
+            .iter()
+            .enumerate()
+            .find(|(_, track)| track.file == file)
+            .map(|(index, track)| (index as u32 + 1, track))
+    }
+
+    fn validate(&self) -> result::Result<(), String> {
+        if self.album.trim().is_empty() {
+            return Err("album must not be empty".to_string());
+        }
+
+        let artists = self
+            .artists
+            .iter()
+            .chain(self.tracks.iter().flat_map(|track| track.artists.iter()));
+        for artist in artists {
+            if artist.trim().is_empty() {
+                return Err("artists must not be empty".to_string());
+            }
+        }
+
+        if let Some(cover) = &self.cover {
+            if !is_relative_file(cover, true) {
+                return Err(format!("cover '{}' is not a relative file path", cover));
+            }
+        }
+
+        let mut files = HashSet::new();
+        for track in self.tracks.iter() {
+            if !is_relative_file(&track.file, self.subdirectories) {
+                return Err(format!("'{}' is not a file name", track.file));
+            }
+
+            if !files.insert(&track.file) {
+                return Err(format!("'{}' is listed more than once", track.file));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Reads the `.cover` file in `dir`, which contains the file name of the cover
+/// image relative to `dir`. Returns `None` if there is no such file or if it
+/// names an image that doesn't exist, in which case a warning is logged so that
+/// the covers get rated instead.
+pub fn load_cover_file(dir: &Path) -> Option<PathBuf> {
+    let path = dir.join(COVER_FILE);
+    if !path.is_file() {
+        return None;
+    }
+
+    let name = match fs::read_to_string(&path) {
+        Ok(contents) => contents.trim().to_string(),
+        Err(e) => {
+            warn!("Ignoring unreadable {}: {}", path.to_string_lossy(), e);
+            return None;
+        }
+    };
+
+    let cover = dir.join(&name);
+    if !is_relative_file(&name, true) || !cover.is_file() {
+        warn!(
+            "Ignoring {}, '{}' is not a cover file in its directory",
+            path.to_string_lossy(),
+            name
+        );
+        return None;
+    }
+
+    debug!("Loaded cover override: {}", path.to_string_lossy());
+    Some(cover)
+}
+
+/// Checks that `file` names a file in the metadata file's directory or, if
+/// `nested` is set, one of its subdirectories.
+fn is_relative_file(file: &str, nested: bool) -> bool {
+    if file.contains('\\') || (!nested && file.contains('/')) {
+        return false;
+    }
+
+    file.split('/')
+        .all(|part| !part.is_empty() && part != "." && part != "..")
+}
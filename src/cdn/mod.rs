@@ -1,11 +1,38 @@
+pub mod caching;
+pub mod covers;
 pub mod files;
 pub mod index;
+pub mod manifest;
+pub mod metadata;
+pub mod names;
+pub mod ratings;
+pub mod transcode;
+pub mod watch;
 
-use crate::config::Config;
-use actix_web::{web, Scope};
+use crate::{config::Config, logging::access::NotFoundLogger};
+use actix_service::ServiceFactory;
+use actix_web::{
+    dev::{ServiceRequest, ServiceResponse},
+    web, Scope,
+};
 
-pub fn apply_services(config: &Config) -> Scope {
+/// Where generated covers written to `cover-cache-dir` are served.
+pub const GENERATED_COVERS_URL: &str = "/cdn/generated-covers";
+
+pub fn apply_services(
+    config: &Config,
+) -> Scope<
+    impl ServiceFactory<
+        Config = (),
+        Request = ServiceRequest,
+        Response = ServiceResponse,
+        Error = actix_web::Error,
+        InitError = (),
+    >,
+> {
     web::scope("/cdn")
-        .service(index::apply_services())
+        .wrap(NotFoundLogger::new(config.cdn_not_found_log_level))
+        .service(index::apply_services(config))
         .service(files::apply_services(config))
+        .service(files::apply_generated_covers(config))
 }
@@ -0,0 +1,160 @@
+use crate::{
+    config::Config,
+    error::{Result, ResultExt},
+    path_error,
+    util::fs::write_atomic,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    fs,
+    path::{Path, PathBuf},
+};
+
+const NAMES_FILE: &str = "unique-names.json";
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct NamesFile {
+    albums: HashMap<String, String>,
+    artists: HashMap<String, String>,
+}
+
+/// Remembers the unique names assigned to albums and artists so they keep the
+/// same unique name across restarts, even when names that sanitize to the same
+/// unique name are discovered in a different order.
+///
+/// Does nothing unless `stable-unique-names` is enabled.
+#[derive(Debug)]
+pub struct UniqueNames {
+    path: Option<PathBuf>,
+    names: NamesFile,
+    // unique name -> name
+    album_owners: HashMap<String, String>,
+    artist_owners: HashMap<String, String>,
+}
+
+impl UniqueNames {
+    /// Loads the stored unique names from the cache directory.
+    pub fn load(config: &Config) -> UniqueNames {
+        if !config.stable_unique_names {
+            return UniqueNames {
+                path: None,
+                names: Default::default(),
+                album_owners: Default::default(),
+                artist_owners: Default::default(),
+            };
+        }
+
+        let path = config.cache_dir.join(NAMES_FILE);
+        let names = match UniqueNames::read(&path) {
+            Ok(names) => names,
+            Err(e) => {
+                warn!("Unable to load unique names, assigning new ones: {}", e);
+                Default::default()
+            }
+        };
+
+        let invert = |map: &HashMap<String, String>| {
+            map.iter()
+                .map(|(name, unique_name)| (unique_name.clone(), name.clone()))
+                .collect()
+        };
+
+        UniqueNames {
+            path: Some(path),
+            album_owners: invert(&names.albums),
+            artist_owners: invert(&names.artists),
+            names,
+        }
+    }
+
+    /// Gets the unique name previously assigned to the album called `name`.
+    pub fn album(&self, name: &str) -> Option<&str> {
+        self.names.albums.get(name).map(|n| n.as_str())
+    }
+
+    /// Checks whether `unique_name` is kept for an album other than `name`.
+    /// Casing variants of an album name are the same album.
+    pub fn album_reserved(&self, unique_name: &str, name: &str) -> bool {
+        self.album_owners
+            .get(unique_name)
+            .map_or(false, |owner| owner.to_lowercase() != name.to_lowercase())
+    }
+
+    pub fn insert_album(&mut self, name: &str, unique_name: &str) {
+        if self.path.is_some() {
+            self.names
+                .albums
+                .insert(name.to_string(), unique_name.to_string());
+            self.album_owners
+                .insert(unique_name.to_string(), name.to_string());
+        }
+    }
+
+    /// Gets the unique name previously assigned to the artist called `name`.
+    pub fn artist(&self, name: &str) -> Option<&str> {
+        self.names.artists.get(name).map(|n| n.as_str())
+    }
+
+    /// Checks whether `unique_name` is kept for an artist other than `name`.
+    pub fn artist_reserved(&self, unique_name: &str, name: &str) -> bool {
+        self.artist_owners
+            .get(unique_name)
+            .map_or(false, |owner| owner != name)
+    }
+
+    pub fn insert_artist(&mut self, name: &str, unique_name: &str) {
+        if self.path.is_some() {
+            self.names
+                .artists
+                .insert(name.to_string(), unique_name.to_string());
+            self.artist_owners
+                .insert(unique_name.to_string(), name.to_string());
+        }
+    }
+
+    /// Saves the unique names to the cache directory.
+    ///
+    /// Names of albums and artists that are gone are kept, so they get their
+    /// old unique names back if they reappear.
+    pub fn save(&self) -> Result<()> {
+        let path = match &self.path {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).chain_err(path_error!(
+                IndexingError,
+                parent,
+                "creating cache dir"
+            ))?;
+        }
+
+        let data = serde_json::to_vec(&self.names).chain_err(path_error!(
+            IndexingError,
+            path,
+            "encoding unique names"
+        ))?;
+
+        write_atomic(path, &data).chain_err(path_error!(
+            IndexingError,
+            path,
+            "writing unique names"
+        ))
+    }
+
+    fn read(path: &Path) -> Result<NamesFile> {
+        if !path.exists() {
+            return Ok(Default::default());
+        }
+
+        let data =
+            fs::read(path).chain_err(path_error!(IndexingError, path, "reading unique names"))?;
+        serde_json::from_slice(&data).chain_err(path_error!(
+            IndexingError,
+            path,
+            "decoding unique names"
+        ))
+    }
+}
@@ -0,0 +1,193 @@
+use crate::{
+    config::Config,
+    error::{Result, ResultExt},
+    path_error,
+    util::fs::write_atomic,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    fs,
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::SystemTime,
+};
+
+const RATINGS_FILE: &str = "ratings.json";
+/// Ratings go from 0 to this many stars.
+pub const MAX_RATING: u8 = 5;
+
+/// The rating and favorite flag of a song or album.
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct Rating {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rating: Option<u8>,
+    #[serde(default)]
+    pub favorite: bool,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct RatingsFile {
+    /// Keyed by `album/song` unique names.
+    songs: HashMap<String, Rating>,
+    /// Keyed by album unique name.
+    albums: HashMap<String, Rating>,
+    /// Counts changes, so responses including ratings can tell them apart.
+    /// Saved along with the ratings so it keeps counting after a restart.
+    #[serde(default)]
+    revision: u64,
+}
+
+/// Stores the ratings and favorite flags of songs and albums in the cache
+/// directory.
+///
+/// Ratings are keyed by unique name, so they survive reindexing. Ratings of
+/// songs and albums that are gone are kept, so they come back if the songs
+/// reappear.
+#[derive(Debug)]
+pub struct Ratings {
+    path: Option<PathBuf>,
+    ratings: Mutex<RatingsFile>,
+    modified: Mutex<Option<SystemTime>>,
+    saving: tokio::sync::Mutex<()>,
+}
+
+impl Ratings {
+    /// Loads the stored ratings from the cache directory.
+    pub fn load(config: &Config) -> Ratings {
+        let path = config.cache_dir.join(RATINGS_FILE);
+        let ratings = match Ratings::read(&path) {
+            Ok(ratings) => ratings,
+            Err(e) => {
+                warn!("Unable to load ratings, starting without any: {}", e);
+                Default::default()
+            }
+        };
+
+        // the file is saved on every change
+        let modified = path.metadata().and_then(|m| m.modified()).ok();
+        let ratings = Ratings::new(Some(path), ratings);
+        *ratings.modified.lock().unwrap() = modified;
+        ratings
+    }
+
+    /// Creates a store without any ratings that is never saved.
+    pub fn empty() -> Ratings {
+        Ratings::new(None, Default::default())
+    }
+
+    fn new(path: Option<PathBuf>, ratings: RatingsFile) -> Ratings {
+        Ratings {
+            path,
+            ratings: Mutex::new(ratings),
+            modified: Mutex::new(None),
+            saving: tokio::sync::Mutex::new(()),
+        }
+    }
+
+    pub fn song(&self, album_name: &str, song_name: &str) -> Rating {
+        let key = song_key(album_name, song_name);
+        let ratings = self.ratings.lock().unwrap();
+        ratings.songs.get(&key).cloned().unwrap_or_default()
+    }
+
+    pub fn album(&self, album_name: &str) -> Rating {
+        let ratings = self.ratings.lock().unwrap();
+        ratings.albums.get(album_name).cloned().unwrap_or_default()
+    }
+
+    /// Changes the rating of a song and saves all ratings.
+    pub async fn update_song<F>(&self, album_name: &str, song_name: &str, f: F) -> Result<Rating>
+    where
+        F: FnOnce(&mut Rating),
+    {
+        let rating = {
+            let mut ratings = self.ratings.lock().unwrap();
+            let rating = ratings
+                .songs
+                .entry(song_key(album_name, song_name))
+                .or_default();
+            f(rating);
+            rating.clone()
+        };
+
+        self.changed().await?;
+        Ok(rating)
+    }
+
+    /// Changes the rating of an album and saves all ratings.
+    pub async fn update_album<F>(&self, album_name: &str, f: F) -> Result<Rating>
+    where
+        F: FnOnce(&mut Rating),
+    {
+        let rating = {
+            let mut ratings = self.ratings.lock().unwrap();
+            let rating = ratings.albums.entry(album_name.to_string()).or_default();
+            f(rating);
+            rating.clone()
+        };
+
+        self.changed().await?;
+        Ok(rating)
+    }
+
+    pub fn revision(&self) -> u64 {
+        self.ratings.lock().unwrap().revision
+    }
+
+    /// When a rating last changed, if any ever changed.
+    pub fn modified(&self) -> Option<SystemTime> {
+        *self.modified.lock().unwrap()
+    }
+
+    async fn changed(&self) -> Result<()> {
+        self.ratings.lock().unwrap().revision += 1;
+        *self.modified.lock().unwrap() = Some(SystemTime::now());
+
+        let path = match &self.path {
+            Some(path) => path.clone(),
+            None => return Ok(()),
+        };
+
+        // saves are serialized so an older snapshot never overwrites a newer one
+        let _guard = self.saving.lock().await;
+        let data = {
+            let ratings = self.ratings.lock().unwrap();
+            serde_json::to_vec(&*ratings).chain_err(path_error!(
+                RatingsError,
+                path,
+                "encoding ratings"
+            ))?
+        };
+
+        let path_moved = path.clone();
+        tokio::task::spawn_blocking(move || Ratings::write(&path_moved, data))
+            .await
+            .chain_err(path_error!(RatingsError, path, "saving ratings off-thread"))?
+    }
+
+    fn write(path: &Path, data: Vec<u8>) -> Result<()> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).chain_err(path_error!(
+                RatingsError,
+                parent,
+                "creating cache dir"
+            ))?;
+        }
+
+        write_atomic(path, &data).chain_err(path_error!(RatingsError, path, "writing ratings"))
+    }
+
+    fn read(path: &Path) -> Result<RatingsFile> {
+        if !path.exists() {
+            return Ok(Default::default());
+        }
+
+        let data = fs::read(path).chain_err(path_error!(RatingsError, path, "reading ratings"))?;
+        serde_json::from_slice(&data).chain_err(path_error!(RatingsError, path, "decoding ratings"))
+    }
+}
+
+fn song_key(album_name: &str, song_name: &str) -> String {
+    format!("{}/{}", album_name, song_name)
+}
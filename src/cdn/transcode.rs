@@ -0,0 +1,420 @@
+use crate::{
+    config::Config,
+    error::{ErrorKind, Result, ResultExt},
+    path_error,
+    util::{
+        ffmpeg,
+        ffmpeg::{transcode_audio, TranscodeOptions},
+    },
+};
+use actix_files::NamedFile;
+use actix_web::web::Bytes;
+use error_chain::ChainedError;
+use futures::{channel::mpsc, executor, SinkExt};
+use regex::Regex;
+use sha2::{Digest, Sha256};
+use std::{
+    collections::HashMap,
+    fs,
+    fs::File,
+    io,
+    io::Read,
+    path::{Path, PathBuf},
+    sync::Arc,
+    thread,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tokio::sync::{Mutex, Semaphore};
+
+const TRANSCODES_DIR: &str = "transcodes";
+const HLS_DIR: &str = "hls";
+pub const HLS_PLAYLIST: &str = "index.m3u8";
+const STREAM_CHUNK_SIZE: usize = 64 * 1024;
+/// Number of chunks read ahead of a slow client before transcoding pauses.
+const STREAM_BUFFER_CHUNKS: usize = 4;
+
+lazy_static::lazy_static! {
+static ref HLS_SEGMENT_PATTERN: Regex = Regex::new("^segment\\d+\\.ts$").unwrap();
+}
+
+/// Manages transcoded versions of songs stored in the cache directory.
+///
+/// Transcodes are keyed by the song's path and modification time, so editing a
+/// song causes it to be transcoded again. Entries older than the configured
+/// max age are removed whenever a new transcode job starts.
+pub struct TranscodeCache {
+    dir: PathBuf,
+    max_age: Duration,
+    jobs: Semaphore,
+    locks: Mutex<HashMap<PathBuf, Arc<Mutex<()>>>>,
+    hls_segment_duration: u32,
+    hls_bit_rate: usize,
+    live: Arc<Semaphore>,
+    max_live: usize,
+}
+
+impl TranscodeCache {
+    pub fn new(config: &Config) -> TranscodeCache {
+        TranscodeCache {
+            dir: config.cache_dir.join(TRANSCODES_DIR),
+            max_age: Duration::from_secs(config.transcode_cache_max_age),
+            jobs: Semaphore::new(config.max_transcode_jobs.max(1)),
+            locks: Default::default(),
+            hls_segment_duration: config.hls_segment_duration,
+            hls_bit_rate: config.hls_bit_rate * 1000,
+            live: Arc::new(Semaphore::new(config.max_live_transcodes.max(1))),
+            max_live: config.max_live_transcodes.max(1),
+        }
+    }
+
+    /// Gets the directory holding an HLS rendition of a song, transcoding the
+    /// song if no up-to-date rendition exists yet.
+    pub async fn hls(&self, song_path: &Path) -> Result<PathBuf> {
+        let output = self.entry_dir(song_path)?.join(HLS_DIR);
+        let song_path = song_path.to_path_buf();
+        let segment_duration = self.hls_segment_duration;
+        let bit_rate = self.hls_bit_rate;
+
+        self.produce(&output, move |tmp| {
+            fs::create_dir_all(tmp).chain_err(path_error!(
+                TranscodeError,
+                tmp,
+                "creating hls directory"
+            ))?;
+
+            let options = TranscodeOptions {
+                format: "hls".to_string(),
+                encoder: "aac".to_string(),
+                bit_rate,
+                muxer_options: vec![
+                    ("hls_time".to_string(), segment_duration.to_string()),
+                    ("hls_playlist_type".to_string(), "vod".to_string()),
+                    (
+                        "hls_segment_filename".to_string(),
+                        tmp.join("segment%03d.ts").to_string_lossy().to_string(),
+                    ),
+                ],
+            };
+
+            transcode_audio(&song_path, &tmp.join(HLS_PLAYLIST), &options)
+        })
+        .await
+    }
+
+    /// Checks whether an up-to-date HLS rendition of a song is cached, without
+    /// transcoding it.
+    pub fn has_hls(&self, song_path: &Path) -> bool {
+        self.entry_dir(song_path)
+            .map(|dir| dir.join(HLS_DIR).is_dir())
+            .unwrap_or(false)
+    }
+
+    /// HLS audio bit rate in bit/s.
+    pub fn hls_bit_rate(&self) -> u64 {
+        self.hls_bit_rate as u64
+    }
+
+    /// Opens a file from an HLS rendition directory. Only the playlist and
+    /// segment files can be opened.
+    pub fn hls_file(&self, hls_dir: &Path, file_name: &str) -> Result<NamedFile> {
+        let content_type = if file_name == HLS_PLAYLIST {
+            "application/vnd.apple.mpegurl"
+        } else if HLS_SEGMENT_PATTERN.is_match(file_name) {
+            "video/mp2t"
+        } else {
+            bail!(ErrorKind::NoSuchResource("hls file", file_name.to_string()));
+        };
+
+        let path = hls_dir.join(file_name);
+        if !path.is_file() {
+            bail!(ErrorKind::NoSuchResource("hls file", file_name.to_string()));
+        }
+
+        Ok(NamedFile::open(&path)
+            .chain_err(path_error!(TranscodeError, path, "opening transcoded file"))?
+            .set_content_type(content_type.parse().unwrap()))
+    }
+
+    /// Transcodes a song to `format` at `bit_rate` bit/s, returning the
+    /// transcoded data as it is produced instead of caching it.
+    ///
+    /// ffmpeg writes into a pipe which is read on another thread. When the
+    /// stream is dropped, the pipe closes and the transcode fails on its next
+    /// write, so abandoned streams stop transcoding. Streams last as long as
+    /// the client listens, so they run on their own threads instead of the
+    /// ffmpeg pool, and fail when all `max-live-transcodes` are in use.
+    /// Streaming needs a unix pipe, so it always fails on other platforms.
+    pub fn stream(
+        &self,
+        song_path: &Path,
+        format: StreamFormat,
+        bit_rate: usize,
+    ) -> Result<mpsc::Receiver<Result<Bytes>>> {
+        let permit = self
+            .live
+            .clone()
+            .try_acquire_owned()
+            .map_err(|_| ErrorKind::TooManyTranscodes(self.max_live))?;
+
+        let (mut reader, writer, output) = TranscodeCache::pipe(song_path)?;
+
+        let (mut tx, rx) = mpsc::channel(STREAM_BUFFER_CHUNKS);
+
+        let mut job_tx = tx.clone();
+        let input = song_path.to_path_buf();
+        let options = format.options(bit_rate);
+        thread::Builder::new()
+            .name("transcode".to_string())
+            .spawn(move || {
+                let res = transcode_audio(&input, &output, &options);
+                // closing the write end ends the stream
+                drop(writer);
+                drop(permit);
+
+                if let Err(e) = res {
+                    // failing to send means the client is gone, which also
+                    // fails the transcode
+                    let msg = e.display_chain().to_string();
+                    if executor::block_on(job_tx.send(Err(e))).is_ok() {
+                        warn!("{}", msg);
+                    } else {
+                        debug!("Stopped abandoned transcode: {}", msg);
+                    }
+                }
+            })
+            .chain_err(path_error!(
+                TranscodeError,
+                song_path,
+                "starting transcode thread"
+            ))?;
+
+        let path = song_path.to_path_buf();
+        thread::spawn(move || {
+            let mut buf = vec![0; STREAM_CHUNK_SIZE];
+            loop {
+                let chunk = match reader.read(&mut buf) {
+                    Ok(0) => break,
+                    Ok(len) => Ok(Bytes::copy_from_slice(&buf[..len])),
+                    Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                    Err(e) => Err(e).chain_err(path_error!(
+                        TranscodeError,
+                        path,
+                        "reading transcode pipe"
+                    )),
+                };
+
+                let failed = chunk.is_err();
+                if executor::block_on(tx.send(chunk)).is_err() || failed {
+                    break;
+                }
+            }
+        });
+
+        Ok(rx)
+    }
+
+    /// Creates a pipe, returning its read and write ends and the url ffmpeg
+    /// writes to the write end with. The pipe's ends are closed when the files
+    /// are dropped.
+    #[cfg(unix)]
+    fn pipe(song_path: &Path) -> Result<(File, File, PathBuf)> {
+        use std::os::unix::io::{AsRawFd, FromRawFd};
+
+        let mut fds = [0 as libc::c_int; 2];
+        if unsafe { libc::pipe(fds.as_mut_ptr()) } != 0 {
+            return Err(io::Error::last_os_error()).chain_err(path_error!(
+                TranscodeError,
+                song_path,
+                "creating transcode pipe"
+            ));
+        }
+        let reader = unsafe { File::from_raw_fd(fds[0]) };
+        let writer = unsafe { File::from_raw_fd(fds[1]) };
+        // ffmpeg's pipe protocol writes to a file descriptor without closing it
+        let output = PathBuf::from(format!("pipe:{}", writer.as_raw_fd()));
+
+        Ok((reader, writer, output))
+    }
+
+    #[cfg(not(unix))]
+    fn pipe(_song_path: &Path) -> Result<(File, File, PathBuf)> {
+        bail!(ErrorKind::LiveTranscodesUnsupported)
+    }
+
+    /// Gets the cache directory for all transcodes of a song.
+    fn entry_dir(&self, song_path: &Path) -> Result<PathBuf> {
+        let modified = song_path
+            .metadata()
+            .and_then(|m| m.modified())
+            .chain_err(path_error!(
+                TranscodeError,
+                song_path,
+                "reading modification time"
+            ))?
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+
+        let mut hasher = Sha256::new();
+        hasher.update(song_path.to_string_lossy().as_bytes());
+        hasher.update(modified.as_nanos().to_le_bytes());
+
+        Ok(self.dir.join(format!("{:x}", hasher.finalize())))
+    }
+
+    /// Makes sure `output` exists, running `job` to create it if it does not.
+    ///
+    /// The job is handed a temporary path to write to, which is renamed to
+    /// `output` once the job completes, so partially transcoded files are never
+    /// served. Only one job runs per output at a time, and the total number of
+    /// running jobs is bounded.
+    async fn produce<F>(&self, output: &Path, job: F) -> Result<PathBuf>
+    where
+        F: FnOnce(&Path) -> Result<()> + Send + 'static,
+    {
+        let lock = {
+            let mut locks = self.locks.lock().await;
+            locks.retain(|_, lock| Arc::strong_count(lock) > 1);
+            locks
+                .entry(output.to_path_buf())
+                .or_insert_with(Default::default)
+                .clone()
+        };
+        let _guard = lock.lock().await;
+
+        if output.exists() {
+            return Ok(output.to_path_buf());
+        }
+
+        let _permit = self.jobs.acquire().await;
+
+        let output_moved = output.to_path_buf();
+        let dir = self.dir.clone();
+        let max_age = self.max_age;
+        let res: Result<_> = ffmpeg::spawn_blocking(move || {
+            TranscodeCache::clean(&dir, max_age);
+
+            let parent = output_moved
+                .parent()
+                .expect("BUG: Transcode output has no parent");
+            fs::create_dir_all(parent).chain_err(path_error!(
+                TranscodeError,
+                parent,
+                "creating cache directory"
+            ))?;
+
+            let tmp = output_moved.with_file_name(format!(
+                ".{}.tmp",
+                output_moved
+                    .file_name()
+                    .expect("BUG: Transcode output has no file name")
+                    .to_string_lossy()
+            ));
+            TranscodeCache::remove(&tmp);
+
+            if let Err(e) = job(&tmp) {
+                TranscodeCache::remove(&tmp);
+                return Err(e);
+            }
+
+            fs::rename(&tmp, &output_moved).chain_err(path_error!(
+                TranscodeError,
+                output_moved,
+                "moving transcode into place"
+            ))
+        })
+        .await
+        .chain_err(path_error!(
+            TranscodeError,
+            output,
+            "running transcode off-thread"
+        ))?;
+        res?;
+
+        Ok(output.to_path_buf())
+    }
+
+    /// Removes cache entries that have not been modified within `max_age`.
+    fn clean(dir: &Path, max_age: Duration) {
+        let entries = match fs::read_dir(dir) {
+            Ok(entries) => entries,
+            Err(_) => return,
+        };
+
+        for entry in entries.filter_map(|e| e.ok()) {
+            let stale = entry
+                .metadata()
+                .and_then(|m| m.modified())
+                .ok()
+                .and_then(|modified| SystemTime::now().duration_since(modified).ok())
+                .map_or(false, |age| age > max_age);
+
+            if stale {
+                debug!(
+                    "Removing stale transcode cache: {}",
+                    entry.path().to_string_lossy()
+                );
+                TranscodeCache::remove(&entry.path());
+            }
+        }
+    }
+
+    fn remove(path: &Path) {
+        let res = if path.is_dir() {
+            fs::remove_dir_all(path)
+        } else if path.exists() {
+            fs::remove_file(path)
+        } else {
+            Ok(())
+        };
+
+        if let Err(e) = res {
+            warn!("Error removing {}: {}", path.to_string_lossy(), e);
+        }
+    }
+}
+
+/// The formats songs can be streamed as while they are being transcoded.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum StreamFormat {
+    Mp3,
+    Opus,
+}
+
+impl StreamFormat {
+    pub fn parse(name: &str) -> Option<StreamFormat> {
+        match name {
+            "mp3" => Some(StreamFormat::Mp3),
+            "opus" => Some(StreamFormat::Opus),
+            _ => None,
+        }
+    }
+
+    pub fn content_type(self) -> &'static str {
+        match self {
+            StreamFormat::Mp3 => "audio/mpeg",
+            StreamFormat::Opus => "audio/ogg",
+        }
+    }
+
+    /// Bit rate in kbit/s used when a client doesn't ask for one.
+    pub fn default_bit_rate(self) -> usize {
+        match self {
+            StreamFormat::Mp3 => 192,
+            StreamFormat::Opus => 96,
+        }
+    }
+
+    fn options(self, bit_rate: usize) -> TranscodeOptions {
+        let (format, encoder) = match self {
+            StreamFormat::Mp3 => ("mp3", "libmp3lame"),
+            StreamFormat::Opus => ("ogg", "libopus"),
+        };
+
+        TranscodeOptions {
+            format: format.to_string(),
+            encoder: encoder.to_string(),
+            bit_rate,
+            muxer_options: vec![],
+        }
+    }
+}
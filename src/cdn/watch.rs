@@ -0,0 +1,69 @@
+use crate::{
+    cdn::index::IndexHandle,
+    config::Config,
+    error::{ErrorKind, Result, ResultExt},
+};
+use actix_web::web;
+use error_chain::ChainedError;
+use notify::{DebouncedEvent, RecursiveMode, Watcher};
+use std::{sync::mpsc, thread, time::Duration};
+use tokio::sync::mpsc as async_mpsc;
+
+/// Watches the music directory and reindexes the library when files in it
+/// change.
+///
+/// Changes are collected until none have happened for `watch-delay`, so a
+/// bulk copy only causes one reindex, and it happens once the copied files are
+/// complete. Unchanged files are looked up in the manifest while reindexing, so
+/// only the files that changed are probed.
+pub fn start(config: &Config, index: web::Data<IndexHandle>) -> Result<()> {
+    let delay = Duration::from_millis(config.watch_delay);
+
+    let (tx, rx) = mpsc::channel();
+    let mut watcher = notify::watcher(tx, delay)
+        .chain_err(|| ErrorKind::IndexingError(None, "starting file watcher"))?;
+    watcher
+        .watch(&config.base_dir, RecursiveMode::Recursive)
+        .chain_err(|| {
+            ErrorKind::IndexingError(
+                Some(config.base_dir.to_string_lossy().to_string()),
+                "watching music directory",
+            )
+        })?;
+
+    info!("Watching {}", config.base_dir.to_string_lossy());
+
+    // notify delivers events over a blocking channel, so they are forwarded to
+    // the runtime from their own thread
+    let (async_tx, mut async_rx) = async_mpsc::unbounded_channel();
+    thread::spawn(move || {
+        // the watcher stops when it is dropped
+        let _watcher = watcher;
+        for event in rx {
+            if async_tx.send(event).is_err() {
+                break;
+            }
+        }
+    });
+
+    actix_web::rt::spawn(async move {
+        while let Some(event) = async_rx.recv().await {
+            let mut events = vec![event];
+            while let Ok(Some(event)) = tokio::time::timeout(delay, async_rx.recv()).await {
+                events.push(event);
+            }
+
+            debug!("Received {} file system events", events.len());
+            match index.apply_fs_events(&events).await {
+                Ok(true) => info!("Reindexed after file system changes"),
+                Ok(false) => {}
+                Err(e) => warn!(
+                    "Error reindexing after file system changes: {}",
+                    e.display_chain()
+                ),
+            }
+        }
+    });
+
+    Ok(())
+}
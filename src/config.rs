@@ -1,18 +1,49 @@
 use crate::error::{ErrorKind::ConfigLoadError, Result, ResultExt};
-use regex::RegexSet;
+use actix_web::http::{header::InvalidHeaderValue, HeaderValue};
+use log::{Level, LevelFilter};
+use regex::{Regex, RegexSet};
 use serde::{Deserialize, Serialize};
 use std::{
+    collections::BTreeMap,
+    env,
+    ffi::OsString,
     fs::{File, OpenOptions},
     io::{Read, Write},
+    net::SocketAddr,
     path::{Path, PathBuf},
+    result,
+    str::FromStr,
 };
+use toml::Value;
 
 const CONFIG_FILE_NAME: &str = "music-server-1.toml";
+const CONFIG_DIR_NAME: &str = "music-server-1";
+const SYSTEM_CONFIG_DIR: &str = "/etc/music-server-1";
+const CONFIG_FLAG: &str = "--config";
+const CONFIG_ENV: &str = "MUSIC_SERVER_CONFIG";
+/// Prefix of env vars overriding individual keys of the `general` table.
+const OVERRIDE_ENV_PREFIX: &str = "MUSIC_SERVER_";
+/// String keys of the `general` table without a default, which are missing from
+/// the encoded defaults that overrides are checked against.
+const OPTIONAL_STRING_KEYS: [&str; 2] = ["files-cover-cache-control", "files-audio-cache-control"];
+/// Origin of `ng serve`, which is allowed to use the api in debug builds.
+const DEV_SERVER_ORIGIN: &str = "http://localhost:4200";
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
 struct ConfigRaw {
     #[serde(default)]
     general: ConfigGeneral,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    tls: Option<ConfigTls>,
+}
+
+/// The certificate and private key served on TLS bindings.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+struct ConfigTls {
+    /// PEM file containing the certificate chain.
+    cert: String,
+    /// PEM file containing the certificate's private key.
+    key: String,
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
@@ -26,6 +57,8 @@ struct ConfigGeneral {
     media_include_patterns: Vec<String>,
     #[serde(rename = "media-exclude-patterns", default)]
     media_exclude_patterns: Vec<String>,
+    #[serde(rename = "media-extensions", default)]
+    media_extensions: Vec<String>,
     #[serde(
         rename = "cover-include-patterns",
         default = "default_cover_include_patterns"
@@ -33,8 +66,143 @@ struct ConfigGeneral {
     cover_include_patterns: Vec<String>,
     #[serde(rename = "cover-exclude-patterns", default)]
     cover_exclude_patterns: Vec<String>,
+    #[serde(rename = "cover-extensions", default)]
+    cover_extensions: Vec<String>,
+    #[serde(
+        rename = "document-include-patterns",
+        default = "default_document_include_patterns"
+    )]
+    document_include_patterns: Vec<String>,
+    #[serde(rename = "document-exclude-patterns", default)]
+    document_exclude_patterns: Vec<String>,
+    #[serde(rename = "cover-priority", default)]
+    cover_priority: Vec<String>,
+    #[serde(rename = "cover-tiebreak", default)]
+    cover_tiebreak: CoverTiebreak,
+    #[serde(rename = "duplicate-tracks", default)]
+    duplicate_tracks: DuplicateTracks,
+    #[serde(rename = "shared-directory-covers", default)]
+    shared_directory_covers: SharedDirectoryCovers,
+    #[serde(rename = "generated-cover-format", default)]
+    generated_cover_format: GeneratedCoverFormat,
+    #[serde(rename = "prefer-embedded-covers", default)]
+    prefer_embedded_covers: bool,
+    #[serde(rename = "artist-covers", default = "default_artist_covers")]
+    artist_covers: Vec<String>,
+    #[serde(rename = "artist-cover-fallback", default)]
+    artist_cover_fallback: bool,
+    #[serde(rename = "artist-grouping", default)]
+    artist_grouping: ArtistGrouping,
+    #[serde(rename = "artist-directory-depth", default)]
+    artist_directory_depth: usize,
+    #[serde(rename = "unknown-title", default = "default_unknown_label")]
+    unknown_title: String,
+    #[serde(rename = "unknown-album", default = "default_unknown_label")]
+    unknown_album: String,
+    #[serde(rename = "unknown-artist", default = "default_unknown_label")]
+    unknown_artist: String,
+    #[serde(rename = "directory-tag-fallback", default)]
+    directory_tag_fallback: bool,
+    #[serde(
+        rename = "artist-split-pattern",
+        default = "default_artist_split_pattern"
+    )]
+    artist_split_pattern: String,
+    #[serde(rename = "artist-split-exceptions", default)]
+    artist_split_exceptions: Vec<String>,
+    #[serde(rename = "artist-articles", default)]
+    artist_articles: Vec<String>,
+    #[serde(rename = "url-scheme", default)]
+    url_scheme: UrlScheme,
+    #[serde(
+        rename = "cdn-not-found-log-level",
+        default = "default_cdn_not_found_log_level"
+    )]
+    cdn_not_found_log_level: String,
+    #[serde(rename = "ffmpeg-threads", default = "default_ffmpeg_threads")]
+    ffmpeg_threads: usize,
+    #[serde(rename = "index-concurrency", default = "default_index_concurrency")]
+    index_concurrency: usize,
+    #[serde(rename = "probe-retries", default = "default_probe_retries")]
+    probe_retries: u32,
+    #[serde(rename = "probe-retry-delay", default = "default_probe_retry_delay")]
+    probe_retry_delay: u64,
+    #[serde(rename = "cache-dir", default = "default_cache_dir")]
+    cache_dir: String,
+    #[serde(rename = "cache-file", default)]
+    cache_file: String,
+    #[serde(rename = "cover-cache-dir", default)]
+    cover_cache_dir: String,
+    #[serde(rename = "max-transcode-jobs", default = "default_max_transcode_jobs")]
+    max_transcode_jobs: usize,
+    #[serde(
+        rename = "max-live-transcodes",
+        default = "default_max_live_transcodes"
+    )]
+    max_live_transcodes: usize,
+    #[serde(
+        rename = "transcode-cache-max-age",
+        default = "default_transcode_cache_max_age"
+    )]
+    transcode_cache_max_age: u64,
+    #[serde(
+        rename = "hls-segment-duration",
+        default = "default_hls_segment_duration"
+    )]
+    hls_segment_duration: u32,
+    #[serde(rename = "hls-bitrate", default = "default_hls_bit_rate")]
+    hls_bit_rate: usize,
+    #[serde(rename = "strict-indexing", default)]
+    strict_indexing: bool,
+    #[serde(rename = "stable-unique-names", default)]
+    stable_unique_names: bool,
+    #[serde(
+        rename = "index-all-max-songs",
+        default = "default_index_all_max_songs"
+    )]
+    index_all_max_songs: usize,
+    #[serde(rename = "min-album-songs", default = "default_min_album_songs")]
+    min_album_songs: usize,
+    #[serde(rename = "group-singles", default)]
+    group_singles: bool,
+    #[serde(default)]
+    debug: bool,
+    #[serde(rename = "expose-file-paths", default)]
+    expose_file_paths: bool,
+    #[serde(rename = "admin-token", default)]
+    admin_token: String,
+    #[serde(rename = "frontend-asset-cache-control", default)]
+    frontend_asset_cache_control: String,
+    #[serde(rename = "frontend-index-cache-control", default)]
+    frontend_index_cache_control: String,
+    #[serde(rename = "frontend-fallback-prefixes", default)]
+    frontend_fallback_prefixes: Vec<String>,
+    #[serde(rename = "files-cover-cache-control", default)]
+    files_cover_cache_control: Option<String>,
+    #[serde(rename = "files-audio-cache-control", default)]
+    files_audio_cache_control: Option<String>,
+    #[serde(default)]
+    watch: bool,
+    #[serde(rename = "watch-delay", default = "default_watch_delay")]
+    watch_delay: u64,
+    #[serde(rename = "shutdown-timeout", default = "default_shutdown_timeout")]
+    shutdown_timeout: u64,
+    #[serde(default = "default_compress")]
+    compress: bool,
+    #[serde(default)]
+    metrics: bool,
+    #[serde(rename = "access-log", default)]
+    access_log: bool,
+    #[serde(rename = "access-log-format", default = "default_access_log_format")]
+    access_log_format: String,
+    #[serde(rename = "cors-allowed-origins", default)]
+    cors_allowed_origins: Vec<String>,
     #[serde(default = "default_bindings")]
-    bindings: Vec<String>,
+    bindings: Vec<BindingRaw>,
+    // tables have to come after plain values, like bindings given as strings,
+    // when writing toml
+    #[serde(rename = "cover-rating-rules", default = "default_cover_rating_rules")]
+    cover_rating_rules: BTreeMap<String, u32>,
 }
 
 impl Default for ConfigGeneral {
@@ -43,13 +211,251 @@ impl Default for ConfigGeneral {
             base_dir: default_base_dir(),
             media_include_patterns: default_media_include_patterns(),
             media_exclude_patterns: Default::default(),
+            media_extensions: Default::default(),
             cover_include_patterns: default_cover_include_patterns(),
             cover_exclude_patterns: Default::default(),
+            cover_extensions: Default::default(),
+            document_include_patterns: default_document_include_patterns(),
+            document_exclude_patterns: Default::default(),
+            cover_priority: Default::default(),
+            cover_tiebreak: Default::default(),
+            duplicate_tracks: Default::default(),
+            shared_directory_covers: Default::default(),
+            generated_cover_format: Default::default(),
+            prefer_embedded_covers: false,
+            artist_covers: default_artist_covers(),
+            artist_cover_fallback: false,
+            artist_grouping: Default::default(),
+            artist_directory_depth: 0,
+            unknown_title: default_unknown_label(),
+            unknown_album: default_unknown_label(),
+            unknown_artist: default_unknown_label(),
+            directory_tag_fallback: false,
+            artist_split_pattern: default_artist_split_pattern(),
+            artist_split_exceptions: Default::default(),
+            artist_articles: Default::default(),
+            url_scheme: Default::default(),
+            cover_rating_rules: default_cover_rating_rules(),
             bindings: default_bindings(),
+            cdn_not_found_log_level: default_cdn_not_found_log_level(),
+            ffmpeg_threads: default_ffmpeg_threads(),
+            index_concurrency: default_index_concurrency(),
+            probe_retries: default_probe_retries(),
+            probe_retry_delay: default_probe_retry_delay(),
+            cache_dir: default_cache_dir(),
+            cache_file: Default::default(),
+            cover_cache_dir: Default::default(),
+            max_transcode_jobs: default_max_transcode_jobs(),
+            max_live_transcodes: default_max_live_transcodes(),
+            transcode_cache_max_age: default_transcode_cache_max_age(),
+            hls_segment_duration: default_hls_segment_duration(),
+            hls_bit_rate: default_hls_bit_rate(),
+            strict_indexing: false,
+            stable_unique_names: false,
+            index_all_max_songs: default_index_all_max_songs(),
+            min_album_songs: default_min_album_songs(),
+            group_singles: false,
+            debug: false,
+            expose_file_paths: false,
+            admin_token: Default::default(),
+            frontend_asset_cache_control: Default::default(),
+            frontend_index_cache_control: Default::default(),
+            frontend_fallback_prefixes: Default::default(),
+            files_cover_cache_control: Default::default(),
+            files_audio_cache_control: Default::default(),
+            watch: false,
+            watch_delay: default_watch_delay(),
+            shutdown_timeout: default_shutdown_timeout(),
+            compress: default_compress(),
+            metrics: false,
+            access_log: false,
+            access_log_format: default_access_log_format(),
+            cors_allowed_origins: Default::default(),
+        }
+    }
+}
+
+/// How to choose between two covers with the same rating.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum CoverTiebreak {
+    /// Keep whichever cover was found first. This depends on directory walk
+    /// order and is not stable across runs.
+    FirstFound,
+    /// Prefer the larger file, then the lexicographically smaller file name.
+    LargestFile,
+    /// Prefer the lexicographically smaller file name.
+    FileName,
+}
+
+impl Default for CoverTiebreak {
+    fn default() -> Self {
+        CoverTiebreak::LargestFile
+    }
+}
+
+/// How to choose between two songs claiming the same track of an album.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum DuplicateTracks {
+    /// Keep whichever song was found first.
+    KeepFirst,
+    /// Prefer the song with the higher bit rate, then the one found first.
+    HigherBitRate,
+}
+
+impl Default for DuplicateTracks {
+    fn default() -> Self {
+        DuplicateTracks::KeepFirst
+    }
+}
+
+/// Image formats covers extracted from embedded art can be saved as.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum GeneratedCoverFormat {
+    Jpg,
+    Png,
+    /// Much smaller than jpg at the same quality.
+    Webp,
+}
+
+impl Default for GeneratedCoverFormat {
+    fn default() -> Self {
+        GeneratedCoverFormat::Jpg
+    }
+}
+
+impl GeneratedCoverFormat {
+    pub fn extension(self) -> &'static str {
+        match self {
+            GeneratedCoverFormat::Jpg => "jpg",
+            GeneratedCoverFormat::Png => "png",
+            GeneratedCoverFormat::Webp => "webp",
+        }
+    }
+}
+
+/// A binding is either just an address or a table describing how to serve on
+/// that address.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(untagged)]
+enum BindingRaw {
+    Address(String),
+    Table {
+        address: String,
+        #[serde(default)]
+        tls: bool,
+        #[serde(rename = "redirect-to-https", default)]
+        redirect_to_https: bool,
+    },
+}
+
+/// An address to serve on.
+#[derive(Debug, Clone)]
+pub struct Binding {
+    pub address: String,
+    /// Serve HTTPS instead of HTTP on this address.
+    pub tls: bool,
+    /// Answer every request on this address with a redirect to the same url
+    /// over HTTPS on the port of the first TLS binding.
+    pub redirect_to_https: bool,
+}
+
+impl BindingRaw {
+    fn address(&self) -> &str {
+        match self {
+            BindingRaw::Address(address) => address,
+            BindingRaw::Table { address, .. } => address,
+        }
+    }
+}
+
+impl From<BindingRaw> for Binding {
+    fn from(raw: BindingRaw) -> Self {
+        match raw {
+            BindingRaw::Address(address) => Binding {
+                address,
+                tls: false,
+                redirect_to_https: false,
+            },
+            BindingRaw::Table {
+                address,
+                tls,
+                redirect_to_https,
+            } => Binding {
+                address,
+                tls,
+                redirect_to_https,
+            },
         }
     }
 }
 
+/// The certificate and private key served on TLS bindings.
+#[derive(Debug, Clone)]
+pub struct TlsConfig {
+    pub cert: PathBuf,
+    pub key: PathBuf,
+}
+
+/// Which albums get the covers in a directory containing songs from more than
+/// one album.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum SharedDirectoryCovers {
+    /// Every album with songs in the directory.
+    All,
+    /// Only the album with the most songs in the directory.
+    MostSongs,
+}
+
+impl Default for SharedDirectoryCovers {
+    fn default() -> Self {
+        SharedDirectoryCovers::All
+    }
+}
+
+/// Where a song's artists come from.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ArtistGrouping {
+    /// Use the song's artist tag.
+    Tags,
+    /// Use the name of the directory at `artist-directory-depth` below the
+    /// base directory. A depth of `0` means the directories directly inside the
+    /// base directory are artists, as in `Artist/Album/track.flac`. Songs that
+    /// aren't nested deeply enough fall back to their artist tag.
+    ByDirectory,
+    /// Use the song's artist tag for songs, but give albums to the composer
+    /// instead of the performing artists, as is usual for classical music.
+    /// Songs without a composer tag fall back to their album artist tag.
+    Composer,
+}
+
+impl Default for ArtistGrouping {
+    fn default() -> Self {
+        ArtistGrouping::Tags
+    }
+}
+
+/// How file urls are built.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum UrlScheme {
+    /// Files are served at their path relative to the base directory.
+    Path,
+    /// Files are served at an opaque id derived from their contents. This hides
+    /// the directory structure and keeps urls stable when files are moved.
+    Id,
+}
+
+impl Default for UrlScheme {
+    fn default() -> Self {
+        UrlScheme::Path
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct Config {
     pub base_dir: PathBuf,
@@ -57,17 +463,160 @@ pub struct Config {
     pub media_exclude_patterns: RegexSet,
     pub cover_include_patterns: RegexSet,
     pub cover_exclude_patterns: RegexSet,
-    pub bindings: Vec<String>,
+    pub document_include_patterns: RegexSet,
+    pub document_exclude_patterns: RegexSet,
+    /// Cover file name globs in order of preference. A cover matching an earlier
+    /// pattern beats covers matching later patterns or no pattern at all.
+    pub cover_priority: Vec<Regex>,
+    /// Weights added to a cover's score when its file name contains the
+    /// corresponding substring.
+    pub cover_rating_rules: Vec<(String, u32)>,
+    pub cover_tiebreak: CoverTiebreak,
+    /// Which song to list when two songs claim the same track of an album.
+    pub duplicate_tracks: DuplicateTracks,
+    pub shared_directory_covers: SharedDirectoryCovers,
+    /// Image format of covers extracted from songs' embedded art.
+    pub generated_cover_format: GeneratedCoverFormat,
+    /// Extracts embedded art even for albums with a cover file, using it
+    /// instead when it is at least as large as the cover file.
+    pub prefer_embedded_covers: bool,
+    /// File name globs of artist images in order of preference. Artist images
+    /// are looked for in the directories containing an artist's album
+    /// directories.
+    pub artist_covers: Vec<Regex>,
+    /// Gives artists without an artist image the best rated cover of their
+    /// albums.
+    pub artist_cover_fallback: bool,
+    pub artist_grouping: ArtistGrouping,
+    /// Depth below the base directory of artist directories when grouping
+    /// artists by directory.
+    pub artist_directory_depth: usize,
+    /// Title of songs without a title tag whose file name is unusable.
+    pub unknown_title: String,
+    /// Album of songs without an album tag.
+    pub unknown_album: String,
+    /// Artist of songs without an artist tag.
+    pub unknown_artist: String,
+    /// Takes missing album tags from the song's directory and missing artist
+    /// tags from the directory above it, as in `Artist/Album/track.flac`.
+    pub directory_tag_fallback: bool,
+    /// Separates the artists listed in a single artist tag.
+    pub artist_split_pattern: Regex,
+    /// Artist names that are never split, like "Earth, Wind & Fire".
+    pub artist_split_exceptions: Vec<String>,
+    /// Lowercase leading articles that are ignored when grouping artists, so
+    /// "The Beatles" and "Beatles" are the same artist.
+    pub artist_articles: Vec<String>,
+    pub url_scheme: UrlScheme,
+    pub bindings: Vec<Binding>,
+    /// Required if any binding uses TLS.
+    pub tls: Option<TlsConfig>,
+    pub cdn_not_found_log_level: Option<Level>,
+    /// Size of the thread pool running blocking ffmpeg work.
+    pub ffmpeg_threads: usize,
+    /// How many songs are read at once while indexing.
+    pub index_concurrency: usize,
+    /// How many times to retry probing a media file after a transient error.
+    pub probe_retries: u32,
+    /// Delay between probe retries in milliseconds.
+    pub probe_retry_delay: u64,
+    pub cache_dir: PathBuf,
+    /// File storing the probed tags of each song between runs. Defaults to
+    /// `manifest.json` in the cache directory.
+    pub cache_file: Option<PathBuf>,
+    /// Directory generated covers are written to. Unset writes them next to
+    /// the songs they were extracted from.
+    pub cover_cache_dir: Option<PathBuf>,
+    pub max_transcode_jobs: usize,
+    /// How many songs can be streamed while being transcoded at once. Each
+    /// stream holds its own threads for as long as the client listens.
+    pub max_live_transcodes: usize,
+    /// Max age of transcode cache entries in seconds.
+    pub transcode_cache_max_age: u64,
+    /// HLS segment duration in seconds.
+    pub hls_segment_duration: u32,
+    /// HLS audio bit rate in kbit/s.
+    pub hls_bit_rate: usize,
+    /// Abort indexing on the first song that can't be read instead of skipping
+    /// it.
+    pub strict_indexing: bool,
+    /// Remembers the unique names given to albums and artists so their urls
+    /// don't change when the library is reindexed.
+    pub stable_unique_names: bool,
+    /// Largest library in songs that `/index/all` will return.
+    pub index_all_max_songs: usize,
+    /// Albums with fewer songs than this are left out of the album listing, but
+    /// can still be looked up directly.
+    pub min_album_songs: usize,
+    /// Groups albums containing a single untracked song into a "Singles" album
+    /// per artist.
+    pub group_singles: bool,
+    /// Enables debugging endpoints that expose server internals.
+    pub debug: bool,
+    /// Includes the absolute path of songs' files in song json, for clients on
+    /// the same machine that open files directly.
+    pub expose_file_paths: bool,
+    /// Bearer token required by admin endpoints, like reindexing. Admin
+    /// endpoints are disabled when no token is set.
+    pub admin_token: Option<String>,
+    /// `Cache-Control` header of frontend files other than `index.html`.
+    pub frontend_asset_cache_control: Option<HeaderValue>,
+    /// `Cache-Control` header of the frontend's `index.html`, including when it
+    /// is served in place of an unknown path.
+    pub frontend_index_cache_control: Option<HeaderValue>,
+    /// Path prefixes that serve the frontend's `index.html` when not found, so
+    /// the frontend's router can handle them. Empty means all paths.
+    pub frontend_fallback_prefixes: Vec<String>,
+    /// `Cache-Control` header of cover images served as files, including
+    /// generated covers.
+    pub files_cover_cache_control: Option<HeaderValue>,
+    /// `Cache-Control` header of audio files.
+    pub files_audio_cache_control: Option<HeaderValue>,
+    /// Watches the music directory and reindexes when files in it change.
+    pub watch: bool,
+    /// Milliseconds without file changes to wait for before reindexing, so
+    /// copying many files only causes one reindex.
+    pub watch_delay: u64,
+    /// Seconds to let in-flight requests finish for when shutting down before
+    /// dropping them.
+    pub shutdown_timeout: u64,
+    /// Compresses responses with gzip or brotli when clients accept it. Can
+    /// be turned off when a reverse proxy compresses responses instead.
+    pub compress: bool,
+    /// Serves request, bytes served and indexing metrics at `/metrics` in the
+    /// Prometheus text format.
+    pub metrics: bool,
+    /// Logs every request at info level with the
+    /// `actix_web::middleware::logger` target.
+    pub access_log: bool,
+    /// Format of access log lines, using the placeholders of actix's `Logger`.
+    pub access_log_format: String,
+    /// Origins allowed to make cross-origin requests. `*` allows any origin.
+    /// Empty disables CORS, except in debug builds, which allow the Angular
+    /// development server.
+    pub cors_allowed_origins: Vec<String>,
 }
 
 impl Config {
+    /// Loads the config file, writing back any missing keys with their
+    /// defaults, then applies env var overrides.
+    ///
+    /// The config file used is the first of:
+    /// 1. the path given with `--config <path>`,
+    /// 2. the path in `MUSIC_SERVER_CONFIG`,
+    /// 3. `music-server-1.toml` in the working directory,
+    ///    `$XDG_CONFIG_HOME/music-server-1/` or `/etc/music-server-1/`,
+    ///    whichever exists first, falling back to the working directory.
+    ///
+    /// Keys of the `general` table can then be overridden by `MUSIC_SERVER_*`
+    /// env vars, for example `MUSIC_SERVER_BASE_DIR` for `base-dir`. Overrides
+    /// are never written back to the config file.
     pub fn load() -> Result<Config> {
-        info!("Loading config: {}", CONFIG_FILE_NAME);
+        let cfg_path = Config::find_path()?;
+        info!("Loading config: {}", cfg_path.to_string_lossy());
 
-        let cfg_path = Path::new(CONFIG_FILE_NAME);
-
-        let cfg_raw: ConfigRaw = if cfg_path.exists() {
-            let mut cfg_file = File::open(cfg_path)
+        let mut cfg_value: Value = if cfg_path.exists() {
+            let mut cfg_file = File::open(&cfg_path)
                 .chain_err(|| ConfigLoadError("Error opening config file".into()))?;
             let mut cfg_string = String::new();
             cfg_file
@@ -79,32 +628,412 @@ impl Config {
             info!("Loading blank cfg file...");
             toml::from_str("").chain_err(|| ConfigLoadError("Error loading blank config".into()))?
         };
+        let file_cfg_raw: ConfigRaw = cfg_value
+            .clone()
+            .try_into()
+            .chain_err(|| ConfigLoadError("Error decoding config".into()))?;
 
+        // system-wide config files usually aren't writable
         debug!("Writing config file...");
-        let new_cfg_string = toml::to_string_pretty(&cfg_raw)
+        if let Err(e) = Config::write_back(&cfg_path, &file_cfg_raw) {
+            warn!("Unable to write back config file: {}", e);
+        }
+
+        Config::apply_env_overrides(&mut cfg_value, &file_cfg_raw, env::vars_os())?;
+        let cfg_raw: ConfigRaw = cfg_value
+            .try_into()
+            .chain_err(|| ConfigLoadError("Error decoding config env overrides".into()))?;
+
+        Config::from_raw(cfg_raw)
+    }
+
+    /// Builds a config from the contents of a config file, without reading any
+    /// files or env vars.
+    #[cfg(test)]
+    pub fn from_toml(toml: &str) -> Result<Config> {
+        let cfg_raw: ConfigRaw =
+            toml::from_str(toml).chain_err(|| ConfigLoadError("Error decoding config".into()))?;
+        Config::from_raw(cfg_raw)
+    }
+
+    /// Validates a decoded config and builds the final config from it.
+    fn from_raw(cfg_raw: ConfigRaw) -> Result<Config> {
+        let tls = cfg_raw.tls.map(|tls| TlsConfig {
+            cert: tls.cert.into(),
+            key: tls.key.into(),
+        });
+        if let Some(tls) = &tls {
+            for path in [&tls.cert, &tls.key].iter() {
+                if !path.is_file() {
+                    return Err(ConfigLoadError(
+                        format!("TLS file does not exist: {}", path.to_string_lossy()).into(),
+                    )
+                    .into());
+                }
+            }
+        }
+        validate_bindings(&cfg_raw.general.bindings)?;
+        if let Some(binding) = cfg_raw
+            .general
+            .bindings
+            .iter()
+            .find(|binding| matches!(binding, BindingRaw::Table { tls: true, .. }))
+        {
+            if tls.is_none() {
+                return Err(ConfigLoadError(
+                    format!(
+                        "TLS binding {} requires a [tls] section with cert and key",
+                        binding.address()
+                    )
+                    .into(),
+                )
+                .into());
+            }
+        }
+
+        Ok(Config {
+            base_dir: cfg_raw.general.base_dir.into(),
+            media_include_patterns: RegexSet::new(with_extensions(
+                cfg_raw.general.media_include_patterns,
+                &cfg_raw.general.media_extensions,
+            ))
+            .chain_err(|| ConfigLoadError("Error decoding regex".into()))?,
+            media_exclude_patterns: RegexSet::new(cfg_raw.general.media_exclude_patterns)
+                .chain_err(|| ConfigLoadError("Error decoding regex".into()))?,
+            cover_include_patterns: RegexSet::new(with_extensions(
+                cfg_raw.general.cover_include_patterns,
+                &cfg_raw.general.cover_extensions,
+            ))
+            .chain_err(|| ConfigLoadError("Error decoding regex".into()))?,
+            cover_exclude_patterns: RegexSet::new(cfg_raw.general.cover_exclude_patterns)
+                .chain_err(|| ConfigLoadError("Error decoding regex".into()))?,
+            document_include_patterns: RegexSet::new(cfg_raw.general.document_include_patterns)
+                .chain_err(|| ConfigLoadError("Error decoding regex".into()))?,
+            document_exclude_patterns: RegexSet::new(cfg_raw.general.document_exclude_patterns)
+                .chain_err(|| ConfigLoadError("Error decoding regex".into()))?,
+            cover_priority: cfg_raw
+                .general
+                .cover_priority
+                .iter()
+                .map(|glob| Regex::new(&glob_to_regex(glob)))
+                .collect::<result::Result<_, _>>()
+                .chain_err(|| ConfigLoadError("Error decoding cover-priority glob".into()))?,
+            cover_rating_rules: cfg_raw.general.cover_rating_rules.into_iter().collect(),
+            cover_tiebreak: cfg_raw.general.cover_tiebreak,
+            duplicate_tracks: cfg_raw.general.duplicate_tracks,
+            shared_directory_covers: cfg_raw.general.shared_directory_covers,
+            generated_cover_format: cfg_raw.general.generated_cover_format,
+            prefer_embedded_covers: cfg_raw.general.prefer_embedded_covers,
+            artist_covers: cfg_raw
+                .general
+                .artist_covers
+                .iter()
+                .map(|glob| Regex::new(&glob_to_regex(glob)))
+                .collect::<result::Result<_, _>>()
+                .chain_err(|| ConfigLoadError("Error decoding artist-covers glob".into()))?,
+            artist_cover_fallback: cfg_raw.general.artist_cover_fallback,
+            artist_grouping: cfg_raw.general.artist_grouping,
+            artist_directory_depth: cfg_raw.general.artist_directory_depth,
+            unknown_title: cfg_raw.general.unknown_title,
+            unknown_album: cfg_raw.general.unknown_album,
+            unknown_artist: cfg_raw.general.unknown_artist,
+            directory_tag_fallback: cfg_raw.general.directory_tag_fallback,
+            artist_split_pattern: Regex::new(&cfg_raw.general.artist_split_pattern)
+                .chain_err(|| ConfigLoadError("Error decoding artist-split-pattern".into()))?,
+            artist_split_exceptions: cfg_raw
+                .general
+                .artist_split_exceptions
+                .into_iter()
+                .filter(|exception| !exception.is_empty())
+                .collect(),
+            artist_articles: cfg_raw
+                .general
+                .artist_articles
+                .iter()
+                .map(|article| article.trim().to_lowercase())
+                .filter(|article| !article.is_empty())
+                .collect(),
+            url_scheme: cfg_raw.general.url_scheme,
+            bindings: cfg_raw
+                .general
+                .bindings
+                .into_iter()
+                .map(Binding::from)
+                .collect(),
+            tls,
+            cdn_not_found_log_level: LevelFilter::from_str(
+                &cfg_raw.general.cdn_not_found_log_level,
+            )
+            .chain_err(|| ConfigLoadError("Error decoding cdn-not-found-log-level".into()))?
+            .to_level(),
+            ffmpeg_threads: cfg_raw.general.ffmpeg_threads,
+            index_concurrency: cfg_raw.general.index_concurrency,
+            probe_retries: cfg_raw.general.probe_retries,
+            probe_retry_delay: cfg_raw.general.probe_retry_delay,
+            cache_dir: cfg_raw.general.cache_dir.into(),
+            cache_file: Some(cfg_raw.general.cache_file)
+                .filter(|file| !file.is_empty())
+                .map(PathBuf::from),
+            cover_cache_dir: Some(cfg_raw.general.cover_cache_dir)
+                .filter(|dir| !dir.is_empty())
+                .map(PathBuf::from),
+            max_transcode_jobs: cfg_raw.general.max_transcode_jobs,
+            max_live_transcodes: cfg_raw.general.max_live_transcodes,
+            transcode_cache_max_age: cfg_raw.general.transcode_cache_max_age,
+            hls_segment_duration: cfg_raw.general.hls_segment_duration,
+            hls_bit_rate: cfg_raw.general.hls_bit_rate,
+            strict_indexing: cfg_raw.general.strict_indexing,
+            stable_unique_names: cfg_raw.general.stable_unique_names,
+            index_all_max_songs: cfg_raw.general.index_all_max_songs,
+            min_album_songs: cfg_raw.general.min_album_songs,
+            group_singles: cfg_raw.general.group_singles,
+            debug: cfg_raw.general.debug,
+            expose_file_paths: cfg_raw.general.expose_file_paths,
+            admin_token: Some(cfg_raw.general.admin_token).filter(|token| !token.is_empty()),
+            frontend_asset_cache_control: cache_control(
+                &cfg_raw.general.frontend_asset_cache_control,
+            )
+            .chain_err(|| ConfigLoadError("Error decoding frontend-asset-cache-control".into()))?,
+            frontend_index_cache_control: cache_control(
+                &cfg_raw.general.frontend_index_cache_control,
+            )
+            .chain_err(|| ConfigLoadError("Error decoding frontend-index-cache-control".into()))?,
+            frontend_fallback_prefixes: cfg_raw.general.frontend_fallback_prefixes,
+            files_cover_cache_control: cache_control(
+                &cfg_raw
+                    .general
+                    .files_cover_cache_control
+                    .clone()
+                    .unwrap_or_else(|| {
+                        default_files_cover_cache_control(cfg_raw.general.url_scheme)
+                    }),
+            )
+            .chain_err(|| ConfigLoadError("Error decoding files-cover-cache-control".into()))?,
+            files_audio_cache_control: cache_control(
+                &cfg_raw
+                    .general
+                    .files_audio_cache_control
+                    .clone()
+                    .unwrap_or_else(|| {
+                        default_files_audio_cache_control(cfg_raw.general.url_scheme)
+                    }),
+            )
+            .chain_err(|| ConfigLoadError("Error decoding files-audio-cache-control".into()))?,
+            watch: cfg_raw.general.watch,
+            watch_delay: cfg_raw.general.watch_delay,
+            shutdown_timeout: cfg_raw.general.shutdown_timeout,
+            compress: cfg_raw.general.compress,
+            metrics: cfg_raw.general.metrics,
+            access_log: cfg_raw.general.access_log,
+            access_log_format: cfg_raw.general.access_log_format,
+            cors_allowed_origins: cors_allowed_origins(cfg_raw.general.cors_allowed_origins),
+        })
+    }
+
+    fn find_path() -> Result<PathBuf> {
+        let mut args = env::args_os().skip(1);
+        while let Some(arg) = args.next() {
+            if arg == CONFIG_FLAG {
+                return args.next().map(PathBuf::from).ok_or_else(|| {
+                    ConfigLoadError(format!("{} requires a path", CONFIG_FLAG).into()).into()
+                });
+            }
+
+            if let Some(path) = arg
+                .to_str()
+                .and_then(|arg| arg.strip_prefix(CONFIG_FLAG))
+                .and_then(|rest| rest.strip_prefix('='))
+            {
+                return Ok(path.into());
+            }
+        }
+
+        if let Some(path) = env::var_os(CONFIG_ENV) {
+            return Ok(path.into());
+        }
+
+        let mut search_dirs = vec![PathBuf::new()];
+        if let Some(dir) = dirs::config_dir() {
+            search_dirs.push(dir.join(CONFIG_DIR_NAME));
+        }
+        search_dirs.push(SYSTEM_CONFIG_DIR.into());
+
+        let found = search_dirs
+            .into_iter()
+            .map(|dir| dir.join(CONFIG_FILE_NAME))
+            .find(|path| path.is_file());
+
+        Ok(found.unwrap_or_else(|| CONFIG_FILE_NAME.into()))
+    }
+
+    fn write_back(cfg_path: &Path, cfg_raw: &ConfigRaw) -> Result<()> {
+        let new_cfg_string = toml::to_string_pretty(cfg_raw)
             .chain_err(|| ConfigLoadError("Error re-encoding config file".into()))?;
         let mut cfg_file = OpenOptions::new()
             .write(true)
             .create(true)
+            .truncate(true)
             .open(cfg_path)
             .chain_err(|| ConfigLoadError("Error opening config file for re-encoding".into()))?;
         cfg_file
             .write_all(new_cfg_string.as_bytes())
-            .chain_err(|| ConfigLoadError("Error writing to config file for re-encoding".into()))?;
+            .chain_err(|| ConfigLoadError("Error writing to config file for re-encoding".into()))
+    }
 
-        Ok(Config {
-            base_dir: cfg_raw.general.base_dir.into(),
-            media_include_patterns: RegexSet::new(cfg_raw.general.media_include_patterns)
-                .chain_err(|| ConfigLoadError("Error decoding regex".into()))?,
-            media_exclude_patterns: RegexSet::new(cfg_raw.general.media_exclude_patterns)
-                .chain_err(|| ConfigLoadError("Error decoding regex".into()))?,
-            cover_include_patterns: RegexSet::new(cfg_raw.general.cover_include_patterns)
-                .chain_err(|| ConfigLoadError("Error decoding regex".into()))?,
-            cover_exclude_patterns: RegexSet::new(cfg_raw.general.cover_exclude_patterns)
-                .chain_err(|| ConfigLoadError("Error decoding regex".into()))?,
-            bindings: cfg_raw.general.bindings,
-        })
+    /// Overrides keys of the `general` table with `MUSIC_SERVER_*` env vars.
+    ///
+    /// Values of string keys are used as-is, all other values are parsed as
+    /// TOML, so `MUSIC_SERVER_BINDINGS='["0.0.0.0:8980"]'` works too. Env vars
+    /// that aren't valid UTF-8 are skipped.
+    fn apply_env_overrides(
+        cfg_value: &mut Value,
+        defaults: &ConfigRaw,
+        vars: impl Iterator<Item = (OsString, OsString)>,
+    ) -> Result<()> {
+        let known = Value::try_from(&defaults.general)
+            .chain_err(|| ConfigLoadError("Error encoding config".into()))?;
+        let known = known
+            .as_table()
+            .expect("BUG: General config is not a table");
+
+        let root = cfg_value
+            .as_table_mut()
+            .ok_or_else(|| ConfigLoadError("Config is not a table".into()))?;
+        let general = root
+            .entry("general")
+            .or_insert_with(|| Value::Table(Default::default()))
+            .as_table_mut()
+            .ok_or_else(|| ConfigLoadError("general is not a table".into()))?;
+
+        for (var, value) in vars {
+            let (var, value) = match (var.into_string(), value.into_string()) {
+                (Ok(var), Ok(value)) => (var, value),
+                (Ok(var), Err(_)) if var.starts_with(OVERRIDE_ENV_PREFIX) => {
+                    warn!("Ignoring {}, its value is not valid UTF-8", var);
+                    continue;
+                }
+                _ => continue,
+            };
+            if var == CONFIG_ENV || !var.starts_with(OVERRIDE_ENV_PREFIX) {
+                continue;
+            }
+
+            let key = var[OVERRIDE_ENV_PREFIX.len()..]
+                .to_lowercase()
+                .replace('_', "-");
+            let value = match known.get(&key) {
+                Some(Value::String(_)) => Value::String(value),
+                None if OPTIONAL_STRING_KEYS.contains(&key.as_str()) => Value::String(value),
+                Some(_) => toml::from_str::<Value>(&format!("value = {}", value))
+                    .ok()
+                    .and_then(|table| table.get("value").cloned())
+                    .ok_or_else(|| ConfigLoadError(format!("Error decoding {}", var).into()))?,
+                None => {
+                    warn!("Ignoring {}, {} is not a config key", var, key);
+                    continue;
+                }
+            };
+
+            debug!("Overriding {} from {}", key, var);
+            general.insert(key, value);
+        }
+
+        Ok(())
+    }
+}
+
+/// Parses a `Cache-Control` header value, where an empty value means the header
+/// isn't set.
+fn cache_control(value: &str) -> result::Result<Option<HeaderValue>, InvalidHeaderValue> {
+    if value.is_empty() {
+        Ok(None)
+    } else {
+        HeaderValue::from_str(value).map(Some)
+    }
+}
+
+/// Checks that every binding is a `host:port` address and that no address is
+/// bound twice. Hostnames are only resolved when binding.
+fn validate_bindings(bindings: &[BindingRaw]) -> Result<()> {
+    let mut seen: Vec<(String, Option<SocketAddr>)> = vec![];
+    for binding in bindings {
+        let address = binding.address().trim();
+        let socket_addr = address.parse::<SocketAddr>().ok();
+        if socket_addr.is_none() {
+            let valid = match address.rfind(':') {
+                Some(index) => index > 0 && address[index + 1..].parse::<u16>().is_ok(),
+                None => false,
+            };
+            if !valid {
+                return Err(ConfigLoadError(
+                    format!(
+                        "Invalid binding {:?}, expected an address like 127.0.0.1:8980 or localhost:8980",
+                        address
+                    )
+                    .into(),
+                )
+                .into());
+            }
+        }
+
+        let duplicate = seen.iter().any(|(seen_address, seen_socket_addr)| {
+            match (socket_addr, seen_socket_addr) {
+                (Some(socket_addr), Some(seen_socket_addr)) => socket_addr == *seen_socket_addr,
+                _ => address.eq_ignore_ascii_case(seen_address),
+            }
+        });
+        if duplicate {
+            return Err(ConfigLoadError(format!("Duplicate binding {:?}", address).into()).into());
+        }
+        seen.push((address.to_string(), socket_addr));
+    }
+
+    Ok(())
+}
+
+/// Falls back to allowing the Angular development server in debug builds when
+/// no origins are configured.
+fn cors_allowed_origins(origins: Vec<String>) -> Vec<String> {
+    if origins.is_empty() && cfg!(debug_assertions) {
+        vec![DEV_SERVER_ORIGIN.to_string()]
+    } else {
+        origins
+    }
+}
+
+/// Adds a case-insensitive pattern matching each file extension, with or
+/// without its leading dot, to the given patterns.
+fn with_extensions(mut patterns: Vec<String>, extensions: &[String]) -> Vec<String> {
+    patterns.extend(
+        extensions
+            .iter()
+            .map(|ext| ext.trim().trim_start_matches('.'))
+            .filter(|ext| !ext.is_empty())
+            .map(|ext| format!("(?i)\\.{}$", regex::escape(ext))),
+    );
+    patterns
+}
+
+/// Converts a file name glob into a case-insensitive regex. `*` matches any
+/// number of characters and `?` matches a single character.
+fn glob_to_regex(glob: &str) -> String {
+    let mut regex = "(?i)^".to_string();
+    let mut literal = String::new();
+
+    for c in glob.chars() {
+        match c {
+            '*' | '?' => {
+                regex.push_str(&regex::escape(&literal));
+                literal.clear();
+                regex.push_str(if c == '*' { ".*" } else { "." });
+            }
+            _ => literal.push(c),
+        }
     }
+
+    regex.push_str(&regex::escape(&literal));
+    regex.push('$');
+    regex
 }
 
 fn default_base_dir() -> String {
@@ -126,6 +1055,9 @@ fn default_media_include_patterns() -> Vec<String> {
         ".*\\.flac$".to_string(),
         ".*\\.mp3$".to_string(),
         ".*\\.ogg$".to_string(),
+        ".*\\.opus$".to_string(),
+        ".*\\.m4a$".to_string(),
+        ".*\\.wav$".to_string(),
     ]
 }
 
@@ -133,6 +1065,196 @@ fn default_cover_include_patterns() -> Vec<String> {
     vec![".*\\.jpg$".to_string(), ".*\\.png$".to_string()]
 }
 
-fn default_bindings() -> Vec<String> {
-    vec!["127.0.0.1:8980".to_string()]
+fn default_artist_split_pattern() -> String {
+    "( +& +| *, +)".to_string()
+}
+
+fn default_unknown_label() -> String {
+    "Unknown".to_string()
+}
+
+fn default_artist_covers() -> Vec<String> {
+    vec!["artist.*".to_string(), "folder.*".to_string()]
+}
+
+fn default_document_include_patterns() -> Vec<String> {
+    vec![".*\\.pdf$".to_string()]
+}
+
+fn default_cover_rating_rules() -> BTreeMap<String, u32> {
+    let mut rules = BTreeMap::new();
+    rules.insert("cover".to_string(), 100);
+    rules.insert("small".to_string(), 20);
+    rules
+}
+
+fn default_bindings() -> Vec<BindingRaw> {
+    vec![BindingRaw::Address("127.0.0.1:8980".to_string())]
+}
+
+fn default_cdn_not_found_log_level() -> String {
+    "warn".to_string()
+}
+
+fn default_index_all_max_songs() -> usize {
+    5000
+}
+
+fn default_min_album_songs() -> usize {
+    1
+}
+
+fn default_ffmpeg_threads() -> usize {
+    num_cpus::get()
+}
+
+fn default_index_concurrency() -> usize {
+    num_cpus::get()
+}
+
+fn default_probe_retries() -> u32 {
+    2
+}
+
+fn default_probe_retry_delay() -> u64 {
+    500
+}
+
+fn default_watch_delay() -> u64 {
+    2000
+}
+
+fn default_shutdown_timeout() -> u64 {
+    30
+}
+
+fn default_files_cover_cache_control(url_scheme: UrlScheme) -> String {
+    match url_scheme {
+        // ids only change along with the files
+        UrlScheme::Id => "public, max-age=31536000, immutable".to_string(),
+        // paths stay the same when a file is replaced, so revalidate against
+        // its Last-Modified
+        UrlScheme::Path => "no-cache".to_string(),
+    }
+}
+
+fn default_files_audio_cache_control(url_scheme: UrlScheme) -> String {
+    match url_scheme {
+        UrlScheme::Id => "public, max-age=86400".to_string(),
+        UrlScheme::Path => "no-cache".to_string(),
+    }
+}
+
+fn default_compress() -> bool {
+    true
+}
+
+fn default_access_log_format() -> String {
+    // remote address, request line, status, response size and time taken
+    r#"%a "%r" %s %b %Dms"#.to_string()
+}
+
+fn default_cache_dir() -> String {
+    match dirs::cache_dir() {
+        None => "cache".to_string(),
+        Some(dir) => dir.join("music-server-1").to_string_lossy().to_string(),
+    }
+}
+
+fn default_max_transcode_jobs() -> usize {
+    2
+}
+
+fn default_max_live_transcodes() -> usize {
+    4
+}
+
+fn default_transcode_cache_max_age() -> u64 {
+    // one week
+    7 * 24 * 60 * 60
+}
+
+fn default_hls_segment_duration() -> u32 {
+    10
+}
+
+fn default_hls_bit_rate() -> usize {
+    128
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Encodes a config the way `write_back` does and decodes it again.
+    fn round_trip(cfg_raw: &ConfigRaw) -> String {
+        let encoded = toml::to_string_pretty(cfg_raw).unwrap();
+        let decoded: ConfigRaw = toml::from_str(&encoded).unwrap();
+        assert_eq!(toml::to_string_pretty(&decoded).unwrap(), encoded);
+        encoded
+    }
+
+    #[test]
+    fn default_config_round_trips() {
+        let cfg_raw = ConfigRaw {
+            general: Default::default(),
+            tls: None,
+        };
+        let encoded = round_trip(&cfg_raw);
+        assert!(encoded.contains("[general.cover-rating-rules]"));
+
+        Config::from_toml(&encoded).unwrap();
+    }
+
+    /// Applies env var overrides to an empty config.
+    fn with_overrides(vars: Vec<(OsString, OsString)>) -> Config {
+        let mut cfg_value = Value::Table(Default::default());
+        let defaults = ConfigRaw {
+            general: Default::default(),
+            tls: None,
+        };
+        Config::apply_env_overrides(&mut cfg_value, &defaults, vars.into_iter()).unwrap();
+        Config::from_raw(cfg_value.try_into().unwrap()).unwrap()
+    }
+
+    #[test]
+    fn env_overrides_include_keys_without_defaults() {
+        let config = with_overrides(vec![
+            ("MUSIC_SERVER_WATCH".into(), "true".into()),
+            (
+                "MUSIC_SERVER_FILES_COVER_CACHE_CONTROL".into(),
+                "max-age=60".into(),
+            ),
+        ]);
+        assert!(config.watch);
+        assert_eq!(
+            config.files_cover_cache_control,
+            Some(HeaderValue::from_static("max-age=60"))
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn env_vars_that_arent_utf8_are_skipped() {
+        use std::os::unix::ffi::OsStringExt;
+
+        let config = with_overrides(vec![
+            ("UNRELATED".into(), OsString::from_vec(vec![0xff, 0xfe])),
+            (
+                "MUSIC_SERVER_ADMIN_TOKEN".into(),
+                OsString::from_vec(vec![0xff]),
+            ),
+            ("MUSIC_SERVER_WATCH".into(), "true".into()),
+        ]);
+        assert!(config.watch);
+        assert_eq!(config.admin_token, None);
+    }
+
+    #[test]
+    fn table_bindings_round_trip() {
+        let cfg_raw: ConfigRaw =
+            toml::from_str("[[general.bindings]]\naddress = \"0.0.0.0:8443\"\ntls = true\n")
+                .unwrap();
+        round_trip(&cfg_raw);
+    }
 }
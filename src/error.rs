@@ -1,5 +1,9 @@
 use crate::util::w_err;
-use actix_web::{dev::HttpResponseBuilder, http::StatusCode, HttpResponse, ResponseError};
+use actix_web::{
+    dev::HttpResponseBuilder,
+    http::{header, StatusCode},
+    HttpResponse, ResponseError,
+};
 use error_chain::ChainedError;
 use serde::Serialize;
 use std::{borrow::Cow, fmt::Debug};
@@ -9,12 +13,36 @@ error_chain! {
         IndexingError(path: Option<String>, desc: &'static str) {
             display("Indexing Error at {:?} while {}", path, desc)
         }
+        TranscodeError(path: String, desc: &'static str) {
+            display("Transcoding Error at {} while {}", path, desc)
+        }
+        RatingsError(path: String, desc: &'static str) {
+            display("Ratings Error at {} while {}", path, desc)
+        }
         ConfigLoadError(msg: Cow<'static, str>) {
             display("Error loading config: {}", msg)
         }
-        NoSuchResource {}
+        NoSuchResource(kind: &'static str, unique_name: String) {
+            display("No such {}: {}", kind, unique_name)
+        }
+        Unauthorized {}
+        InvalidQuery(msg: String) {
+            display("Invalid query: {}", msg)
+        }
+        IndexTooLarge(songs: usize, max: usize) {
+            display("Index of {} songs is larger than the maximum of {}", songs, max)
+        }
         UriSegmentError {}
+        RedirectToHttps(url: String) {
+            display("Redirecting to {}", url)
+        }
         FilesLimiterError {}
+        TooManyTranscodes(max: usize) {
+            display("All {} live transcodes are in use", max)
+        }
+        LiveTranscodesUnsupported {
+            display("Live transcodes are only supported on unix")
+        }
     }
 }
 
@@ -24,12 +52,24 @@ impl ResponseError for Error {
         match self {
             Error(ErrorKind::FilesLimiterError, ..) => StatusCode::NOT_FOUND,
             Error(ErrorKind::UriSegmentError, ..) => StatusCode::BAD_REQUEST,
-            Error(ErrorKind::NoSuchResource, ..) => StatusCode::NOT_FOUND,
+            Error(ErrorKind::RedirectToHttps(..), ..) => StatusCode::MOVED_PERMANENTLY,
+            Error(ErrorKind::NoSuchResource(..), ..) => StatusCode::NOT_FOUND,
+            Error(ErrorKind::Unauthorized, ..) => StatusCode::UNAUTHORIZED,
+            Error(ErrorKind::InvalidQuery(..), ..) => StatusCode::BAD_REQUEST,
+            Error(ErrorKind::IndexTooLarge(..), ..) => StatusCode::FORBIDDEN,
+            Error(ErrorKind::TooManyTranscodes(..), ..) => StatusCode::SERVICE_UNAVAILABLE,
+            Error(ErrorKind::LiveTranscodesUnsupported, ..) => StatusCode::SERVICE_UNAVAILABLE,
             _ => StatusCode::INTERNAL_SERVER_ERROR,
         }
     }
 
     fn error_response(&self) -> HttpResponse {
+        if let Error(ErrorKind::RedirectToHttps(url), ..) = self {
+            return HttpResponseBuilder::new(self.status_code())
+                .header(header::LOCATION, url.as_str())
+                .finish();
+        }
+
         if let Some(json) = self.handle() {
             HttpResponseBuilder::new(self.status_code()).json(&w_err(json))
         } else {
@@ -44,7 +84,27 @@ impl Error {
         match self {
             Error(ErrorKind::FilesLimiterError, ..) => None,
             Error(ErrorKind::UriSegmentError, ..) => None,
-            Error(ErrorKind::NoSuchResource, ..) => Some(JsonError::NoSuchResource),
+            Error(ErrorKind::RedirectToHttps(..), ..) => None,
+            Error(ErrorKind::NoSuchResource(kind, unique_name), ..) => {
+                Some(JsonError::NoSuchResource {
+                    kind,
+                    unique_name: unique_name.clone(),
+                })
+            }
+            Error(ErrorKind::Unauthorized, ..) => Some(JsonError::Unauthorized),
+            Error(ErrorKind::InvalidQuery(msg), ..) => {
+                Some(JsonError::InvalidQuery { msg: msg.clone() })
+            }
+            Error(ErrorKind::IndexTooLarge(songs, max), ..) => Some(JsonError::IndexTooLarge {
+                songs: *songs,
+                max: *max,
+            }),
+            Error(ErrorKind::TooManyTranscodes(max), ..) => {
+                Some(JsonError::TooManyTranscodes { max: *max })
+            }
+            Error(ErrorKind::LiveTranscodesUnsupported, ..) => {
+                Some(JsonError::LiveTranscodesUnsupported)
+            }
             _ => {
                 self.log();
                 Some(JsonError::InternalServerError)
@@ -61,5 +121,20 @@ impl Error {
 #[serde(tag = "type")]
 pub enum JsonError {
     InternalServerError,
-    NoSuchResource,
+    NoSuchResource {
+        kind: &'static str,
+        unique_name: String,
+    },
+    Unauthorized,
+    InvalidQuery {
+        msg: String,
+    },
+    IndexTooLarge {
+        songs: usize,
+        max: usize,
+    },
+    TooManyTranscodes {
+        max: usize,
+    },
+    LiveTranscodesUnsupported,
 }
@@ -0,0 +1,142 @@
+use crate::config::Config;
+use actix_service::{Service, ServiceFactory, Transform};
+use actix_web::{
+    dev::{ServiceRequest, ServiceResponse},
+    http::{header, HeaderValue, StatusCode},
+    web, HttpResponse, Scope,
+};
+use actix_web_static_files::{Resource, ResourceFiles};
+use futures::{
+    future,
+    future::Ready,
+    task::{Context, Poll},
+};
+use std::{collections::HashMap, future::Future, pin::Pin, rc::Rc, result};
+
+const INDEX_FILE: &str = "index.html";
+
+/// Serves the bundled frontend.
+pub fn apply_services(
+    config: &Config,
+    files: HashMap<&'static str, Resource>,
+) -> Scope<
+    impl ServiceFactory<
+        Config = (),
+        Request = ServiceRequest,
+        Response = ServiceResponse,
+        Error = actix_web::Error,
+        InitError = (),
+    >,
+> {
+    let index = files
+        .get(INDEX_FILE)
+        .map(|resource| (resource.data, resource.mime_type));
+
+    web::scope("")
+        .wrap(FrontendFiles {
+            settings: Rc::new(FrontendSettings {
+                index,
+                fallback_prefixes: config.frontend_fallback_prefixes.clone(),
+                asset_cache_control: config.frontend_asset_cache_control.clone(),
+                index_cache_control: config.frontend_index_cache_control.clone(),
+            }),
+        })
+        .service(ResourceFiles::new("/", files))
+}
+
+struct FrontendSettings {
+    /// Contents and mime type of `index.html`.
+    index: Option<(&'static [u8], &'static str)>,
+    fallback_prefixes: Vec<String>,
+    asset_cache_control: Option<HeaderValue>,
+    index_cache_control: Option<HeaderValue>,
+}
+
+impl FrontendSettings {
+    fn falls_back(&self, path: &str) -> bool {
+        self.fallback_prefixes.is_empty()
+            || self
+                .fallback_prefixes
+                .iter()
+                .any(|prefix| path.starts_with(prefix.as_str()))
+    }
+}
+
+/// Middleware that serves `index.html` in place of frontend files that don't
+/// exist, so the frontend's router can handle those paths, and that sets the
+/// `Cache-Control` headers of frontend files.
+struct FrontendFiles {
+    settings: Rc<FrontendSettings>,
+}
+
+impl<S> Transform<S> for FrontendFiles
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse, Error = actix_web::Error>,
+    S::Future: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse;
+    type Error = actix_web::Error;
+    type Transform = FrontendFilesMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<result::Result<Self::Transform, ()>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        future::ok(FrontendFilesMiddleware {
+            service,
+            settings: self.settings.clone(),
+        })
+    }
+}
+
+struct FrontendFilesMiddleware<S> {
+    service: S,
+    settings: Rc<FrontendSettings>,
+}
+
+impl<S> Service for FrontendFilesMiddleware<S>
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse, Error = actix_web::Error>,
+    S::Future: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse;
+    type Error = actix_web::Error;
+    type Future = Pin<Box<dyn Future<Output = result::Result<Self::Response, Self::Error>>>>;
+
+    fn poll_ready(&mut self, ctx: &mut Context<'_>) -> Poll<result::Result<(), Self::Error>> {
+        self.service.poll_ready(ctx)
+    }
+
+    fn call(&mut self, req: Self::Request) -> Self::Future {
+        let settings = self.settings.clone();
+        let path = req.path().to_string();
+        let fut = self.service.call(req);
+
+        Box::pin(async move {
+            let mut res = fut.await?;
+            let mut is_index = matches!(path.trim_start_matches('/'), "" | INDEX_FILE);
+
+            if res.status() == StatusCode::NOT_FOUND && settings.falls_back(&path) {
+                if let Some((data, mime_type)) = settings.index {
+                    res = res.into_response(HttpResponse::Ok().content_type(mime_type).body(data));
+                    is_index = true;
+                }
+            }
+
+            let cache_control = if is_index {
+                &settings.index_cache_control
+            } else {
+                &settings.asset_cache_control
+            };
+            if let Some(cache_control) = cache_control {
+                if res.status().is_success() || res.status() == StatusCode::NOT_MODIFIED {
+                    res.headers_mut()
+                        .insert(header::CACHE_CONTROL, cache_control.clone());
+                }
+            }
+
+            Ok(res)
+        })
+    }
+}
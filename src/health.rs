@@ -0,0 +1,27 @@
+use crate::cdn::index::IndexHandle;
+use actix_web::{dev::HttpResponseBuilder, http::StatusCode, web, HttpResponse};
+use serde::Serialize;
+
+#[derive(Serialize)]
+struct HealthJson {
+    status: &'static str,
+}
+
+/// Answers as long as the server is running. Never touches the index, so it
+/// stays responsive during a long reindex.
+#[get("/healthz")]
+pub async fn get_healthz() -> HttpResponse {
+    HttpResponseBuilder::new(StatusCode::OK).json(HealthJson { status: "ok" })
+}
+
+/// Answers `503 Service Unavailable` until the library has been indexed for
+/// the first time.
+#[get("/readyz")]
+pub async fn get_readyz(index: web::Data<IndexHandle>) -> HttpResponse {
+    if index.is_ready() {
+        HttpResponseBuilder::new(StatusCode::OK).json(HealthJson { status: "ok" })
+    } else {
+        HttpResponseBuilder::new(StatusCode::SERVICE_UNAVAILABLE)
+            .json(HealthJson { status: "indexing" })
+    }
+}
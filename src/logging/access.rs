@@ -0,0 +1,94 @@
+use actix_service::{Service, Transform};
+use actix_web::{
+    dev::{ServiceRequest, ServiceResponse},
+    http::StatusCode,
+};
+use futures::{
+    future,
+    future::Ready,
+    task::{Context, Poll},
+};
+use log::Level;
+use std::{future::Future, pin::Pin, result};
+
+/// Middleware that logs requests passing through it, logging requests that end
+/// up as `404 Not Found` at a configurable level.
+pub struct NotFoundLogger {
+    level: Option<Level>,
+}
+
+impl NotFoundLogger {
+    /// Creates a new not-found logger. A level of `None` disables logging of
+    /// `404`s.
+    pub fn new(level: Option<Level>) -> NotFoundLogger {
+        NotFoundLogger { level }
+    }
+}
+
+impl<S, B> Transform<S> for NotFoundLogger
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = actix_web::Error;
+    type Transform = NotFoundLoggerMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<result::Result<Self::Transform, ()>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        future::ok(NotFoundLoggerMiddleware {
+            service,
+            level: self.level,
+        })
+    }
+}
+
+pub struct NotFoundLoggerMiddleware<S> {
+    service: S,
+    level: Option<Level>,
+}
+
+impl<S, B> Service for NotFoundLoggerMiddleware<S>
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = actix_web::Error;
+    type Future = Pin<Box<dyn Future<Output = result::Result<Self::Response, Self::Error>>>>;
+
+    fn poll_ready(&mut self, ctx: &mut Context<'_>) -> Poll<result::Result<(), Self::Error>> {
+        self.service.poll_ready(ctx)
+    }
+
+    fn call(&mut self, req: Self::Request) -> Self::Future {
+        let level = self.level;
+        let method = req.method().clone();
+        let path = req.path().to_string();
+        let fut = self.service.call(req);
+
+        Box::pin(async move {
+            let res = fut.await;
+
+            let status = match &res {
+                Ok(res) => res.status(),
+                Err(e) => e.as_response_error().status_code(),
+            };
+
+            if status == StatusCode::NOT_FOUND {
+                if let Some(level) = level {
+                    log!(level, "Not found: {} {}", method, path);
+                }
+            } else {
+                debug!("{} {} -> {}", method, path, status.as_u16());
+            }
+
+            res
+        })
+    }
+}
@@ -1,14 +1,18 @@
+pub mod access;
 mod fancy_file;
 mod ffmpeg;
 
 use crate::logging::fancy_file::FancyFileAppenderDeserializer;
+use log::LevelFilter;
 use log4rs::config::Deserializers;
-use std::{fs::OpenOptions, io::Write, path::Path};
+use std::{env, fs::OpenOptions, io::Write, path::Path, str::FromStr};
 
 pub use ffmpeg::log_callback;
 
 const DEFAULT_CONFIG_FILE: &str = "music-server-1.log4rs.yaml";
 const DEFAULT_CONFIG: &[u8] = include_bytes!("default.log4rs.yaml");
+/// Env var overriding the root logger level of the config file.
+const LEVEL_ENV: &str = "RUST_LOG";
 
 pub fn init() {
     let config_file_path = Path::new(DEFAULT_CONFIG_FILE);
@@ -24,5 +28,19 @@ pub fn init() {
     let mut deserializers = Deserializers::new();
     deserializers.insert("fancy_file", FancyFileAppenderDeserializer);
 
-    log4rs::init_file(config_file_path, deserializers).unwrap();
+    let mut config = log4rs::config::load_config_file(config_file_path, deserializers).unwrap();
+    let level = env::var(LEVEL_ENV).ok().filter(|level| !level.is_empty());
+    let parsed = level.as_deref().map(LevelFilter::from_str);
+    if let Some(Ok(level)) = parsed {
+        config.root_mut().set_level(level);
+    }
+
+    log4rs::init_config(config).unwrap();
+
+    if let (Some(level), Some(Err(_))) = (level, parsed) {
+        warn!(
+            "Ignoring {}={}, only a single level like debug is supported",
+            LEVEL_ENV, level
+        );
+    }
 }
@@ -12,20 +12,32 @@ extern crate log;
 mod cdn;
 mod config;
 mod error;
+#[cfg(feature = "frontend")]
+mod frontend;
+mod health;
 mod logging;
+mod metrics;
+mod openapi;
 mod util;
 
 use crate::{
-    cdn::index::Index,
+    cdn::{covers::CoverCache, index::IndexHandle, transcode::TranscodeCache, watch},
     config::Config,
     error::{Result, ResultExt},
-    util::ffmpeg,
+    metrics::{Metrics, RequestMetrics},
+    util::{cors, ffmpeg, redirect::HttpsRedirect, shutdown, tls},
 };
-use actix_web::{middleware::DefaultHeaders, web::Data, App, HttpServer};
-use std::process::exit;
+use actix_web::{
+    middleware::{Compress, Condition, Logger},
+    web::Data,
+    App, HttpServer,
+};
+use std::{net::ToSocketAddrs, process::exit, sync::Arc};
 
 const FILES_URL: &str = "/cdn/files";
+const INDEX_URL: &str = "/cdn/index";
 
+#[cfg(feature = "frontend")]
 mod generated_files {
     include!(concat!(env!("OUT_DIR"), "/generated.rs"));
 }
@@ -33,53 +45,129 @@ mod generated_files {
 async fn run() -> Result<()> {
     let config = Config::load()?;
 
-    let base_dir = config.base_dir.clone();
+    let mut redirect_addrs = vec![];
+    let mut https_port = None;
+    for binding in config.bindings.iter() {
+        if binding.redirect_to_https {
+            redirect_addrs.extend(
+                binding
+                    .address
+                    .to_socket_addrs()
+                    .chain_err(|| "Error resolving a redirect binding")?,
+            );
+        }
+        if binding.tls && https_port.is_none() {
+            https_port = binding
+                .address
+                .to_socket_addrs()
+                .chain_err(|| "Error resolving a TLS binding")?
+                .next()
+                .map(|addr| addr.port());
+        }
+    }
+    // redirect to the default port when HTTPS is served by something else
+    let https_port = https_port.unwrap_or(443);
 
-    ffmpeg::init_ffmpeg()?;
-    let index = Index::index(
-        &base_dir,
+    ffmpeg::init_ffmpeg(&config)?;
+    let metrics = Arc::new(Metrics::default());
+    let index_data = Data::new(IndexHandle::empty(
+        &config,
         FILES_URL,
-        &config.media_include_patterns,
-        &config.media_exclude_patterns,
-        &config.cover_include_patterns,
-        &config.cover_exclude_patterns,
-    )
-    .await?;
-    let index_data = Data::new(index);
+        Some(metrics.clone()).filter(|_| config.metrics),
+    ));
+    if config.watch {
+        watch::start(&config, index_data.clone())?;
+    }
+    let transcode_data = Data::new(TranscodeCache::new(&config));
+    let cover_data = Data::new(CoverCache::new(&config));
 
+    let load_index = index_data.clone();
     let server_config = config.clone();
     let mut server = HttpServer::new(move || {
-        let generated = generated_files::generate();
         let index_data = index_data.clone();
+        let transcode_data = transcode_data.clone();
+        let cover_data = cover_data.clone();
         let config = server_config.clone();
-
-        #[allow(unused_mut)]
-        let mut app = App::new().app_data(index_data);
-
-        // allows CORS from development server to api server
-        #[cfg(debug_assertions)]
-        let mut app = app.wrap(
-            DefaultHeaders::new().header("Access-Control-Allow-Origin", "http://localhost:4200"),
-        );
-
-        app = app.service(cdn::apply_services(&config));
-        app = app.service(
-            actix_web_static_files::ResourceFiles::new("/", generated).resolve_not_found_to_root(),
-        );
+        let redirect_addrs = redirect_addrs.clone();
+        let metrics = metrics.clone();
+
+        let mut app = App::new()
+            .app_data(index_data)
+            .app_data(transcode_data)
+            .app_data(cover_data)
+            .wrap(Condition::new(config.compress, Compress::default()))
+            .wrap(HttpsRedirect::new(redirect_addrs, https_port))
+            .wrap(Condition::new(
+                !config.cors_allowed_origins.is_empty(),
+                cors::cors(&config.cors_allowed_origins),
+            ))
+            .wrap(Condition::new(
+                config.metrics,
+                RequestMetrics::new(metrics.clone()),
+            ))
+            .wrap(Condition::new(
+                config.access_log,
+                Logger::new(&config.access_log_format),
+            ));
+
+        app = app
+            .service(health::get_healthz)
+            .service(health::get_readyz)
+            .service(openapi::get_openapi)
+            .service(cdn::apply_services(&config));
+
+        if config.metrics {
+            app = app
+                .app_data(Data::from(metrics))
+                .service(metrics::get_metrics);
+        }
+
+        #[cfg(feature = "frontend")]
+        {
+            app = app.service(frontend::apply_services(
+                &config,
+                generated_files::generate(),
+            ));
+        }
 
         app
     });
 
+    let tls_config = match &config.tls {
+        Some(tls_paths) => Some(tls::load_server_config(tls_paths)?),
+        None => None,
+    };
+
     for binding in config.bindings.iter() {
-        server = server
-            .bind(binding.clone())
-            .chain_err(|| "Error binding the actix server")?;
+        server = match &tls_config {
+            Some(tls_config) if binding.tls => server
+                .bind_rustls(&binding.address, tls_config.clone())
+                .chain_err(|| "Error binding the actix server with TLS")?,
+            _ => server
+                .bind(&binding.address)
+                .chain_err(|| "Error binding the actix server")?,
+        };
     }
 
+    let server = server
+        .shutdown_timeout(config.shutdown_timeout)
+        .disable_signals()
+        .run();
+    shutdown::install(server.clone())?;
+
+    // the server answers health checks while the library is indexed
+    actix_web::rt::spawn(async move {
+        if let Err(e) = load_index.load().await {
+            e.log();
+            exit(1);
+        }
+    });
+
     server
-        .run()
         .await
-        .chain_err(|| "Error starting the actix server")
+        .chain_err(|| "Error starting the actix server")?;
+    info!("Server stopped");
+    Ok(())
 }
 
 #[actix_web::main]
@@ -0,0 +1,231 @@
+use crate::cdn::index::IndexHandle;
+use actix_service::{Service, Transform};
+use actix_web::{
+    dev::{HttpResponseBuilder, ServiceRequest, ServiceResponse},
+    http::StatusCode,
+    web, HttpResponse,
+};
+use futures::{
+    future,
+    future::Ready,
+    task::{Context, Poll},
+};
+use std::{
+    collections::BTreeMap,
+    fmt::Write,
+    future::Future,
+    pin::Pin,
+    result,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+/// Upper bounds in seconds of the reindex duration histogram's buckets.
+const REINDEX_BUCKETS: [f64; 8] = [0.1, 0.5, 1.0, 5.0, 10.0, 30.0, 60.0, 300.0];
+
+/// Usage counters exported in the Prometheus text format at `/metrics`.
+#[derive(Debug, Default)]
+pub struct Metrics {
+    /// Request counts keyed by method, route pattern and status.
+    requests: Mutex<BTreeMap<(String, String, u16), u64>>,
+    bytes_served: AtomicU64,
+    reindexes: Mutex<Histogram>,
+}
+
+#[derive(Debug, Default)]
+struct Histogram {
+    /// Non-cumulative counts for each of the `REINDEX_BUCKETS`.
+    buckets: [u64; REINDEX_BUCKETS.len()],
+    count: u64,
+    sum: f64,
+}
+
+impl Metrics {
+    fn record_request(&self, method: &str, endpoint: String, status: StatusCode) {
+        let mut requests = self.requests.lock().unwrap();
+        *requests
+            .entry((method.to_string(), endpoint, status.as_u16()))
+            .or_insert(0) += 1;
+    }
+
+    /// Counts bytes of media, cover and document files sent to clients.
+    pub fn add_bytes_served(&self, bytes: u64) {
+        self.bytes_served.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    pub fn record_reindex(&self, duration: Duration) {
+        let seconds = duration.as_secs_f64();
+        let mut reindexes = self.reindexes.lock().unwrap();
+        if let Some(bucket) = REINDEX_BUCKETS.iter().position(|&max| seconds <= max) {
+            reindexes.buckets[bucket] += 1;
+        }
+        reindexes.count += 1;
+        reindexes.sum += seconds;
+    }
+
+    fn render(&self, song_count: usize) -> String {
+        let mut out = String::new();
+
+        out.push_str("# HELP music_server_http_requests_total Requests handled.\n");
+        out.push_str("# TYPE music_server_http_requests_total counter\n");
+        for ((method, endpoint, status), count) in self.requests.lock().unwrap().iter() {
+            writeln!(
+                out,
+                "music_server_http_requests_total{{method=\"{}\",endpoint=\"{}\",status=\"{}\"}} {}",
+                escape_label(method),
+                escape_label(endpoint),
+                status,
+                count
+            )
+            .unwrap();
+        }
+
+        out.push_str("# HELP music_server_files_bytes_served_total Bytes of files served.\n");
+        out.push_str("# TYPE music_server_files_bytes_served_total counter\n");
+        writeln!(
+            out,
+            "music_server_files_bytes_served_total {}",
+            self.bytes_served.load(Ordering::Relaxed)
+        )
+        .unwrap();
+
+        out.push_str("# HELP music_server_index_songs Songs in the current index.\n");
+        out.push_str("# TYPE music_server_index_songs gauge\n");
+        writeln!(out, "music_server_index_songs {}", song_count).unwrap();
+
+        let reindexes = self.reindexes.lock().unwrap();
+        out.push_str("# HELP music_server_reindex_duration_seconds Time taken by reindexes.\n");
+        out.push_str("# TYPE music_server_reindex_duration_seconds histogram\n");
+        let mut cumulative = 0;
+        for (max, count) in REINDEX_BUCKETS.iter().zip(reindexes.buckets.iter()) {
+            cumulative += count;
+            writeln!(
+                out,
+                "music_server_reindex_duration_seconds_bucket{{le=\"{}\"}} {}",
+                max, cumulative
+            )
+            .unwrap();
+        }
+        writeln!(
+            out,
+            "music_server_reindex_duration_seconds_bucket{{le=\"+Inf\"}} {}",
+            reindexes.count
+        )
+        .unwrap();
+        writeln!(
+            out,
+            "music_server_reindex_duration_seconds_sum {}",
+            reindexes.sum
+        )
+        .unwrap();
+        writeln!(
+            out,
+            "music_server_reindex_duration_seconds_count {}",
+            reindexes.count
+        )
+        .unwrap();
+
+        out
+    }
+}
+
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+#[get("/metrics")]
+pub async fn get_metrics(
+    metrics: web::Data<Metrics>,
+    index: web::Data<IndexHandle>,
+) -> HttpResponse {
+    let song_count = index.get().await.song_count().await;
+
+    HttpResponseBuilder::new(StatusCode::OK)
+        .content_type("text/plain; version=0.0.4; charset=utf-8")
+        .body(metrics.render(song_count))
+}
+
+/// Middleware counting requests by the route pattern they matched, so urls
+/// with different album and song names share one counter.
+pub struct RequestMetrics {
+    metrics: Arc<Metrics>,
+}
+
+impl RequestMetrics {
+    pub fn new(metrics: Arc<Metrics>) -> RequestMetrics {
+        RequestMetrics { metrics }
+    }
+}
+
+impl<S, B> Transform<S> for RequestMetrics
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = actix_web::Error;
+    type Transform = RequestMetricsMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<result::Result<Self::Transform, ()>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        future::ok(RequestMetricsMiddleware {
+            service,
+            metrics: self.metrics.clone(),
+        })
+    }
+}
+
+pub struct RequestMetricsMiddleware<S> {
+    service: S,
+    metrics: Arc<Metrics>,
+}
+
+impl<S, B> Service for RequestMetricsMiddleware<S>
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = actix_web::Error;
+    type Future = Pin<Box<dyn Future<Output = result::Result<Self::Response, Self::Error>>>>;
+
+    fn poll_ready(&mut self, ctx: &mut Context<'_>) -> Poll<result::Result<(), Self::Error>> {
+        self.service.poll_ready(ctx)
+    }
+
+    fn call(&mut self, req: Self::Request) -> Self::Future {
+        let method = req.method().clone();
+        let metrics = self.metrics.clone();
+        let fut = self.service.call(req);
+
+        Box::pin(async move {
+            let res = fut.await;
+            match &res {
+                Ok(res) => metrics.record_request(
+                    method.as_str(),
+                    res.request()
+                        .match_pattern()
+                        .unwrap_or_else(|| "unmatched".to_string()),
+                    res.status(),
+                ),
+                Err(e) => metrics.record_request(
+                    method.as_str(),
+                    "unmatched".to_string(),
+                    e.as_response_error().status_code(),
+                ),
+            }
+            res
+        })
+    }
+}
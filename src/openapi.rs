@@ -0,0 +1,12 @@
+use actix_web::{dev::HttpResponseBuilder, http::StatusCode, HttpResponse};
+
+/// OpenAPI 3 description of the api. Written by hand, so it has to be updated
+/// along with the endpoints and json types of `cdn::index`.
+const OPENAPI: &str = include_str!("openapi.json");
+
+#[get("/openapi.json")]
+pub async fn get_openapi() -> HttpResponse {
+    HttpResponseBuilder::new(StatusCode::OK)
+        .content_type("application/json")
+        .body(OPENAPI)
+}
@@ -0,0 +1,38 @@
+use crate::{
+    config::Config,
+    error::{ErrorKind, Result},
+};
+use actix_web::{http::header, HttpRequest};
+
+/// Checks that a request carries the admin token as a bearer token.
+pub fn require_admin(req: &HttpRequest, config: &Config) -> Result<()> {
+    let expected = match &config.admin_token {
+        Some(token) => token,
+        None => bail!(ErrorKind::Unauthorized),
+    };
+
+    let token = req
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .map(|token| token.trim());
+
+    match token {
+        Some(token) if constant_time_eq(token.as_bytes(), expected.as_bytes()) => Ok(()),
+        _ => bail!(ErrorKind::Unauthorized),
+    }
+}
+
+/// Compares two byte strings in time independent of where they differ, so the
+/// token can't be guessed byte by byte.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+
+    a.iter()
+        .zip(b.iter())
+        .fold(0u8, |acc, (a, b)| acc | (a ^ b))
+        == 0
+}
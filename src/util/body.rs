@@ -0,0 +1,50 @@
+use crate::error::Result;
+use actix_web::web::Bytes;
+use futures::{channel::mpsc, executor, SinkExt};
+use std::{io, io::Write};
+
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// Writes a streaming response body from a blocking thread, sending it in
+/// chunks. Writing blocks while the client is behind and fails once the
+/// response is dropped.
+pub struct BodyWriter {
+    tx: mpsc::Sender<Result<Bytes>>,
+    buf: Vec<u8>,
+}
+
+impl BodyWriter {
+    pub fn new(tx: mpsc::Sender<Result<Bytes>>) -> BodyWriter {
+        BodyWriter {
+            tx,
+            buf: Vec::with_capacity(CHUNK_SIZE),
+        }
+    }
+
+    fn send(&mut self) -> io::Result<()> {
+        if self.buf.is_empty() {
+            return Ok(());
+        }
+
+        let chunk = Bytes::copy_from_slice(&self.buf);
+        self.buf.clear();
+        executor::block_on(self.tx.send(Ok(chunk)))
+            .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "response was dropped"))
+    }
+}
+
+impl Write for BodyWriter {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        let len = data.len().min(CHUNK_SIZE - self.buf.len());
+        self.buf.extend_from_slice(&data[..len]);
+        if self.buf.len() >= CHUNK_SIZE {
+            self.send()?;
+        }
+
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.send()
+    }
+}
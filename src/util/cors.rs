@@ -0,0 +1,18 @@
+use actix_cors::Cors;
+
+/// Builds the CORS middleware allowing requests from `origins`. A `*` origin
+/// allows any origin. The origin of allowed requests is reflected back instead
+/// of answering with a wildcard, so credentials keep working.
+pub fn cors(origins: &[String]) -> Cors {
+    let cors = if origins.iter().any(|origin| origin == "*") {
+        Cors::default().allow_any_origin()
+    } else {
+        origins
+            .iter()
+            .fold(Cors::default(), |cors, origin| cors.allowed_origin(origin))
+    };
+
+    cors.allowed_methods(vec!["GET", "POST", "PUT"])
+        .allow_any_header()
+        .max_age(3600)
+}
@@ -1,12 +1,319 @@
 use crate::{
+    config::Config,
     error::{Result, ResultExt},
-    logging,
+    logging, path_error,
 };
+use ffmpeg4::{
+    codec, encoder, filter, format, frame, media, ChannelLayout, Dictionary, Packet, Rational,
+};
+use futures::channel::{oneshot, oneshot::Canceled};
+use std::{path::Path, result, sync::Mutex};
+use threadpool::ThreadPool;
+
+lazy_static::lazy_static! {
+static ref POOL: Mutex<ThreadPool> = Mutex::new(ThreadPool::with_name("ffmpeg".to_string(), num_cpus::get()));
+}
 
-/// Initializes ffmpeg and the custom logging callback.
-pub fn init_ffmpeg() -> Result<()> {
+/// Initializes ffmpeg, the custom logging callback and the ffmpeg thread pool.
+pub fn init_ffmpeg(config: &Config) -> Result<()> {
     ffmpeg4::init().chain_err(|| "Initializing ffmpeg")?;
     unsafe { ffmpeg4_sys::av_log_set_callback(Some(logging::log_callback)) };
 
+    POOL.lock()
+        .unwrap()
+        .set_num_threads(config.ffmpeg_threads.max(1));
+
+    Ok(())
+}
+
+/// Runs blocking ffmpeg work on the dedicated ffmpeg thread pool, which keeps
+/// the number of ffmpeg contexts open at once bounded.
+///
+/// Fails if `f` panics.
+pub async fn spawn_blocking<F, R>(f: F) -> result::Result<R, Canceled>
+where
+    F: FnOnce() -> R + Send + 'static,
+    R: Send + 'static,
+{
+    let (tx, rx) = oneshot::channel();
+    POOL.lock().unwrap().execute(move || {
+        // the receiver only goes away if the request was dropped
+        let _ = tx.send(f());
+    });
+
+    rx.await
+}
+
+/// Describes the output of an audio transcode.
+pub struct TranscodeOptions {
+    /// The name of the ffmpeg muxer to write with, e.g. `mp3` or `hls`.
+    pub format: String,
+    /// The name of the ffmpeg encoder to encode with, e.g. `libmp3lame`.
+    pub encoder: String,
+    /// The target bit rate in bits per second.
+    pub bit_rate: usize,
+    /// Options passed to the muxer when writing the header.
+    pub muxer_options: Vec<(String, String)>,
+}
+
+/// Transcodes the best audio stream of `input` into `output`.
+///
+/// This does blocking work and should be run off-thread.
+pub fn transcode_audio(input: &Path, output: &Path, options: &TranscodeOptions) -> Result<()> {
+    let mut ictx =
+        format::input(&input).chain_err(path_error!(TranscodeError, input, "opening input"))?;
+    let mut octx = format::output_as(&output, &options.format).chain_err(path_error!(
+        TranscodeError,
+        output,
+        "opening output"
+    ))?;
+
+    let mut transcoder = Transcoder::new(&mut ictx, &mut octx, options).chain_err(path_error!(
+        TranscodeError,
+        input,
+        "setting up transcoder"
+    ))?;
+
+    let mut muxer_options = Dictionary::new();
+    for (key, value) in options.muxer_options.iter() {
+        muxer_options.set(key, value);
+    }
+
+    octx.set_metadata(ictx.metadata().to_owned());
+    octx.write_header_with(muxer_options)
+        .chain_err(path_error!(TranscodeError, output, "writing header"))?;
+
+    let in_time_base = transcoder.decoder.time_base();
+    let encoder_time_base = transcoder.encoder.time_base();
+    let out_time_base = octx
+        .stream(0)
+        .chain_err(path_error!(TranscodeError, output, "finding output stream"))?
+        .time_base();
+
+    let mut decoded = frame::Audio::empty();
+    let mut filtered = frame::Audio::empty();
+    let mut encoded = Packet::empty();
+
+    for (stream, mut packet) in ictx.packets() {
+        if stream.index() == transcoder.stream {
+            packet.rescale_ts(stream.time_base(), in_time_base);
+
+            if let Ok(true) = transcoder.decoder.decode(&packet, &mut decoded) {
+                let timestamp = decoded.timestamp();
+                decoded.set_pts(timestamp);
+
+                transcoder.add_frame(&decoded).chain_err(path_error!(
+                    TranscodeError,
+                    input,
+                    "filtering frame"
+                ))?;
+                transcoder
+                    .drain(
+                        &mut filtered,
+                        &mut encoded,
+                        &mut octx,
+                        encoder_time_base,
+                        out_time_base,
+                    )
+                    .chain_err(path_error!(TranscodeError, output, "encoding frame"))?;
+            }
+        }
+    }
+
+    transcoder
+        .flush(
+            &mut filtered,
+            &mut encoded,
+            &mut octx,
+            encoder_time_base,
+            out_time_base,
+        )
+        .chain_err(path_error!(TranscodeError, output, "flushing encoder"))?;
+
+    octx.write_trailer()
+        .chain_err(path_error!(TranscodeError, output, "writing trailer"))?;
+
     Ok(())
 }
+
+struct Transcoder {
+    stream: usize,
+    filter: filter::Graph,
+    decoder: codec::decoder::Audio,
+    encoder: codec::encoder::Audio,
+}
+
+impl Transcoder {
+    fn new(
+        ictx: &mut format::context::Input,
+        octx: &mut format::context::Output,
+        options: &TranscodeOptions,
+    ) -> result::Result<Transcoder, ffmpeg4::Error> {
+        let input = ictx
+            .streams()
+            .best(media::Type::Audio)
+            .ok_or(ffmpeg4::Error::StreamNotFound)?;
+        let stream = input.index();
+
+        let mut decoder = input.codec().decoder().audio()?;
+        decoder.set_parameters(input.parameters())?;
+
+        // some containers (e.g. wav) don't report a channel layout
+        let in_layout = if decoder.channel_layout().is_empty() {
+            ChannelLayout::default(decoder.channels() as i32)
+        } else {
+            decoder.channel_layout()
+        };
+
+        let codec = encoder::find_by_name(&options.encoder)
+            .ok_or(ffmpeg4::Error::EncoderNotFound)?
+            .audio()?;
+        let global = octx
+            .format()
+            .flags()
+            .contains(format::flag::Flags::GLOBAL_HEADER);
+
+        let channel_layout = codec
+            .channel_layouts()
+            .map(|layouts| layouts.best(in_layout.channels()))
+            .unwrap_or(ChannelLayout::STEREO);
+
+        // use the input sample rate if the encoder supports it
+        let rate = match codec.rates() {
+            Some(rates) => {
+                let rates: Vec<i32> = rates.collect();
+                if rates.is_empty() || rates.contains(&(decoder.rate() as i32)) {
+                    decoder.rate() as i32
+                } else {
+                    *rates.iter().max().unwrap()
+                }
+            }
+            None => decoder.rate() as i32,
+        };
+
+        let sample_format = codec
+            .formats()
+            .and_then(|mut formats| formats.next())
+            .ok_or(ffmpeg4::Error::InvalidData)?;
+
+        let mut output = octx.add_stream(codec)?;
+        let mut encoder = output.codec().encoder().audio()?;
+
+        if global {
+            encoder.set_flags(codec::flag::Flags::GLOBAL_HEADER);
+        }
+
+        encoder.set_rate(rate);
+        encoder.set_channel_layout(channel_layout);
+        encoder.set_channels(channel_layout.channels());
+        encoder.set_format(sample_format);
+        encoder.set_bit_rate(options.bit_rate);
+
+        encoder.set_time_base((1, rate));
+        output.set_time_base((1, rate));
+
+        let encoder = encoder.open_as(codec)?;
+        output.set_parameters(&encoder);
+
+        let mut filter = filter::Graph::new();
+
+        let args = format!(
+            "time_base={}:sample_rate={}:sample_fmt={}:channel_layout=0x{:x}",
+            decoder.time_base(),
+            decoder.rate(),
+            decoder.format().name(),
+            in_layout.bits()
+        );
+
+        filter.add(
+            &filter::find("abuffer").ok_or(ffmpeg4::Error::FilterNotFound)?,
+            "in",
+            &args,
+        )?;
+        filter.add(
+            &filter::find("abuffersink").ok_or(ffmpeg4::Error::FilterNotFound)?,
+            "out",
+            "",
+        )?;
+
+        {
+            let mut out = filter.get("out").unwrap();
+
+            out.set_sample_format(encoder.format());
+            out.set_channel_layout(encoder.channel_layout());
+            out.set_sample_rate(encoder.rate());
+        }
+
+        filter.output("in", 0)?.input("out", 0)?.parse("anull")?;
+        filter.validate()?;
+
+        // encoders like aac and mp3 need exactly frame_size samples per frame
+        if !codec
+            .capabilities()
+            .contains(codec::capabilities::Capabilities::VARIABLE_FRAME_SIZE)
+        {
+            filter
+                .get("out")
+                .unwrap()
+                .sink()
+                .set_frame_size(encoder.frame_size());
+        }
+
+        Ok(Transcoder {
+            stream,
+            filter,
+            decoder,
+            encoder,
+        })
+    }
+
+    fn add_frame(&mut self, frame: &frame::Audio) -> result::Result<(), ffmpeg4::Error> {
+        self.filter.get("in").unwrap().source().add(frame)
+    }
+
+    fn drain(
+        &mut self,
+        filtered: &mut frame::Audio,
+        encoded: &mut Packet,
+        octx: &mut format::context::Output,
+        encoder_time_base: Rational,
+        out_time_base: Rational,
+    ) -> result::Result<(), ffmpeg4::Error> {
+        while self
+            .filter
+            .get("out")
+            .unwrap()
+            .sink()
+            .frame(filtered)
+            .is_ok()
+        {
+            if let Ok(true) = self.encoder.encode(filtered, encoded) {
+                encoded.set_stream(0);
+                encoded.rescale_ts(encoder_time_base, out_time_base);
+                encoded.write_interleaved(octx)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn flush(
+        &mut self,
+        filtered: &mut frame::Audio,
+        encoded: &mut Packet,
+        octx: &mut format::context::Output,
+        encoder_time_base: Rational,
+        out_time_base: Rational,
+    ) -> result::Result<(), ffmpeg4::Error> {
+        self.filter.get("in").unwrap().source().flush()?;
+        self.drain(filtered, encoded, octx, encoder_time_base, out_time_base)?;
+
+        while let Ok(true) = self.encoder.flush(encoded) {
+            encoded.set_stream(0);
+            encoded.rescale_ts(encoder_time_base, out_time_base);
+            encoded.write_interleaved(octx)?;
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,80 @@
+use std::{
+    ffi::OsString,
+    fs, io,
+    path::Path,
+    process,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+/// Numbers temporary files, so concurrent writers of a file never share one.
+static TMP_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+/// Writes `data` to `path` through a temporary file next to it that is then
+/// renamed into place, so a partial file is never read, not even after a
+/// crash.
+///
+/// The temporary file is hidden, keeps the whole file name and is unique to
+/// this write, so concurrent writers of the same file each rename a complete
+/// file of their own into place.
+pub fn write_atomic(path: &Path, data: &[u8]) -> io::Result<()> {
+    let name = path
+        .file_name()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "path has no file name"))?;
+    let mut tmp_name = OsString::from(".");
+    tmp_name.push(name);
+    tmp_name.push(format!(
+        ".{}-{}.tmp",
+        process::id(),
+        TMP_COUNTER.fetch_add(1, Ordering::Relaxed)
+    ));
+    let tmp = path.with_file_name(tmp_name);
+
+    fs::write(&tmp, data)?;
+    fs::rename(&tmp, path).map_err(|e| {
+        let _ = fs::remove_file(&tmp);
+        e
+    })
+}
+
+/// Checks whether `output`, generated from `source`, is at least as new as
+/// `source`, so that it can be reused instead of being generated again.
+pub fn is_fresh(output: &Path, source: &Path) -> bool {
+    let modified = |path: &Path| path.metadata().and_then(|m| m.modified()).ok();
+
+    match (modified(output), modified(source)) {
+        (Some(output), Some(source)) => output >= source,
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{sync::Arc, thread};
+
+    #[test]
+    fn concurrent_writers_each_write_whole_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = Arc::new(dir.path().join("file"));
+
+        let writers: Vec<_> = (0..8u8)
+            .map(|i| {
+                let path = path.clone();
+                thread::spawn(move || {
+                    for _ in 0..20 {
+                        write_atomic(&path, &[i; 64 * 1024]).unwrap();
+                    }
+                })
+            })
+            .collect();
+        for writer in writers {
+            writer.join().unwrap();
+        }
+
+        let data = fs::read(&*path).unwrap();
+        assert_eq!(data.len(), 64 * 1024);
+        assert!(data.iter().all(|&byte| byte == data[0]));
+        // no temporary files are left behind
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 1);
+    }
+}
@@ -0,0 +1,42 @@
+use sha2::{Digest, Sha256};
+use std::{
+    fs::File,
+    io,
+    io::{Read, Seek, SeekFrom},
+    path::Path,
+};
+
+/// How many bytes from each end of a file are hashed.
+const HASH_CHUNK_SIZE: u64 = 64 * 1024;
+
+/// Cheaply hashes a file's contents by hashing its size and its first and last
+/// few kilobytes, returning the hash as a hex string.
+pub fn hash_file(path: &Path) -> io::Result<String> {
+    let mut file = File::open(path)?;
+    let size = file.metadata()?.len();
+
+    let mut hasher = Sha256::new();
+    hasher.update(size.to_le_bytes());
+
+    let mut buf = vec![];
+    (&mut file).take(HASH_CHUNK_SIZE).read_to_end(&mut buf)?;
+    hasher.update(&buf);
+
+    if size > HASH_CHUNK_SIZE {
+        buf.clear();
+        file.seek(SeekFrom::Start(
+            size.saturating_sub(HASH_CHUNK_SIZE).max(HASH_CHUNK_SIZE),
+        ))?;
+        file.read_to_end(&mut buf)?;
+        hasher.update(&buf);
+    }
+
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+/// Hashes a path, returning the hash as a hex string.
+pub fn hash_path(path: &Path) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(path.to_string_lossy().as_bytes());
+    format!("{:x}", hasher.finalize())
+}
@@ -1,4 +1,28 @@
+pub mod auth;
+pub mod body;
+pub mod cors;
 pub mod ffmpeg;
+pub mod fs;
+pub mod hash;
+pub mod redirect;
+pub mod shutdown;
+pub mod tls;
+pub mod zip;
+
+/// Makes a `chain_err` closure building an error of `kind` about the file at
+/// `path`, e.g. `path_error!(IndexingError, path, "opening manifest")`.
+#[macro_export]
+macro_rules! path_error {
+    ($kind:ident, $path:expr, $desc:expr) => {
+        || {
+            let path: &std::path::Path = $path.as_ref();
+            // some kinds take an optional path
+            #[allow(clippy::useless_conversion)]
+            let path = path.to_string_lossy().to_string().into();
+            $crate::error::ErrorKind::$kind(path, $desc)
+        }
+    };
+}
 
 // Result wrapper functions
 
@@ -0,0 +1,145 @@
+use crate::error::{Error, ErrorKind};
+use actix_service::{Service, Transform};
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use futures::{
+    future,
+    future::{ok, Either, Ready},
+    task::{Context, Poll},
+};
+use std::{net::SocketAddr, rc::Rc, result};
+
+/// Middleware that redirects every request received on one of the given local
+/// addresses to the same url over HTTPS on `https_port`.
+pub struct HttpsRedirect {
+    addrs: Rc<Vec<SocketAddr>>,
+    https_port: u16,
+}
+
+impl HttpsRedirect {
+    pub fn new(addrs: Vec<SocketAddr>, https_port: u16) -> HttpsRedirect {
+        HttpsRedirect {
+            addrs: Rc::new(addrs),
+            https_port,
+        }
+    }
+}
+
+impl<S, B> Transform<S> for HttpsRedirect
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = actix_web::Error;
+    type Transform = HttpsRedirectMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<result::Result<Self::Transform, ()>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        future::ok(HttpsRedirectMiddleware {
+            service,
+            addrs: self.addrs.clone(),
+            https_port: self.https_port,
+        })
+    }
+}
+
+pub struct HttpsRedirectMiddleware<S> {
+    service: S,
+    addrs: Rc<Vec<SocketAddr>>,
+    https_port: u16,
+}
+
+impl<S, B> Service for HttpsRedirectMiddleware<S>
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = actix_web::Error;
+    type Future = Either<Ready<result::Result<Self::Response, Self::Error>>, S::Future>;
+
+    fn poll_ready(&mut self, ctx: &mut Context<'_>) -> Poll<result::Result<(), Self::Error>> {
+        self.service.poll_ready(ctx)
+    }
+
+    fn call(&mut self, req: Self::Request) -> Self::Future {
+        if !self.addrs.contains(&req.app_config().local_addr()) {
+            return Either::Right(self.service.call(req));
+        }
+
+        let url = {
+            let info = req.connection_info();
+            let host = info.host();
+            // drop the port of the plain binding, the redirect goes to the
+            // HTTPS one
+            let host = match host.rfind(':') {
+                Some(index) if !host.ends_with(']') => &host[..index],
+                _ => host,
+            };
+            let port = match self.https_port {
+                443 => String::new(),
+                port => format!(":{}", port),
+            };
+
+            format!(
+                "https://{}{}{}",
+                host,
+                port,
+                req.uri()
+                    .path_and_query()
+                    .map_or("/", |path_and_query| path_and_query.as_str())
+            )
+        };
+
+        Either::Left(ok(
+            req.error_response(Error::from_kind(ErrorKind::RedirectToHttps(url)))
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::{http::header, rt::System, test, web, App, HttpResponse};
+
+    fn redirect(https_port: u16) -> String {
+        System::new("test").block_on(async move {
+            // test requests are received on 127.0.0.1:8080
+            let mut app = test::init_service(
+                App::new()
+                    .wrap(HttpsRedirect::new(
+                        vec!["127.0.0.1:8080".parse().unwrap()],
+                        https_port,
+                    ))
+                    .route("/", web::get().to(HttpResponse::Ok)),
+            )
+            .await;
+            let req = test::TestRequest::get()
+                .uri("/cdn/index/all?limit=1")
+                .header(header::HOST, "music.example:8080")
+                .to_request();
+            let res = test::call_service(&mut app, req).await;
+
+            res.headers()
+                .get(header::LOCATION)
+                .unwrap()
+                .to_str()
+                .unwrap()
+                .to_string()
+        })
+    }
+
+    #[test]
+    fn redirects_keep_ports_other_than_443() {
+        assert_eq!(redirect(443), "https://music.example/cdn/index/all?limit=1");
+        assert_eq!(
+            redirect(8443),
+            "https://music.example:8443/cdn/index/all?limit=1"
+        );
+    }
+}
@@ -0,0 +1,81 @@
+use crate::error::{Result, ResultExt};
+use actix_web::dev::Server;
+
+/// Stops the server gracefully on `SIGTERM` or `SIGINT`.
+///
+/// The server stops accepting connections right away and waits for in-flight
+/// requests until its shutdown timeout runs out. A second signal stops it
+/// without waiting.
+pub fn install(server: Server) -> Result<()> {
+    let mut signals = Signals::new()?;
+
+    actix_web::rt::spawn(async move {
+        let name = signals.next().await;
+        info!(
+            "Received {}, waiting for in-flight requests to finish...",
+            name
+        );
+        actix_web::rt::spawn({
+            let server = server.clone();
+            async move { server.stop(true).await }
+        });
+
+        let name = signals.next().await;
+        warn!("Received {} again, stopping immediately", name);
+        server.stop(false).await;
+    });
+
+    Ok(())
+}
+
+#[cfg(unix)]
+struct Signals {
+    terminate: tokio::signal::unix::Signal,
+    interrupt: tokio::signal::unix::Signal,
+}
+
+#[cfg(unix)]
+impl Signals {
+    fn new() -> Result<Signals> {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        Ok(Signals {
+            terminate: signal(SignalKind::terminate())
+                .chain_err(|| "Error listening for SIGTERM")?,
+            interrupt: signal(SignalKind::interrupt())
+                .chain_err(|| "Error listening for SIGINT")?,
+        })
+    }
+
+    async fn next(&mut self) -> &'static str {
+        use futures::future::{select, Either};
+
+        match select(
+            Box::pin(self.terminate.recv()),
+            Box::pin(self.interrupt.recv()),
+        )
+        .await
+        {
+            Either::Left(_) => "SIGTERM",
+            Either::Right(_) => "SIGINT",
+        }
+    }
+}
+
+#[cfg(not(unix))]
+struct Signals;
+
+#[cfg(not(unix))]
+impl Signals {
+    fn new() -> Result<Signals> {
+        Ok(Signals)
+    }
+
+    async fn next(&mut self) -> &'static str {
+        if let Err(e) = tokio::signal::ctrl_c().await {
+            error!("Error listening for Ctrl-C: {}", e);
+            futures::future::pending::<()>().await;
+        }
+        "Ctrl-C"
+    }
+}
@@ -0,0 +1,51 @@
+use crate::{
+    config::TlsConfig,
+    error::{Result, ResultExt},
+};
+use rustls::{
+    internal::pemfile::{certs, pkcs8_private_keys, rsa_private_keys},
+    NoClientAuth, PrivateKey, ServerConfig,
+};
+use std::{fs::File, io::BufReader, path::Path};
+
+/// Builds the rustls config serving the configured certificate chain and key.
+///
+/// Keys can be either PKCS#8 or PKCS#1 (RSA) PEM files.
+pub fn load_server_config(tls: &TlsConfig) -> Result<ServerConfig> {
+    let cert_chain = certs(&mut open(&tls.cert)?).map_err(|_| {
+        format!(
+            "Error decoding certificates: {}",
+            tls.cert.to_string_lossy()
+        )
+    })?;
+    if cert_chain.is_empty() {
+        bail!("No certificates found in {}", tls.cert.to_string_lossy());
+    }
+
+    let key = load_key(&tls.key)?;
+
+    let mut config = ServerConfig::new(NoClientAuth::new());
+    config
+        .set_single_cert(cert_chain, key)
+        .chain_err(|| format!("Error using TLS key {}", tls.key.to_string_lossy()))?;
+    Ok(config)
+}
+
+fn load_key(path: &Path) -> Result<PrivateKey> {
+    let decode_error = || format!("Error decoding private key: {}", path.to_string_lossy());
+
+    let mut keys = pkcs8_private_keys(&mut open(path)?).map_err(|_| decode_error())?;
+    if keys.is_empty() {
+        keys = rsa_private_keys(&mut open(path)?).map_err(|_| decode_error())?;
+    }
+
+    keys.into_iter()
+        .next()
+        .ok_or_else(|| format!("No private key found in {}", path.to_string_lossy()).into())
+}
+
+fn open(path: &Path) -> Result<BufReader<File>> {
+    File::open(path)
+        .map(BufReader::new)
+        .chain_err(|| format!("Error opening {}", path.to_string_lossy()))
+}
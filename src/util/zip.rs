@@ -0,0 +1,258 @@
+use std::{
+    convert::TryFrom,
+    fs::File,
+    io,
+    io::{Read, Write},
+    path::Path,
+    sync::Mutex,
+};
+use threadpool::ThreadPool;
+
+const LOCAL_HEADER_SIGNATURE: u32 = 0x0403_4b50;
+const CENTRAL_HEADER_SIGNATURE: u32 = 0x0201_4b50;
+const END_SIGNATURE: u32 = 0x0605_4b50;
+/// Version 2.0, the lowest version most unzip tools expect.
+const ZIP_VERSION: u16 = 20;
+/// Marks file names as utf-8.
+const UTF8_FLAG: u16 = 1 << 11;
+const METHOD_STORED: u16 = 0;
+/// 1980-01-01 00:00 in dos format, the earliest date zip can represent.
+const DOS_TIME: u16 = 0;
+const DOS_DATE: u16 = (1 << 5) | 1;
+const COPY_CHUNK_SIZE: usize = 64 * 1024;
+/// The size of a local file header without the file name.
+const LOCAL_HEADER_SIZE: u64 = 30;
+/// How many zips are written at once. Writers block while their client is
+/// behind, so further zips wait in the queue for a free thread.
+const ZIP_THREADS: usize = 4;
+
+lazy_static::lazy_static! {
+static ref POOL: Mutex<ThreadPool> = Mutex::new(ThreadPool::with_name("zip".to_string(), ZIP_THREADS));
+}
+
+/// Runs `f`, which writes a zip, on the dedicated zip thread pool.
+pub fn spawn<F>(f: F)
+where
+    F: FnOnce() + Send + 'static,
+{
+    POOL.lock().unwrap().execute(f);
+}
+
+struct Entry {
+    name: String,
+    crc: u32,
+    size: u32,
+    offset: u32,
+}
+
+/// Writes a zip archive to a stream that can't seek, like a response body.
+///
+/// Files are stored without compression, which suits files that are already
+/// compressed, like images. Each file is read twice, first for its checksum
+/// and then for its contents, so that nothing has to be buffered.
+pub struct ZipWriter<W: Write> {
+    out: W,
+    offset: u64,
+    entries: Vec<Entry>,
+}
+
+impl<W: Write> ZipWriter<W> {
+    pub fn new(out: W) -> ZipWriter<W> {
+        ZipWriter {
+            out,
+            offset: 0,
+            entries: vec![],
+        }
+    }
+
+    /// Adds the file at `path` to the archive as `name`.
+    pub fn add_file(&mut self, name: &str, path: &Path) -> io::Result<()> {
+        let mut crc = crc32fast::Hasher::new();
+        let mut size = 0u64;
+        let mut buf = vec![0; COPY_CHUNK_SIZE];
+        let mut file = File::open(path)?;
+        loop {
+            let len = file.read(&mut buf)?;
+            if len == 0 {
+                break;
+            }
+            crc.update(&buf[..len]);
+            size += len as u64;
+        }
+
+        // fail before writing anything that would make the archive need zip64
+        to_u16(self.entries.len() + 1)?;
+        to_u32(self.offset + LOCAL_HEADER_SIZE + name.len() as u64 + size)?;
+
+        let entry = Entry {
+            name: name.to_string(),
+            crc: crc.finalize(),
+            size: to_u32(size)?,
+            offset: to_u32(self.offset)?,
+        };
+
+        let mut header = vec![];
+        put_u32(&mut header, LOCAL_HEADER_SIGNATURE);
+        put_u16(&mut header, ZIP_VERSION);
+        put_u16(&mut header, UTF8_FLAG);
+        put_u16(&mut header, METHOD_STORED);
+        put_u16(&mut header, DOS_TIME);
+        put_u16(&mut header, DOS_DATE);
+        put_u32(&mut header, entry.crc);
+        put_u32(&mut header, entry.size);
+        put_u32(&mut header, entry.size);
+        put_u16(&mut header, to_u16(entry.name.len())?);
+        put_u16(&mut header, 0);
+        header.extend_from_slice(entry.name.as_bytes());
+        self.write(&header)?;
+
+        // the file may have changed in between, which would corrupt the archive
+        let mut file = File::open(path)?.take(size);
+        let mut copied = 0u64;
+        loop {
+            let len = file.read(&mut buf)?;
+            if len == 0 {
+                break;
+            }
+            self.write(&buf[..len])?;
+            copied += len as u64;
+        }
+        if copied != size {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "file shrank while being archived",
+            ));
+        }
+
+        self.entries.push(entry);
+        Ok(())
+    }
+
+    /// Writes the archive's central directory, returning the underlying writer.
+    pub fn finish(mut self) -> io::Result<W> {
+        let directory_offset = to_u32(self.offset)?;
+
+        let mut directory = vec![];
+        for entry in self.entries.iter() {
+            put_u32(&mut directory, CENTRAL_HEADER_SIGNATURE);
+            put_u16(&mut directory, ZIP_VERSION);
+            put_u16(&mut directory, ZIP_VERSION);
+            put_u16(&mut directory, UTF8_FLAG);
+            put_u16(&mut directory, METHOD_STORED);
+            put_u16(&mut directory, DOS_TIME);
+            put_u16(&mut directory, DOS_DATE);
+            put_u32(&mut directory, entry.crc);
+            put_u32(&mut directory, entry.size);
+            put_u32(&mut directory, entry.size);
+            put_u16(&mut directory, to_u16(entry.name.len())?);
+            // extra field, comment, disk number, internal and external attributes
+            put_u16(&mut directory, 0);
+            put_u16(&mut directory, 0);
+            put_u16(&mut directory, 0);
+            put_u16(&mut directory, 0);
+            put_u32(&mut directory, 0);
+            put_u32(&mut directory, entry.offset);
+            directory.extend_from_slice(entry.name.as_bytes());
+        }
+
+        let directory_size = to_u32(directory.len() as u64)?;
+        let entry_count = to_u16(self.entries.len())?;
+        put_u32(&mut directory, END_SIGNATURE);
+        // this disk and the disk the directory starts on
+        put_u16(&mut directory, 0);
+        put_u16(&mut directory, 0);
+        put_u16(&mut directory, entry_count);
+        put_u16(&mut directory, entry_count);
+        put_u32(&mut directory, directory_size);
+        put_u32(&mut directory, directory_offset);
+        // comment length
+        put_u16(&mut directory, 0);
+        self.write(&directory)?;
+
+        self.out.flush()?;
+        Ok(self.out)
+    }
+
+    fn write(&mut self, data: &[u8]) -> io::Result<()> {
+        self.out.write_all(data)?;
+        self.offset += data.len() as u64;
+        Ok(())
+    }
+}
+
+fn put_u16(buf: &mut Vec<u8>, value: u16) {
+    buf.extend_from_slice(&value.to_le_bytes());
+}
+
+fn put_u32(buf: &mut Vec<u8>, value: u32) {
+    buf.extend_from_slice(&value.to_le_bytes());
+}
+
+/// Converts a count to a zip32 field. The maximum value marks the field as
+/// stored in a zip64 record, which this writer doesn't write.
+fn to_u16(value: usize) -> io::Result<u16> {
+    u16::try_from(value)
+        .ok()
+        .filter(|&value| value != u16::MAX)
+        .ok_or_else(too_large)
+}
+
+/// Converts a size or offset to a zip32 field, see `to_u16`.
+fn to_u32(value: u64) -> io::Result<u32> {
+    u32::try_from(value)
+        .ok()
+        .filter(|&value| value != u32::MAX)
+        .ok_or_else(too_large)
+}
+
+fn too_large() -> io::Error {
+    io::Error::new(io::ErrorKind::Other, "archive too large for zip32")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{fs, io::Cursor};
+
+    #[test]
+    fn written_zips_can_be_read_back() {
+        let dir = tempfile::tempdir().unwrap();
+        let cover = dir.path().join("cover.jpg");
+        let empty = dir.path().join("empty.txt");
+        let contents: Vec<u8> = (0..200_000u32).map(|i| (i % 251) as u8).collect();
+        fs::write(&cover, &contents).unwrap();
+        fs::write(&empty, b"").unwrap();
+
+        let mut zip = ZipWriter::new(vec![]);
+        zip.add_file("cover.jpg", &cover).unwrap();
+        zip.add_file("Überschrift/empty.txt", &empty).unwrap();
+        let bytes = zip.finish().unwrap();
+
+        let mut archive = ::zip::ZipArchive::new(Cursor::new(bytes)).unwrap();
+        assert_eq!(archive.len(), 2);
+
+        let mut file = archive.by_index(0).unwrap();
+        assert_eq!(file.name(), "cover.jpg");
+        let mut read = vec![];
+        file.read_to_end(&mut read).unwrap();
+        assert_eq!(read, contents);
+        drop(file);
+
+        let mut file = archive.by_index(1).unwrap();
+        assert_eq!(file.name(), "Überschrift/empty.txt");
+        let mut read = vec![];
+        file.read_to_end(&mut read).unwrap();
+        assert!(read.is_empty());
+    }
+
+    #[test]
+    fn zip64_marker_values_are_rejected() {
+        assert_eq!(to_u16(65534).unwrap(), 65534);
+        assert!(to_u16(65535).is_err());
+        assert!(to_u16(65536).is_err());
+
+        assert_eq!(to_u32(0xFFFF_FFFE).unwrap(), 0xFFFF_FFFE);
+        assert!(to_u32(0xFFFF_FFFF).is_err());
+        assert!(to_u32(1 << 32).is_err());
+    }
+}
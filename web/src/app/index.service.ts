@@ -1,7 +1,9 @@
 import { Injectable } from '@angular/core';
 import { HttpClient } from "@angular/common/http";
+import { EMPTY, Observable } from "rxjs";
+import { expand, reduce } from "rxjs/operators";
 import { environment } from "../environments/environment";
-import { AlbumJson, ResponseResult } from "./index.types";
+import { AlbumJson, PageJson, ResponseResult } from "./index.types";
 
 @Injectable({
   providedIn: 'root'
@@ -12,8 +14,25 @@ export class IndexService {
 
   constructor(private client: HttpClient) { }
 
-  getAlbums() {
-    return this.client.get<ResponseResult<AlbumJson[]>>(this.url(this.albumsUrl));
+  getAlbums(offset = 0, limit = 100) {
+    return this.client.get<ResponseResult<PageJson<AlbumJson>>>(this.url(this.albumsUrl), {
+      params: { offset: offset.toString(), limit: limit.toString() }
+    });
+  }
+
+  /// Loads every album, requesting pages until the listing's total is reached.
+  getAllAlbums(limit = 100): Observable<AlbumJson[]> {
+    return this.getAlbums(0, limit).pipe(
+      expand(result => {
+        const page = result.Ok;
+        if (!page || page.items.length === 0) {
+          return EMPTY;
+        }
+        const next = page.offset + page.items.length;
+        return next < page.total ? this.getAlbums(next, limit) : EMPTY;
+      }),
+      reduce((albums: AlbumJson[], result) => albums.concat(result.Ok?.items ?? []), [])
+    );
   }
 
   url(path: string) {
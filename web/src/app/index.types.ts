@@ -4,14 +4,44 @@ export interface ResponseResult<T> {
   Err: string | null;
 }
 
+/// One page of a listing returned by the server.
+export interface PageJson<T> {
+  total: number;
+  offset: number;
+  limit: number;
+  items: T[];
+}
+
 /// Describes an album returned by the server.
 export interface AlbumJson {
   name: string;
   unique_name: string;
   artists: ArtistRef[];
   songs: Array<SongRef | null>;
+  discs: DiscJson[];
   cover_url: string | null;
+  cover_thumb_url: string | null;
+  cover_medium_url: string | null;
+  cover_width: number | null;
+  cover_height: number | null;
   tracked: boolean;
+  is_single: boolean;
+  year: number | null;
+  genres: string[];
+  disc_total: number | null;
+  duration: number | null;
+  total_tracks: number | null;
+  replay_gain: number | null;
+  replay_peak: number | null;
+  documents: string[];
+  rating: number | null;
+  favorite: boolean;
+}
+
+/// Describes the tracks of one disc of a multi-disc album.
+export interface DiscJson {
+  disc: number;
+  songs: Array<SongRef | null>;
 }
 
 /// Describes a reference to an artist.
@@ -19,9 +19,7 @@ export class NavigationComponent implements OnInit {
       shareReplay()
     );
 
-  albums$: Observable<AlbumJson[]> = this.index.getAlbums().pipe(
-    map(result => result.Ok ?? [])
-  );
+  albums$: Observable<AlbumJson[]> = this.index.getAllAlbums();
 
   constructor(private breakpointObserver: BreakpointObserver, private index: IndexService) {}
 